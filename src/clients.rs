@@ -59,6 +59,9 @@ pub enum ClientManagerCommand {
 	/// Add a client to the list of paired clients.
 	AddClient(AddClientCommand),
 
+	/// Check if a certificate belongs to a paired client.
+	IsCertificatePaired(IsCertificatePairedCommand),
+
 	// /// Remove client from the list of paired clients.
 	// RemoveClient(RemoveClientCommand),
 }
@@ -135,6 +138,15 @@ pub struct AddClientCommand {
 	pub response: oneshot::Sender<Result<(), String>>,
 }
 
+/// Check if a certificate belongs to a paired client.
+pub struct IsCertificatePairedCommand {
+	/// Certificate that the client presented.
+	pub certificate: X509,
+
+	/// Channel used to provide a response.
+	pub response: oneshot::Sender<Result<bool, String>>,
+}
+
 // /// Remove client from the list of paired clients.
 // pub struct RemoveClientCommand {
 // 	/// Id of the client.
@@ -174,6 +186,17 @@ impl ClientManager {
 			.map_err(|e| log::error!("Failed to check paired status: {e}"))
 	}
 
+	pub async fn is_certificate_paired(&self, certificate: X509) -> Result<bool, ()> {
+		let (response_tx, response_rx) = oneshot::channel();
+		self.command_tx.send(ClientManagerCommand::IsCertificatePaired(IsCertificatePairedCommand { certificate, response: response_tx }))
+			.await
+			.map_err(|e| log::error!("Failed to check paired status of certificate: {e}"))?;
+
+		response_rx.await
+			.map_err(|e| log::error!("Failed to receive IsCertificatePaired response: {e}"))?
+			.map_err(|e| log::error!("Failed to check paired status of certificate: {e}"))
+	}
+
 	pub async fn start_pairing(&self, pending_client: PendingClient) -> Result<(), ()> {
 		self.command_tx.send(ClientManagerCommand::StartPairing(StartPairingCommand { pending_client }))
 			.await
@@ -398,6 +421,20 @@ impl ClientManagerInner {
 				},
 
 				ClientManagerCommand::AddClient(command) => {
+					// Remember the certificate of the client, even if its unique id is already known,
+					// because all Moonlight clients share the same unique id.
+					if let Some(client) = pending_clients.get(&command.id) {
+						let added = match certificate_fingerprint(&client.pem) {
+							Ok(fingerprint) => state.add_certificate(fingerprint).await,
+							Err(e) => Err(log::error!("{e}")),
+						};
+						if added.is_err() {
+							command.response.send(Err("Failed to add client certificate.".to_string()))
+								.map_err(|_| log::error!("Failed to send AddClient command response.")).ok();
+							continue;
+						}
+					}
+
 					let Ok(has_client) = state.has_client(command.id.clone()).await else {
 						command.response.send(Err("Failed to check client paired status.".to_string()))
 							.map_err(|_| log::error!("Failed to send AddClient command response.")).ok();
@@ -419,6 +456,28 @@ impl ClientManagerInner {
 					}
 				},
 
+				ClientManagerCommand::IsCertificatePaired(command) => {
+					let fingerprint = match certificate_fingerprint(&command.certificate) {
+						Ok(fingerprint) => fingerprint,
+						Err(e) => {
+							command.response.send(Err(e))
+								.map_err(|_| log::error!("Failed to send IsCertificatePaired response.")).ok();
+							continue;
+						},
+					};
+
+					match state.has_certificate(fingerprint).await {
+						Ok(result) => {
+							command.response.send(Ok(result))
+								.map_err(|_| log::error!("Failed to send IsCertificatePaired response.")).ok();
+						},
+						Err(()) => {
+							command.response.send(Err("Failed to check certificate paired status.".to_string()))
+								.map_err(|_| log::error!("Failed to send IsCertificatePaired response.")).ok();
+						},
+					}
+				},
+
 				// ClientManagerCommand::RemoveClient(command) => {
 				// 	pending_clients.remove(&command.id);
 				// 	let Ok(result) = state.remove_client(command.id).await else {
@@ -517,6 +576,13 @@ fn create_key(salt: &[u8; 16], pin: &str) -> Result<[u8; 16], String> {
 		.map_err(|e| format!("Received unexpected key result: {e}"))
 }
 
+/// SHA-256 fingerprint of a certificate, used to recognize the certificates of paired clients.
+fn certificate_fingerprint(certificate: &X509) -> Result<String, String> {
+	certificate.digest(MessageDigest::sha256())
+		.map(hex::encode)
+		.map_err(|e| format!("Failed to compute certificate fingerprint: {e}"))
+}
+
 fn sign<T>(data: &[u8], key: &PKeyRef<T>) -> Result<Vec<u8>, openssl::error::ErrorStack>
 	where T: openssl::pkey::HasPrivate
 {
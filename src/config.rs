@@ -61,6 +61,8 @@ impl Default for Config {
 						vec!["$HOME/.local/bin/resolution".to_string()],
 					]),
 					boxart: None,
+					yuv444: false,
+					stream: Default::default(),
 				},
 
 				ApplicationConfig {
@@ -80,6 +82,8 @@ impl Default for Config {
 						vec!["$HOME/.local/bin/resolution".to_string()],
 					]),
 					boxart: None,
+					yuv444: false,
+					stream: Default::default(),
 				},
 			],
 			application_scanners: vec![
@@ -153,6 +157,18 @@ pub struct ApplicationConfig {
 	///
 	/// Note that multiple entries can be provided, in which case they will be executed in that same order.
 	pub run_after: Option<Vec<Vec<String>>>,
+
+	/// Stream this application without chroma subsampling (YUV 4:4:4) to clients that ask for it.
+	///
+	/// This keeps text sharp, at the cost of a higher bitrate. See also `stream.video.yuv444`.
+	/// 4:4:4 is only advertised to clients while this application is running, so clients can only use it when
+	/// resuming the stream, unless `stream.video.yuv444` is enabled.
+	#[serde(default)]
+	pub yuv444: bool,
+
+	/// Stream settings for this application, which override the global stream settings.
+	#[serde(default)]
+	pub stream: StreamOverrides,
 }
 
 impl ApplicationConfig {
@@ -163,6 +179,110 @@ impl ApplicationConfig {
 	}
 }
 
+/// Stream settings of an application, every setting that is set replaces the global setting.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StreamOverrides {
+	/// Which type of encoder to use, replaces `stream.video.encoder`.
+	pub encoder: Option<VideoEncoderType>,
+
+	/// Type of codec to use for h264, replaces `stream.video.codec_h264`.
+	pub codec_h264: Option<String>,
+
+	/// Type of codec to use for hevc, replaces `stream.video.codec_hevc`.
+	pub codec_hevc: Option<String>,
+
+	/// Type of codec to use for av1, replaces `stream.video.codec_av1`.
+	pub codec_av1: Option<String>,
+
+	/// Type of codec to use for h264 when encoding in software, replaces `stream.video.codec_h264_software`.
+	pub codec_h264_software: Option<String>,
+
+	/// Type of codec to use for hevc when encoding in software, replaces `stream.video.codec_hevc_software`.
+	pub codec_hevc_software: Option<String>,
+
+	/// Type of codec to use for av1 when encoding in software, replaces `stream.video.codec_av1_software`.
+	pub codec_av1_software: Option<String>,
+
+	/// Best video codec that is offered to clients, replaces `stream.video.preferred_codec`.
+	pub preferred_codec: Option<VideoCodec>,
+
+	/// Maximum bitrate in kbps, replaces `stream.video.max_bitrate`.
+	pub max_bitrate: Option<usize>,
+
+	/// Maximum frame rate, replaces `stream.video.max_fps`.
+	pub max_fps: Option<u32>,
+
+	/// What percentage of data packets should be parity packets, replaces `stream.video.fec_percentage`.
+	pub fec_percentage: Option<u8>,
+
+	/// Time in seconds since last ping after which the stream closes, replaces `stream_timeout`.
+	pub stream_timeout: Option<u64>,
+
+	/// Maximum number of audio channels, replaces `stream.audio.channels`.
+	pub audio_channels: Option<u8>,
+
+	/// Name of the PipeWire node or PulseAudio source to record, replaces `stream.audio.target`.
+	pub audio_target: Option<String>,
+
+	/// Types of input devices the client can use, replaces `stream.control.input_devices`.
+	pub input_devices: Option<Vec<InputDeviceType>>,
+}
+
+impl StreamOverrides {
+	/// Merge these overrides over the global configuration.
+	pub fn apply(&self, mut config: Config) -> Config {
+		let video = &mut config.stream.video;
+		if let Some(encoder) = self.encoder {
+			video.encoder = encoder;
+		}
+		if let Some(codec_h264) = &self.codec_h264 {
+			video.codec_h264 = codec_h264.clone();
+		}
+		if let Some(codec_hevc) = &self.codec_hevc {
+			video.codec_hevc = codec_hevc.clone();
+		}
+		if let Some(codec_av1) = &self.codec_av1 {
+			video.codec_av1 = codec_av1.clone();
+		}
+		if let Some(codec_h264_software) = &self.codec_h264_software {
+			video.codec_h264_software = codec_h264_software.clone();
+		}
+		if let Some(codec_hevc_software) = &self.codec_hevc_software {
+			video.codec_hevc_software = codec_hevc_software.clone();
+		}
+		if let Some(codec_av1_software) = &self.codec_av1_software {
+			video.codec_av1_software = codec_av1_software.clone();
+		}
+		if let Some(preferred_codec) = self.preferred_codec {
+			video.preferred_codec = preferred_codec;
+		}
+		if self.max_bitrate.is_some() {
+			video.max_bitrate = self.max_bitrate;
+		}
+		if self.max_fps.is_some() {
+			video.max_fps = self.max_fps;
+		}
+		if let Some(fec_percentage) = self.fec_percentage {
+			video.fec_percentage = fec_percentage;
+		}
+		if let Some(stream_timeout) = self.stream_timeout {
+			config.stream_timeout = stream_timeout;
+		}
+		if let Some(audio_channels) = self.audio_channels {
+			config.stream.audio.channels = audio_channels;
+		}
+		if let Some(audio_target) = &self.audio_target {
+			config.stream.audio.target = Some(audio_target.clone());
+		}
+		if let Some(input_devices) = &self.input_devices {
+			config.stream.control.input_devices = input_devices.clone();
+		}
+
+		config
+	}
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 #[serde(tag = "type")]
 #[serde(rename_all = "snake_case")]
@@ -201,6 +321,10 @@ pub struct StreamConfig {
 
 	/// Configuration for the control stream.
 	pub control: ControlStreamConfig,
+
+	/// Configuration for recording sessions to disk.
+	#[serde(default)]
+	pub recording: RecordingConfig,
 }
 
 impl Default for StreamConfig {
@@ -210,56 +334,361 @@ impl Default for StreamConfig {
 			video: Default::default(),
 			audio: Default::default(),
 			control: Default::default(),
+			recording: Default::default(),
 		}
 	}
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct VideoStreamConfig {
 	/// Port to use for streaming video data.
 	pub port: u16,
 
+	/// Which type of encoder to use.
+	pub encoder: VideoEncoderType,
+
 	/// Type of codec to use for h264.
 	pub codec_h264: String,
 
-	/// Type of codec to use for h264.
+	/// Type of codec to use for hevc.
 	pub codec_hevc: String,
 
+	/// Type of codec to use for av1.
+	pub codec_av1: String,
+
+	/// Type of codec to use for h264 when encoding in software.
+	pub codec_h264_software: String,
+
+	/// Type of codec to use for hevc when encoding in software.
+	pub codec_hevc_software: String,
+
+	/// Type of codec to use for av1 when encoding in software.
+	pub codec_av1_software: String,
+
+	/// Best video codec that is offered to clients, clients stream with the best offered codec that they support.
+	///
+	/// Lower this when encoding latency matters more than quality, for example to H264 when encoding in software.
+	pub preferred_codec: VideoCodec,
+
 	/// What percentage of data packets should be parity packets.
 	pub fec_percentage: u8,
+
+	/// Maximum bitrate in kbps, if the client asks for a higher bitrate it is lowered to this value.
+	pub max_bitrate: Option<usize>,
+
+	/// Maximum frame rate, if the client asks for a higher frame rate it is lowered to this value.
+	pub max_fps: Option<u32>,
+
+	/// Lower the bitrate (and raise the FEC percentage) when the client reports packet loss.
+	///
+	/// Loss is reported through loss statistics (older clients), requests for IDR frames and frames that couldn't be sent in time.
+	/// The bitrate is raised again in steps once no loss was reported for a few seconds,
+	/// but it never exceeds the maximum bitrate requested by the client.
+	pub adaptive_bitrate: bool,
+
+	/// Periodically refresh the frame with a wave of intra coded blocks, instead of sending IDR frames.
+	///
+	/// Clients still receive an IDR frame when they lose frames, since reference frames can't be invalidated.
+	/// Encoders that don't support this fall back to IDR frames.
+	pub intra_refresh: bool,
+
+	/// Ask the client to encrypt video packets, clients that don't support this stream unencrypted video.
+	pub encryption: bool,
+
+	/// Spread the packets of every frame over this fraction (0.0 - 1.0) of the frame interval.
+	///
+	/// This avoids bursts that overflow the buffers of switches and Wi-Fi bridges, at the cost of some latency.
+	/// Set to 0 to send every frame as fast as possible.
+	pub pacing: f64,
+
+	/// Maximum number of packets that are sent with a single system call.
+	///
+	/// With pacing enabled, a batch is also limited to a burst of a few packets (or a millisecond of data at high bitrates).
+	pub send_batch_size: usize,
+
+	/// Stream every application without chroma subsampling (YUV 4:4:4) to clients that ask for it.
+	///
+	/// Only 8-bit H264 (High 4:4:4) and HEVC (RExt) are supported, HDR streams are always subsampled.
+	/// 4:4:4 is only advertised for formats that the encoder can open in 4:4:4.
+	pub yuv444: bool,
+
+	/// Source of the frames that are streamed to the client.
+	pub source: VideoSourceConfig,
 }
 
 impl Default for VideoStreamConfig {
 	fn default() -> Self {
 		Self {
 			port: 47998,
+			encoder: Default::default(),
 			codec_h264: "h264_nvenc".to_string(),
 			codec_hevc: "hevc_nvenc".to_string(),
+			codec_av1: "av1_nvenc".to_string(),
+			codec_h264_software: "libx264".to_string(),
+			codec_hevc_software: "libx265".to_string(),
+			codec_av1_software: "libsvtav1".to_string(),
+			preferred_codec: Default::default(),
 			fec_percentage: 20,
+			max_bitrate: None,
+			max_fps: None,
+			adaptive_bitrate: true,
+			intra_refresh: false,
+			encryption: true,
+			pacing: 0.25,
+			send_batch_size: 64,
+			yuv444: false,
+			source: Default::default(),
 		}
 	}
 }
 
+/// Video codecs, ordered from worst to best.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoCodec {
+	H264,
+	Hevc,
+
+	/// AV1 is only offered if an AV1 encoder is available.
+	#[default]
+	Av1,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VideoEncoderType {
+	/// Use a CUDA encoder if CUDA is available, otherwise fall back to a software encoder.
+	#[default]
+	Auto,
+
+	/// Always use a CUDA (NVENC) encoder.
+	Cuda,
+
+	/// Always use a software encoder.
+	Software,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum VideoSourceConfig {
+	/// Capture the desktop using NvFBC, this requires an NVIDIA GPU.
+	#[default]
+	Nvfbc,
+
+	/// Generate moving colour bars with a frame counter, at the resolution requested by the client.
+	TestPattern,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct AudioStreamConfig {
 	/// Port to use for streaming audio data.
 	pub port: u16,
+
+	/// Maximum number of audio channels to capture and stream: 1 (mono), 2 (stereo), 6 (5.1 surround) or 8 (7.1 surround).
+	///
+	/// Clients request the number of channels they want, up to this maximum.
+	pub channels: u8,
+
+	/// Bitrate of the Opus encoder in kbps, by default the bitrate depends on the number of channels.
+	///
+	/// Every packet has to fit in a single FEC shard, so the bitrate is limited to about 3200 kbps for clients
+	/// that ask for 5ms packets and about 1600 kbps for clients that ask for 10ms packets.
+	pub bitrate: Option<u32>,
+
+	/// Complexity of the Opus encoder, from 0 (fastest) to 10 (best quality).
+	pub complexity: u8,
+
+	/// Ask the client to encrypt audio packets, disable this for clients that don't support encrypted audio (such as Steam Link).
+	pub encryption: bool,
+
+	/// Source of the audio that is streamed to the client.
+	pub source: AudioSourceConfig,
+
+	/// Audio system to capture audio from.
+	pub backend: AudioBackend,
+
+	/// Name of the PipeWire node or PulseAudio source to record, such as the output stream of a single application.
+	///
+	/// If not set, the audio that is played on the default sink (or on the virtual sink of the session) is recorded.
+	/// The monitor of a sink can be given as `<sink name>.monitor` for both backends.
+	pub target: Option<String>,
+
+	/// Create a sink for every session, so that streamed audio doesn't play on the speakers of the host.
+	pub virtual_sink: VirtualSinkMode,
 }
 
 impl Default for AudioStreamConfig {
 	fn default() -> Self {
-		Self { port: 48000 }
+		Self {
+			port: 48000,
+			channels: 8,
+			bitrate: None,
+			complexity: 10,
+			encryption: true,
+			source: Default::default(),
+			backend: Default::default(),
+			target: None,
+			virtual_sink: Default::default(),
+		}
 	}
 }
 
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(tag = "type")]
+#[serde(rename_all = "snake_case")]
+pub enum AudioSourceConfig {
+	/// Capture the audio that plays on the host, using `backend`.
+	#[default]
+	Capture,
+
+	/// Generate a sine wave of `frequency` Hz on every channel.
+	Tone {
+		#[serde(default = "default_tone_frequency")]
+		frequency: f64,
+	},
+
+	/// Generate a sine wave that sweeps from `start_frequency` to `end_frequency` Hz in `duration` seconds, and then starts over.
+	Sweep {
+		#[serde(default = "default_sweep_start_frequency")]
+		start_frequency: f64,
+
+		#[serde(default = "default_sweep_end_frequency")]
+		end_frequency: f64,
+
+		#[serde(default = "default_sweep_duration")]
+		duration: f64,
+	},
+
+	/// Play a 16-bit PCM WAV file in a loop.
+	///
+	/// Mono files play on every channel, other files play on the channels they have.
+	Wav {
+		path: PathBuf,
+	},
+}
+
+fn default_tone_frequency() -> f64 {
+	440.0
+}
+
+fn default_sweep_start_frequency() -> f64 {
+	20.0
+}
+
+fn default_sweep_end_frequency() -> f64 {
+	20000.0
+}
+
+fn default_sweep_duration() -> f64 {
+	10.0
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AudioBackend {
+	/// Use PipeWire if it is running, otherwise use PulseAudio.
+	#[default]
+	Auto,
+
+	/// Capture audio with the native PipeWire API, this requires the `pipewire` feature.
+	#[serde(rename = "pipewire")]
+	PipeWire,
+
+	/// Capture audio with PulseAudio, which also works with the PulseAudio server of PipeWire.
+	Pulse,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum VirtualSinkMode {
+	/// Record the audio that is played on the default sink, which also plays on the host.
+	Disabled,
+
+	/// Make the sink the default sink for the duration of the session, the previous default sink is restored afterwards.
+	///
+	/// If another sink becomes the default during the session, the sink is made the default again and the other sink is restored afterwards.
+	#[default]
+	Session,
+
+	/// Only route the launched application to the sink (through `PULSE_SINK`), other audio still plays on the host.
+	Application,
+}
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
 pub struct ControlStreamConfig {
 	/// Port to use for streaming control data.
 	pub port: u16,
+
+	/// Types of input devices the client can use, input from other devices is ignored.
+	pub input_devices: Vec<InputDeviceType>,
 }
 
 impl Default for ControlStreamConfig {
 	fn default() -> Self {
-		Self { port: 47999 }
+		Self {
+			port: 47999,
+			input_devices: vec![InputDeviceType::Keyboard, InputDeviceType::Mouse, InputDeviceType::Gamepad],
+		}
+	}
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InputDeviceType {
+	Keyboard,
+	Mouse,
+	Gamepad,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+	/// What to record of a session.
+	pub mode: RecordingMode,
+
+	/// Directory to write recordings and replays to.
+	pub directory: PathBuf,
+
+	/// Container format of the recordings, used as file extension (ie. `mkv` or `mp4`).
+	pub format: String,
+
+	/// Number of seconds that are kept in memory, and saved when a replay is requested.
+	pub replay_duration: u64,
+
+	/// Number of seconds between key frames in replay mode.
+	///
+	/// A replay has to start at a key frame, but the stream normally only has key frames when the client asks for them.
+	/// Forcing key frames costs bitrate (and can briefly degrade quality at low bitrates) of the stream to the client,
+	/// while a longer interval makes replays up to this much longer than `replay_duration`.
+	pub replay_keyframe_interval: u64,
+}
+
+impl Default for RecordingConfig {
+	fn default() -> Self {
+		Self {
+			mode: Default::default(),
+			directory: "$HOME/Videos/moonshine".into(),
+			format: "mkv".to_string(),
+			replay_duration: 30,
+			replay_keyframe_interval: 5,
+		}
 	}
 }
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RecordingMode {
+	/// Don't record anything.
+	#[default]
+	Disabled,
+
+	/// Record every session from start to finish.
+	Session,
+
+	/// Keep the last seconds of a session in memory, save them with Ctrl+Alt+Shift+R or by requesting `/save-replay` from the HTTPS webserver with the certificate of a paired client.
+	Replay,
+}
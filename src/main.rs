@@ -7,7 +7,7 @@ use crate::clients::ClientManager;
 use crate::config::Config;
 use crate::crypto::create_certificate;
 use crate::rtsp::RtspServer;
-use crate::session::SessionManager;
+use crate::session::{stream::VideoCapabilities, SessionManager};
 use crate::state::State;
 use crate::webserver::Webserver;
 use openssl::pkey::PKey;
@@ -184,8 +184,16 @@ impl Moonshine {
 		// Create a manager for saving and loading client state.
 		let client_manager = ClientManager::new(state.clone(), cert.clone(), pkey, shutdown.trigger_shutdown_token(3));
 
+		// Find out what the encoders can stream before advertising it to clients.
+		let video_capabilities = tokio::task::spawn_blocking({
+			let video_config = config.stream.video.clone();
+			move || VideoCapabilities::probe(&video_config)
+		})
+			.await
+			.map_err(|e| log::error!("Failed to probe video encoders: {e}"))?;
+
 		// Run the RTSP server.
-		let rtsp_server = RtspServer::new(config.clone(), session_manager.clone(), shutdown.clone());
+		let rtsp_server = RtspServer::new(config.clone(), video_capabilities, session_manager.clone(), shutdown.clone());
 
 		// Publish the Moonshine service using zeroconf.
 		publisher::spawn(config.webserver.port, config.name.clone(), shutdown.clone());
@@ -193,6 +201,7 @@ impl Moonshine {
 		// Create a handler for the webserver.
 		let webserver = Webserver::new(
 			config,
+			video_capabilities,
 			state.get_uuid().await?,
 			cert,
 			client_manager.clone(),
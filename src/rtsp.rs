@@ -3,21 +3,23 @@ use async_shutdown::ShutdownManager;
 use rtsp_types::{headers::{self, Transport}, Method};
 use tokio::{net::{TcpListener, TcpStream}, io::{AsyncReadExt, AsyncWriteExt}};
 
-use crate::{config::Config, session::{stream::{AudioStreamContext, VideoStreamContext}, manager::SessionManager}};
+use crate::{config::{Config, VideoCodec}, session::{stream::{AudioStreamContext, OpusConfiguration, StreamFeatures, VideoCapabilities, VideoFormat, VideoStreamContext}, manager::SessionManager}};
 
 #[derive(Clone)]
 pub struct RtspServer {
 	config: Config,
+	video_capabilities: VideoCapabilities,
 	session_manager: SessionManager,
 }
 
 impl RtspServer {
 	pub fn new(
 		config: Config,
+		video_capabilities: VideoCapabilities,
 		session_manager: SessionManager,
 		shutdown: ShutdownManager<i32>,
 	) -> Self {
-		let server = Self { config: config.clone(), session_manager };
+		let server = Self { config: config.clone(), video_capabilities, session_manager };
 
 		tokio::spawn({
 			let server = server.clone();
@@ -63,16 +65,38 @@ impl RtspServer {
 	}
 
 	#[allow(clippy::result_unit_err)]
-	pub fn description(&self) -> String {
+	/// Describe the stream of an application, `config` is the configuration with the overrides of that application applied.
+	pub fn description(&self, config: &Config) -> String {
 		// This is a very simple SDP description, the minimal that Moonlight requires.
-		// TODO: Fill this based on server settings.
-		// TODO: Use:
-		//       "a=x-ss-general.featureFlags: <FEATURE FLAGS>"
-		//       "x-nv-video[0].refPicInvalidation=1"
-		//       "a=rtpmap:98 AV1/90000" (For AV1 support)
-		//       "a=fmtp:97 surround-params=<SURROUND PARAMS>"
-		//       "<AUDIO STREAM MAPPING>"
-		"sprop-parameter-sets=AAAAAU\na=fmtp:96 packetization-mode=1".to_string()
+		// Moonlight falls back to H264 if HEVC or AV1 isn't mentioned, which is how the preferred codec is enforced.
+		let preferred_codec = config.stream.video.preferred_codec;
+		let mut description = String::new();
+		if preferred_codec >= VideoCodec::Hevc {
+			description += "sprop-parameter-sets=AAAAAU\n";
+		}
+		description += "a=fmtp:96 packetization-mode=1";
+		// Reference frame invalidation isn't advertised, because FFmpeg can't invalidate reference frames of the encoder.
+		// Clients request an IDR frame instead when they lose frames.
+		if self.video_capabilities.av1 && preferred_codec >= VideoCodec::Av1 {
+			description += "\na=rtpmap:98 AV1/90000";
+		}
+
+		description += &StreamFeatures::description(config);
+
+		// Clients that request more channels than advertised fall back to stereo.
+		for surround_params in OpusConfiguration::surround_params(config.stream.audio.channels) {
+			description += &format!("\na=fmtp:97 surround-params={surround_params}");
+		}
+
+		description
+	}
+
+	/// Configuration with the stream overrides of the application that is launched, if any.
+	async fn application_config(&self) -> Config {
+		match self.session_manager.get_session_context().await {
+			Ok(Some(context)) => context.application.stream.apply(self.config.clone()),
+			_ => self.config.clone(),
+		}
 	}
 
 	fn handle_options_request(&self, request: &rtsp_types::Request<Vec<u8>>, cseq: i32) -> rtsp_types::Response<Vec<u8>> {
@@ -164,7 +188,7 @@ impl RtspServer {
 		request: &rtsp_types::Request<Vec<u8>>,
 		cseq: i32,
 	) -> rtsp_types::Response<Vec<u8>> {
-		let description = self.description();
+		let description = self.description(&self.application_config().await);
 		log::debug!("SDP session data: \n{}", description.trim());
 		rtsp_types::Response::builder(request.version(), rtsp_types::StatusCode::Ok)
 			.header(headers::CSEQ, cseq.to_string())
@@ -183,6 +207,7 @@ impl RtspServer {
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			}
 		};
+		let config = self.application_config().await;
 
 		log::trace!("Received SDP session from ANNOUNCE request: {sdp_session:#?}");
 
@@ -243,6 +268,58 @@ impl RtspServer {
 				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
 			},
 		};
+		let video_format = match VideoFormat::from_bitstream_format(video_format) {
+			Some(video_format) => video_format,
+			None => {
+				log::warn!("Unknown video format {video_format} requested in SDP session.");
+				return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::BadRequest);
+			},
+		};
+
+		// Older clients don't send the dynamic range, assume they want SDR.
+		let dynamic_range_mode: u32 = get_sdp_attribute(&sdp_session, "x-nv-video[0].dynamicRangeMode").unwrap_or(0);
+		let hdr = dynamic_range_mode != 0;
+		if hdr && video_format == VideoFormat::H264 {
+			log::warn!("Client requested HDR with H264, which doesn't support 10-bit streaming, falling back to SDR.");
+		}
+
+		let slices_per_frame: u32 = match get_sdp_attribute(&sdp_session, "x-nv-video[0].videoEncoderSlicesPerFrame") {
+			Ok(slices_per_frame) => slices_per_frame,
+			Err(()) => {
+				log::debug!("No slices per frame in SDP session, using a single slice per frame.");
+				1
+			},
+		};
+
+		// Clients only send this if we advertised 4:4:4 support, 1 means they want 4:4:4.
+		let chroma_sampling_type: u32 = get_sdp_attribute(&sdp_session, "x-ss-video[0].chromaSamplingType").unwrap_or(0);
+		let yuv444 = if chroma_sampling_type == 1 {
+			let application_yuv444 = match self.session_manager.get_session_context().await {
+				Ok(Some(context)) => context.application.yuv444,
+				_ => false,
+			};
+
+			if !self.config.stream.video.yuv444 && !application_yuv444 {
+				log::warn!("Client requested YUV 4:4:4, but it isn't enabled for this application, streaming YUV 4:2:0.");
+				false
+			} else if (hdr && video_format != VideoFormat::H264) || video_format == VideoFormat::Av1 {
+				log::warn!("Client requested YUV 4:4:4, which is only supported for 8-bit H264 and HEVC, streaming YUV 4:2:0.");
+				false
+			} else if !self.video_capabilities.yuv444(video_format) {
+				log::warn!("Client requested YUV 4:4:4, but the encoder can't encode {video_format:?} in 4:4:4, streaming YUV 4:2:0.");
+				false
+			} else {
+				true
+			}
+		} else {
+			false
+		};
+
+		// Clients that don't negotiate features don't send these.
+		let feature_flags: u32 = get_sdp_attribute(&sdp_session, "x-ss-general.featureFlags").unwrap_or(0);
+		let encryption_enabled: Option<u32> = get_sdp_attribute(&sdp_session, "x-ss-general.encryptionEnabled").ok();
+		let features = StreamFeatures::negotiate(&config, feature_flags, encryption_enabled);
+		log::debug!("Negotiated feature flags {:#x} and encryption flags {:#x}.", features.feature_flags, features.encryption);
 
 		let video_stream_context = VideoStreamContext {
 			width,
@@ -253,6 +330,10 @@ impl RtspServer {
 			minimum_fec_packets,
 			qos: video_qos_type != "0",
 			video_format,
+			hdr: hdr && video_format != VideoFormat::H264,
+			encrypted: features.video_encrypted(),
+			slices_per_frame: slices_per_frame.max(1),
+			yuv444,
 		};
 
 		let packet_duration = match get_sdp_attribute(&sdp_session, "x-nv-aqos.packetDuration") {
@@ -270,12 +351,23 @@ impl RtspServer {
 			},
 		};
 
+		// Older clients don't send the surround settings, assume they want stereo.
+		let audio_channels: u8 = get_sdp_attribute(&sdp_session, "x-nv-audio.surround.numChannels").unwrap_or(2);
+		let max_audio_channels = config.stream.audio.channels;
+		if audio_channels > max_audio_channels {
+			log::warn!("Client requested {audio_channels} audio channels, but at most {max_audio_channels} channels are enabled.");
+		}
+		let audio_quality: u32 = get_sdp_attribute(&sdp_session, "x-nv-audio.surround.AudioQuality").unwrap_or(0);
+
 		let audio_stream_context = AudioStreamContext {
 			packet_duration,
 			qos: audio_qos_type != "0",
+			channels: audio_channels.min(max_audio_channels),
+			high_quality: audio_quality != 0,
+			encrypted: features.audio_encrypted(),
 		};
 
-		if self.session_manager.set_stream_context(video_stream_context, audio_stream_context).await.is_err() {
+		if self.session_manager.set_stream_context(video_stream_context, audio_stream_context, features).await.is_err() {
 			return rtsp_response(cseq, request.version(), rtsp_types::StatusCode::InternalServerError)
 		}
 
@@ -4,16 +4,17 @@ use tokio::sync::{mpsc, oneshot};
 
 use crate::config::Config;
 
-use super::{Session, stream::{AudioStreamContext, VideoStreamContext}, SessionContext, SessionKeys};
+use super::{Session, stream::{AudioStreamContext, StreamFeatures, VideoStreamContext}, SessionContext, SessionKeys};
 
 pub enum SessionManagerCommand {
-	SetStreamContext(VideoStreamContext, AudioStreamContext),
+	SetStreamContext(VideoStreamContext, AudioStreamContext, StreamFeatures),
 	GetSessionContext(oneshot::Sender<Option<SessionContext>>),
 	InitializeSession(SessionContext),
 	// GetCurrentSession(oneshot::Sender<Option<Session>>),
 	StartSession,
 	StopSession,
 	UpdateKeys(SessionKeys),
+	SaveReplay,
 }
 
 #[derive(Clone)]
@@ -51,9 +52,10 @@ impl SessionManager {
 	pub async fn set_stream_context(
 		&self,
 		video_stream_context: VideoStreamContext,
-		audio_stream_context: AudioStreamContext
+		audio_stream_context: AudioStreamContext,
+		features: StreamFeatures,
 	) -> Result<(), ()> {
-		self.command_tx.send(SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context)).await
+		self.command_tx.send(SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context, features)).await
 			.map_err(|e| log::error!("Failed to send SetStreamContext command: {e}"))
 	}
 
@@ -99,6 +101,12 @@ impl SessionManager {
 			.await
 			.map_err(|e| log::error!("Failed to stop session: {e}"))
 	}
+
+	pub async fn save_replay(&self) -> Result<(), ()> {
+		self.command_tx.send(SessionManagerCommand::SaveReplay)
+			.await
+			.map_err(|e| log::error!("Failed to save replay: {e}"))
+	}
 }
 
 impl SessionManagerInner {
@@ -130,13 +138,14 @@ impl SessionManagerInner {
 					};
 
 					match command {
-						SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context) =>  {
-							if self.session.is_none() {
+						SessionManagerCommand::SetStreamContext(video_stream_context, audio_stream_context, features) =>  {
+							let Some(session) = self.session.as_mut() else {
 								// Well we can, but it is not expected.
 								log::warn!("Can't set stream context without an active session.");
 								continue;
-							}
+							};
 
+							session.set_features(features);
 							self.video_stream_context = Some(video_stream_context);
 							self.audio_stream_context = Some(audio_stream_context);
 						},
@@ -154,7 +163,7 @@ impl SessionManagerInner {
 								continue;
 							}
 
-							self.session = match Session::new(config.clone(), session_context, enet.clone(), stop_signal.clone()) {
+							self.session = match Session::new(config.clone(), session_context, enet.clone(), stop_signal.clone()).await {
 								Ok(session) => Some(session),
 								Err(()) => continue,
 							};
@@ -172,15 +181,18 @@ impl SessionManagerInner {
 								continue;
 							};
 
-							if session.is_running() {
-								log::info!("Can't start session, it is already running.");
-								continue;
-							}
-
 							let Some(video_stream_context) = self.video_stream_context.clone() else {
 								log::warn!("Can't start a stream without a video stream context.");
 								continue;
 							};
+
+							if session.is_running() {
+								// The client resumed the session, possibly with a different resolution or frame rate.
+								log::info!("Session is already running, continuing the stream.");
+								let _ = session.reconfigure_stream(video_stream_context).await;
+								continue;
+							}
+
 							let Some(audio_stream_context) = self.audio_stream_context.clone() else {
 								log::warn!("Can't start a stream without a audio stream context.");
 								continue;
@@ -206,6 +218,15 @@ impl SessionManagerInner {
 
 							let _ = session.update_keys(keys).await;
 						},
+
+						SessionManagerCommand::SaveReplay => {
+							let Some(session) = &self.session else {
+								log::warn!("Can't save a replay, there is no active session.");
+								continue;
+							};
+
+							let _ = session.save_replay().await;
+						},
 					};
 				}
 			}
@@ -1,15 +1,16 @@
-use std::process::Stdio;
+use std::{process::Stdio, sync::Arc};
 
 use async_shutdown::ShutdownManager;
 use enet::Enet;
 use tokio::sync::mpsc;
 
-use crate::{config::{Config, ApplicationConfig}, session::stream::{VideoStream, AudioStream, ControlStream}};
+use crate::{config::{Config, ApplicationConfig, AudioSourceConfig, RecordingMode, VirtualSinkMode}, session::stream::{VideoStream, AudioStream, ControlStream, MediaClock}};
 
-use self::stream::{VideoStreamContext, AudioStreamContext};
+use self::{recorder::Recorder, stream::{VideoStreamContext, AudioStreamContext, OpusConfiguration, StreamFeatures, VirtualSink}};
 pub use manager::SessionManager;
 
 pub mod manager;
+pub mod recorder;
 pub mod stream;
 
 #[derive(Clone, Debug)]
@@ -38,12 +39,17 @@ pub struct SessionContext {
 
 	/// Encryption keys for encoding traffic.
 	pub keys: SessionKeys,
+
+	/// Features that were negotiated with the client, which are the defaults until the client announces its stream.
+	pub features: StreamFeatures,
 }
 
 enum SessionCommand {
 	StartStream(VideoStreamContext, AudioStreamContext),
+	ReconfigureStream(VideoStreamContext),
 	StopStream,
 	UpdateKeys(SessionKeys),
+	SaveReplay,
 }
 
 #[derive(Clone)]
@@ -51,26 +57,47 @@ pub struct Session {
 	command_tx: mpsc::Sender<SessionCommand>,
 	context: SessionContext,
 	running: bool,
+
+	/// Sink that the audio of this session is played on, removed when the last handle to the session is dropped.
+	virtual_sink: Option<Arc<VirtualSink>>,
 }
 
 #[allow(clippy::result_unit_err)]
 impl Session {
-	pub fn new(
+	pub async fn new(
 		config: Config,
 		context: SessionContext,
 		enet: Enet,
 		stop_signal: ShutdownManager<()>,
 	) -> Result<Self, ()> {
+		// Streams of this session use the settings of the application where it has any.
+		let mut config = context.application.stream.apply(config);
+
+		// Create the sink before launching the application, so that the application plays its audio on the sink.
+		// A failure to create the sink shouldn't prevent the session from starting, the audio plays on the host instead.
+		let virtual_sink = match config.stream.audio.virtual_sink {
+			VirtualSinkMode::Disabled => None,
+			// Synthetic audio doesn't play on a sink, and may be used on hosts without an audio system.
+			_ if !matches!(config.stream.audio.source, AudioSourceConfig::Capture) => None,
+			mode => {
+				let channels = OpusConfiguration::select(config.stream.audio.channels, false).channels;
+				VirtualSink::new(channels, mode == VirtualSinkMode::Session).await.ok().map(Arc::new)
+			},
+		};
+		if let Some(virtual_sink) = &virtual_sink {
+			config.stream.audio.target.get_or_insert_with(|| virtual_sink.monitor_name());
+		}
+
 		if let Some(run_before) = &context.application.run_before {
 			for command in run_before {
-				run_command(command, &context);
+				run_command(command, &context, virtual_sink.as_deref());
 			}
 		}
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = SessionInner { config, video_stream: None, audio_stream: None, control_stream: None };
+		let inner = SessionInner { config, video_stream: None, audio_stream: None, control_stream: None, recorder: None };
 		tokio::spawn(inner.run(command_rx, context.clone(), enet, stop_signal));
-		Ok(Self { command_tx, context, running: false })
+		Ok(Self { command_tx, context, running: false, virtual_sink })
 	}
 
 	pub async fn start_stream(
@@ -84,6 +111,13 @@ impl Session {
 			.map_err(|e| log::error!("Failed to send StartStream command: {e}"))
 	}
 
+	/// Continue the running stream with a new video stream context.
+	pub async fn reconfigure_stream(&self, video_stream_context: VideoStreamContext) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::ReconfigureStream(video_stream_context))
+			.await
+			.map_err(|e| log::error!("Failed to send ReconfigureStream command: {e}"))
+	}
+
 	pub async fn stop_stream(&mut self) -> Result<(), ()> {
 		self.running = false;
 		self.command_tx.send(SessionCommand::StopStream)
@@ -95,6 +129,11 @@ impl Session {
 		&self.context
 	}
 
+	/// Store the features that were negotiated with the client for the next stream.
+	pub fn set_features(&mut self, features: StreamFeatures) {
+		self.context.features = features;
+	}
+
 	pub fn is_running(&self) -> bool {
 		self.running
 	}
@@ -103,13 +142,19 @@ impl Session {
 		self.command_tx.send(SessionCommand::UpdateKeys(keys)).await
 			.map_err(|e| log::error!("Failed to send UpdateKeys command: {e}"))
 	}
+
+	/// Save the last seconds of the stream, if the session is recorded in replay mode.
+	pub async fn save_replay(&self) -> Result<(), ()> {
+		self.command_tx.send(SessionCommand::SaveReplay).await
+			.map_err(|e| log::error!("Failed to send SaveReplay command: {e}"))
+	}
 }
 
 impl Drop for Session {
 	fn drop(&mut self) {
 		if let Some(run_after) = &self.context.application.run_after {
 			for command in run_after {
-				run_command(command, &self.context);
+				run_command(command, &self.context, self.virtual_sink.as_deref());
 			}
 		}
 	}
@@ -120,6 +165,7 @@ struct SessionInner {
 	video_stream: Option<VideoStream>,
 	audio_stream: Option<AudioStream>,
 	control_stream: Option<ControlStream>,
+	recorder: Option<Recorder>,
 }
 
 impl SessionInner {
@@ -133,13 +179,25 @@ impl SessionInner {
 		while let Some(command) = command_rx.recv().await {
 			match command {
 				SessionCommand::StartStream(video_stream_context, audio_stream_context) => {
-					let video_stream = VideoStream::new(self.config.clone(), video_stream_context, stop_signal.clone());
-					let audio_stream = AudioStream::new(self.config.clone(), audio_stream_context, stop_signal.clone());
+					let hdr = video_stream_context.hdr;
+
+					// A failure to record shouldn't prevent the stream from starting.
+					let recorder = match self.config.stream.recording.mode {
+						RecordingMode::Disabled => None,
+						_ => Recorder::new(self.config.stream.recording.clone()).ok(),
+					};
+
+					// Audio and video are stamped by the same clock, so that the client can keep them in sync.
+					let clock = MediaClock::new();
+					let video_stream = VideoStream::new(self.config.clone(), video_stream_context, clock, recorder.clone(), stop_signal.clone());
+					let audio_stream = AudioStream::new(self.config.clone(), audio_stream_context, clock, recorder.clone(), stop_signal.clone());
 					let control_stream = match ControlStream::new(
 						self.config.clone(),
 						video_stream.clone(),
 						audio_stream.clone(),
+						recorder.clone(),
 						session_context.clone(),
+						hdr,
 						enet.clone(),
 						stop_signal.clone()
 					) {
@@ -153,6 +211,16 @@ impl SessionInner {
 					self.video_stream = Some(video_stream);
 					self.audio_stream = Some(audio_stream);
 					self.control_stream = Some(control_stream);
+					self.recorder = recorder;
+				},
+
+				SessionCommand::ReconfigureStream(video_stream_context) => {
+					let Some(video_stream) = &self.video_stream else {
+						log::warn!("Can't reconfigure a video stream that isn't running.");
+						continue;
+					};
+
+					let _ = video_stream.reconfigure(video_stream_context).await;
 				},
 
 				SessionCommand::StopStream => {
@@ -170,9 +238,21 @@ impl SessionInner {
 					};
 
 					session_context.keys = keys.clone();
+					if let Some(video_stream) = &self.video_stream {
+						let _ = video_stream.update_keys(keys.clone()).await;
+					}
 					let _ = audio_stream.update_keys(keys.clone()).await;
 					let _ = control_stream.update_keys(keys).await;
 				},
+
+				SessionCommand::SaveReplay => {
+					let Some(recorder) = &self.recorder else {
+						log::warn!("Can't save a replay, the session isn't being recorded.");
+						continue;
+					};
+
+					let _ = recorder.save_replay().await;
+				},
 			}
 		}
 
@@ -181,7 +261,8 @@ impl SessionInner {
 	}
 }
 
-fn run_command(command: &[String], context: &SessionContext) {
+/// Run a command of an application, which plays its audio on `virtual_sink` if there is one.
+fn run_command(command: &[String], context: &SessionContext, virtual_sink: Option<&VirtualSink>) {
 	if command.is_empty() {
 		log::warn!("Can't run an empty command.");
 		return;
@@ -200,7 +281,11 @@ fn run_command(command: &[String], context: &SessionContext) {
 	log::info!("Running command: {command:?}");
 
 	// Now run the command.
-	let _ = std::process::Command::new(&command[0])
+	let mut process = std::process::Command::new(&command[0]);
+	if let Some(virtual_sink) = virtual_sink {
+		process.env("PULSE_SINK", virtual_sink.name());
+	}
+	let _ = process
 		.args(&command[1..])
 		.stdout(Stdio::null())
 		.stderr(Stdio::null())
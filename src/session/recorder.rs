@@ -0,0 +1,503 @@
+use std::{collections::VecDeque, path::PathBuf, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant, SystemTime, UNIX_EPOCH}};
+
+use ffmpeg::{codec::packet::flag::Flags, format::context::Output, Rational, Rescale};
+use tokio::sync::mpsc;
+
+use crate::config::{RecordingConfig, RecordingMode};
+
+/// Number of commands that can be queued for the recorder, packets are dropped when the recorder can't keep up.
+const CHANNEL_SIZE: usize = 1024;
+
+/// Time base in which packet timestamps are computed, before rescaling them to the time base of the muxer.
+const MICROSECONDS: (i32, i32) = (1, 1_000_000);
+
+/// Parameters of an encoded stream, required to mux its packets.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum StreamParameters {
+	Video {
+		codec_id: ffmpeg::codec::Id,
+		width: u32,
+		height: u32,
+
+		/// Global headers of the encoder, for H264 and HEVC these are taken from the first key frame if empty.
+		extradata: Vec<u8>,
+	},
+
+	/// Audio is always encoded with Opus, channels are ordered as in WAVE files.
+	Audio {
+		sample_rate: u32,
+		channels: u8,
+
+		/// Layout of the Opus multistream, see `OpusConfiguration`.
+		streams: u8,
+		coupled_streams: u8,
+		mapping: Vec<u8>,
+	},
+}
+
+/// A packet produced by one of the encoders.
+#[derive(Clone, Debug)]
+pub struct EncodedPacket {
+	pub data: Vec<u8>,
+
+	/// Time at which the packet was produced, used to synchronize audio and video.
+	pub timestamp: Instant,
+
+	pub key_frame: bool,
+}
+
+#[derive(Debug)]
+enum RecorderCommand {
+	VideoParameters(StreamParameters),
+	AudioParameters(StreamParameters),
+	VideoPacket(EncodedPacket),
+	AudioPacket(EncodedPacket),
+	SaveReplay,
+}
+
+/// Copies the encoded audio and video packets of a session to a file.
+///
+/// Depending on the configuration, either the whole session is recorded, or the last seconds are kept in memory until a replay is saved.
+#[derive(Clone)]
+pub struct Recorder {
+	command_tx: mpsc::Sender<RecorderCommand>,
+
+	/// Interval at which key frames are requested, only in replay mode.
+	///
+	/// A replay starts at a key frame, so it can be up to this much longer than the configured duration.
+	keyframe_interval: Option<Duration>,
+
+	/// Set when a video packet was dropped, the video packets that follow can't be decoded until the next key frame.
+	needs_key_frame: Arc<AtomicBool>,
+
+	/// Number of commands dropped since the recorder last kept up, to log drops once instead of per packet.
+	dropped: Arc<AtomicU64>,
+}
+
+#[allow(clippy::result_unit_err)]
+impl Recorder {
+	pub fn new(config: RecordingConfig) -> Result<Self, ()> {
+		let directory = shellexpand::full(&config.directory.to_string_lossy())
+			.map_err(|e| log::error!("Failed to expand recording directory: {e}"))?
+			.to_string();
+		let directory = PathBuf::from(directory);
+		std::fs::create_dir_all(&directory)
+			.map_err(|e| log::error!("Failed to create recording directory {directory:?}: {e}"))?;
+
+		let keyframe_interval = match config.mode {
+			RecordingMode::Replay => Some(Duration::from_secs(config.replay_keyframe_interval.max(1))),
+			_ => None,
+		};
+		let (command_tx, command_rx) = mpsc::channel(CHANNEL_SIZE);
+		let inner = RecorderInner {
+			config,
+			directory,
+			video: None,
+			audio: None,
+			muxer: None,
+			replay_buffer: VecDeque::new(),
+		};
+		std::thread::Builder::new().name("recorder".to_string()).spawn(move || inner.run(command_rx))
+			.map_err(|e| log::error!("Failed to start recorder thread: {e}"))?;
+
+		Ok(Self {
+			command_tx,
+			keyframe_interval,
+			needs_key_frame: Arc::new(AtomicBool::new(false)),
+			dropped: Arc::new(AtomicU64::new(0)),
+		})
+	}
+
+	/// Interval at which the video encoder should produce key frames, if the recorder needs them.
+	pub fn keyframe_interval(&self) -> Option<Duration> {
+		self.keyframe_interval
+	}
+
+	/// Set the parameters of the video packets that follow, this is called whenever an encoder is created.
+	pub fn set_video_parameters(&self, parameters: StreamParameters) {
+		self.send(RecorderCommand::VideoParameters(parameters));
+	}
+
+	/// Set the parameters of the audio packets that follow, this is called whenever an encoder is created.
+	pub fn set_audio_parameters(&self, parameters: StreamParameters) {
+		self.send(RecorderCommand::AudioParameters(parameters));
+	}
+
+	/// Whether the recorder dropped video packets and needs a key frame to continue recording.
+	pub fn needs_key_frame(&self) -> bool {
+		self.needs_key_frame.load(Ordering::Relaxed)
+	}
+
+	pub fn record_video(&self, packet: EncodedPacket) {
+		// After a dropped packet, skip everything up to the next key frame instead of recording a corrupted video.
+		if !packet.key_frame && self.needs_key_frame() {
+			self.dropped.fetch_add(1, Ordering::Relaxed);
+			return;
+		}
+
+		let key_frame = packet.key_frame;
+		if self.send(RecorderCommand::VideoPacket(packet)) {
+			if key_frame {
+				self.needs_key_frame.store(false, Ordering::Relaxed);
+			}
+		} else {
+			self.needs_key_frame.store(true, Ordering::Relaxed);
+		}
+	}
+
+	pub fn record_audio(&self, packet: EncodedPacket) {
+		self.send(RecorderCommand::AudioPacket(packet));
+	}
+
+	/// Write the contents of the replay buffer to a file.
+	pub async fn save_replay(&self) -> Result<(), ()> {
+		self.command_tx.send(RecorderCommand::SaveReplay).await
+			.map_err(|e| log::error!("Failed to send SaveReplay command: {e}"))
+	}
+
+	/// Send a command without blocking, since packets are recorded from the encoder threads.
+	///
+	/// Returns false if the command was dropped.
+	fn send(&self, command: RecorderCommand) -> bool {
+		match self.command_tx.try_send(command) {
+			Ok(()) => {
+				let dropped = self.dropped.swap(0, Ordering::Relaxed);
+				if dropped > 0 {
+					log::info!("Recorder caught up after dropping {dropped} packets.");
+				}
+				true
+			},
+			Err(e) => {
+				if self.dropped.fetch_add(1, Ordering::Relaxed) == 0 {
+					log::warn!("Failed to send command to recorder, dropping packets until it catches up: {e}");
+				}
+				false
+			},
+		}
+	}
+}
+
+struct RecorderInner {
+	config: RecordingConfig,
+	directory: PathBuf,
+	video: Option<StreamParameters>,
+	audio: Option<StreamParameters>,
+
+	/// The file the session is recorded to, only used when recording the whole session.
+	muxer: Option<Muxer>,
+
+	/// Packets of the last seconds, starting with a video key frame, only used in replay mode.
+	replay_buffer: VecDeque<RecorderCommand>,
+}
+
+impl RecorderInner {
+	fn run(mut self, mut command_rx: mpsc::Receiver<RecorderCommand>) {
+		log::info!("Recording in {:?} mode to {:?}.", self.config.mode, self.directory);
+
+		while let Some(command) = command_rx.blocking_recv() {
+			match command {
+				RecorderCommand::VideoParameters(parameters) => {
+					if self.video.as_ref() == Some(&parameters) {
+						continue;
+					}
+
+					// The new packets can't be added to the existing file, so start a new one.
+					log::debug!("Video parameters changed to {parameters:?}.");
+					self.finish();
+					self.replay_buffer.clear();
+					self.video = Some(parameters);
+				},
+				RecorderCommand::AudioParameters(parameters) => {
+					self.audio = Some(parameters);
+				},
+				RecorderCommand::VideoPacket(packet) => {
+					match self.config.mode {
+						RecordingMode::Replay => self.buffer(RecorderCommand::VideoPacket(packet)),
+						_ => self.record_video(packet),
+					}
+				},
+				RecorderCommand::AudioPacket(packet) => {
+					match self.config.mode {
+						RecordingMode::Replay => self.buffer(RecorderCommand::AudioPacket(packet)),
+						_ => {
+							if let Some(muxer) = &mut self.muxer {
+								let _ = muxer.write_audio(&packet);
+							}
+						},
+					}
+				},
+				RecorderCommand::SaveReplay => {
+					if self.config.mode != RecordingMode::Replay {
+						log::warn!("Can't save a replay, recording mode is {:?}.", self.config.mode);
+						continue;
+					}
+
+					let _ = self.save_replay();
+				},
+			}
+		}
+
+		self.finish();
+		log::debug!("Recorder closing.");
+	}
+
+	fn record_video(&mut self, packet: EncodedPacket) {
+		if self.muxer.is_none() {
+			// Files have to start with a key frame.
+			if !packet.key_frame {
+				return;
+			}
+
+			let Some(video) = &self.video else {
+				log::warn!("Received a video packet before the video parameters, ignoring it.");
+				return;
+			};
+
+			let path = self.output_path("session");
+			self.muxer = Muxer::new(path, video, self.audio.as_ref(), &packet).ok();
+		}
+
+		if let Some(muxer) = &mut self.muxer {
+			if muxer.write_video(&packet).is_err() {
+				self.finish();
+			}
+		}
+	}
+
+	/// Add a packet to the replay buffer and drop packets that are too old.
+	fn buffer(&mut self, command: RecorderCommand) {
+		match &command {
+			RecorderCommand::VideoPacket(packet) => {
+				// The buffer has to start with a key frame.
+				if self.replay_buffer.is_empty() && !packet.key_frame {
+					return;
+				}
+
+				// Drop everything before the most recent key frame that is old enough to cover the replay duration.
+				if packet.key_frame {
+					let replay_duration = Duration::from_secs(self.config.replay_duration);
+					let start = self.replay_buffer.iter()
+						.rposition(|command| matches!(
+							command,
+							RecorderCommand::VideoPacket(buffered) if buffered.key_frame && packet.timestamp - buffered.timestamp >= replay_duration
+						));
+					if let Some(start) = start {
+						self.replay_buffer.drain(..start);
+					}
+				}
+			},
+			RecorderCommand::AudioPacket(_) => {
+				if self.replay_buffer.is_empty() {
+					return;
+				}
+			},
+			_ => unreachable!("Only packets are buffered."),
+		}
+
+		self.replay_buffer.push_back(command);
+	}
+
+	fn save_replay(&mut self) -> Result<(), ()> {
+		let Some(RecorderCommand::VideoPacket(first_packet)) = self.replay_buffer.front() else {
+			log::warn!("Can't save a replay, no video was recorded yet.");
+			return Err(());
+		};
+		let video = self.video.as_ref()
+			.ok_or_else(|| log::warn!("Can't save a replay without video parameters."))?;
+
+		let path = self.output_path("replay");
+		let mut muxer = Muxer::new(path.clone(), video, self.audio.as_ref(), first_packet)?;
+		for command in &self.replay_buffer {
+			match command {
+				RecorderCommand::VideoPacket(packet) => muxer.write_video(packet)?,
+				RecorderCommand::AudioPacket(packet) => muxer.write_audio(packet)?,
+				_ => {},
+			}
+		}
+		muxer.finish()?;
+
+		log::info!("Saved replay to {path:?}.");
+		Ok(())
+	}
+
+	fn finish(&mut self) {
+		if let Some(muxer) = self.muxer.take() {
+			let path = muxer.path.clone();
+			if muxer.finish().is_ok() {
+				log::info!("Saved recording to {path:?}.");
+			}
+		}
+	}
+
+	fn output_path(&self, prefix: &str) -> PathBuf {
+		let timestamp = SystemTime::now()
+			.duration_since(UNIX_EPOCH)
+			.map(|duration| duration.as_secs())
+			.unwrap_or_default();
+		self.directory.join(format!("{prefix}-{timestamp}.{}", self.config.format))
+	}
+}
+
+/// Writes packets to a file, using the container format that matches the file extension.
+struct Muxer {
+	output: Output,
+	path: PathBuf,
+	video_stream: (usize, Rational),
+	audio_stream: Option<(usize, Rational)>,
+
+	/// Timestamp of the first video packet, all packets are timed relative to this.
+	origin: Instant,
+}
+
+impl Muxer {
+	/// Create the file, `key_frame` is the first video packet that will be written.
+	fn new(
+		path: PathBuf,
+		video: &StreamParameters,
+		audio: Option<&StreamParameters>,
+		key_frame: &EncodedPacket,
+	) -> Result<Self, ()> {
+		let mut output = ffmpeg::format::output(&path)
+			.map_err(|e| log::error!("Failed to create recording {path:?}: {e}"))?;
+
+		let video_index = add_stream(&mut output, video, key_frame)?;
+		let audio_index = match audio {
+			Some(audio) => Some(add_stream(&mut output, audio, key_frame)?),
+			None => None,
+		};
+
+		output.write_header()
+			.map_err(|e| log::error!("Failed to write header of recording: {e}"))?;
+
+		// The muxer may change the time base of the streams when writing the header.
+		let time_base = |index: usize| output.stream(index).map(|stream| stream.time_base()).unwrap_or(Rational(1, 1000));
+		let video_stream = (video_index, time_base(video_index));
+		let audio_stream = audio_index.map(|index| (index, time_base(index)));
+
+		log::debug!("Started recording to {path:?}.");
+		Ok(Self { output, path, video_stream, audio_stream, origin: key_frame.timestamp })
+	}
+
+	fn write_video(&mut self, packet: &EncodedPacket) -> Result<(), ()> {
+		self.write(self.video_stream, packet)
+	}
+
+	fn write_audio(&mut self, packet: &EncodedPacket) -> Result<(), ()> {
+		match self.audio_stream {
+			Some(stream) => self.write(stream, packet),
+			None => Ok(()),
+		}
+	}
+
+	fn write(&mut self, (index, time_base): (usize, Rational), packet: &EncodedPacket) -> Result<(), ()> {
+		// Audio captured before the first video frame has no place in the file.
+		if packet.timestamp < self.origin {
+			return Ok(());
+		}
+
+		let timestamp = ((packet.timestamp - self.origin).as_micros() as i64).rescale(MICROSECONDS, time_base);
+
+		let mut output_packet = ffmpeg::Packet::copy(&packet.data);
+		output_packet.set_stream(index);
+		output_packet.set_pts(Some(timestamp));
+		output_packet.set_dts(Some(timestamp));
+		if packet.key_frame {
+			output_packet.set_flags(Flags::KEY);
+		}
+
+		output_packet.write_interleaved(&mut self.output)
+			.map_err(|e| log::error!("Failed to write packet to recording: {e}"))
+	}
+
+	fn finish(mut self) -> Result<(), ()> {
+		self.output.write_trailer()
+			.map_err(|e| log::error!("Failed to finish recording {:?}: {e}", self.path))
+	}
+}
+
+/// Add a stream with the given parameters to the output, returns the index of the stream.
+fn add_stream(output: &mut Output, parameters: &StreamParameters, key_frame: &EncodedPacket) -> Result<usize, ()> {
+	let codec_id = match parameters {
+		StreamParameters::Video { codec_id, .. } => *codec_id,
+		StreamParameters::Audio { .. } => ffmpeg::codec::Id::OPUS,
+	};
+
+	let mut stream = output.add_stream(codec_id)
+		.map_err(|e| log::error!("Failed to add {codec_id:?} stream to recording: {e}"))?;
+
+	unsafe {
+		let codec_parameters = (*stream.as_mut_ptr()).codecpar;
+		(*codec_parameters).codec_id = codec_id.into();
+
+		match parameters {
+			StreamParameters::Video { codec_id, width, height, extradata } => {
+				stream.set_time_base((1, 90_000));
+				(*codec_parameters).codec_type = ffmpeg::sys::AVMediaType::AVMEDIA_TYPE_VIDEO;
+				(*codec_parameters).width = *width as i32;
+				(*codec_parameters).height = *height as i32;
+
+				// Encoders without global headers put the parameter sets in the key frames, muxers can extract them from there.
+				let extradata = match codec_id {
+					ffmpeg::codec::Id::H264 | ffmpeg::codec::Id::HEVC if extradata.is_empty() => &key_frame.data,
+					_ => extradata,
+				};
+				set_extradata(codec_parameters, extradata)?;
+			},
+			StreamParameters::Audio { sample_rate, channels, streams, coupled_streams, mapping } => {
+				stream.set_time_base((1, *sample_rate as i32));
+				(*codec_parameters).codec_type = ffmpeg::sys::AVMediaType::AVMEDIA_TYPE_AUDIO;
+				(*codec_parameters).sample_rate = *sample_rate as i32;
+				ffmpeg::sys::av_channel_layout_default(&mut (*codec_parameters).ch_layout, *channels as i32);
+				set_extradata(codec_parameters, &opus_header(*sample_rate, *channels, *streams, *coupled_streams, mapping))?;
+			},
+		}
+	}
+
+	Ok(stream.index())
+}
+
+/// Copy the extradata in to memory allocated by FFmpeg, which frees it together with the parameters.
+unsafe fn set_extradata(codec_parameters: *mut ffmpeg::sys::AVCodecParameters, extradata: &[u8]) -> Result<(), ()> {
+	if extradata.is_empty() {
+		return Ok(());
+	}
+
+	let buffer = ffmpeg::sys::av_mallocz(extradata.len() + ffmpeg::sys::AV_INPUT_BUFFER_PADDING_SIZE as usize) as *mut u8;
+	if buffer.is_null() {
+		log::error!("Failed to allocate extradata.");
+		return Err(());
+	}
+	std::ptr::copy_nonoverlapping(extradata.as_ptr(), buffer, extradata.len());
+
+	(*codec_parameters).extradata = buffer;
+	(*codec_parameters).extradata_size = extradata.len() as i32;
+
+	Ok(())
+}
+
+/// Create the identification header of an Opus stream (RFC 7845, section 5.1), which containers use as extradata.
+fn opus_header(sample_rate: u32, channels: u8, streams: u8, coupled_streams: u8, mapping: &[u8]) -> Vec<u8> {
+	let mut header = Vec::with_capacity(21 + mapping.len());
+	header.extend(b"OpusHead");
+	header.push(1); // Version.
+	header.push(channels);
+	header.extend(0u16.to_le_bytes()); // Pre-skip, the encoder runs in low delay mode so we don't skip anything.
+	header.extend(sample_rate.to_le_bytes());
+	header.extend(0i16.to_le_bytes()); // Output gain.
+
+	if channels <= 2 {
+		header.push(0); // Channel mapping family, mono or stereo.
+		return header;
+	}
+
+	// Channel mapping family 1 orders the channels as Vorbis does, so reorder the mapping from the WAVE order.
+	let vorbis_order: &[usize] = match channels {
+		6 => &[0, 2, 1, 4, 5, 3],
+		_ => &[0, 2, 1, 6, 7, 4, 5, 3],
+	};
+	header.push(1); // Channel mapping family, Vorbis channel order.
+	header.push(streams);
+	header.push(coupled_streams);
+	header.extend(vorbis_order.iter().map(|&channel| mapping[channel]));
+	header
+}
@@ -1,201 +1,137 @@
-use std::{cell::RefCell, ops::Deref, rc::Rc};
-
-use pulse::{
-	context::{Context, FlagSet},
-	def::BufferAttr,
-	mainloop::standard::{IterateResult, Mainloop},
-	proplist::Proplist,
-	sample::Spec
-};
+use std::time::{Duration, Instant};
+
 use tokio::sync::mpsc::Sender;
 
-fn get_default_sink_name() -> Result<String, ()> {
-	// Create a new PulseAudio context
-	let mainloop = Rc::new(RefCell::new(Mainloop::new()
-
-		.ok_or_else(|| log::error!("Failed to create pulseaudio client."))?));
-
-	let mut proplist = Proplist::new()
-		.ok_or_else(|| log::error!("Failed to create pulseaudio proplist."))?;
-    proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "Moonshine")
-        .map_err(|()| log::error!("Failed to set pulseaudio application name."))?;
-	let context = Rc::new(RefCell::new(
-		Context::new_with_proplist(mainloop.borrow().deref(), "Moonshine context", &proplist)
-			.ok_or_else(|| log::error!("Failed to create pulseaudio context."))?
-	));
-
-	context.borrow_mut().connect(None, FlagSet::NOFLAGS, None)
-		.map_err(|e| log::error!("Failed to connect to pulseaudio server: {e}"))?;
-
-	// Wait for context to be ready.
-	loop {
-		match mainloop.borrow_mut().iterate(false) {
-			IterateResult::Quit(_) | IterateResult::Err(_) => {
-				log::error!("Failed to run pulseaudio main loop.");
-				return Err(());
-			},
-			IterateResult::Success(_) => {}
-		}
+use crate::config::{AudioBackend, AudioSourceConfig, AudioStreamConfig};
 
-		match context.borrow().get_state() {
-			pulse::context::State::Unconnected
-			| pulse::context::State::Connecting
-			| pulse::context::State::Authorizing
-			| pulse::context::State::SettingName => {}
-			pulse::context::State::Failed | pulse::context::State::Terminated => {
-				log::error!("Failed to run context.");
-				return Err(());
-			}
-			pulse::context::State::Ready => break
-		}
-	}
+#[cfg(feature = "pipewire")]
+mod pipewire;
+mod pulse;
+mod synthetic;
+pub use self::pulse::VirtualSink;
 
-	// Start operation to get server info.
-	let result = Rc::new(RefCell::new(None));
-	let operation = {
-		let result = result.clone();
-		context.borrow().introspect().get_server_info(move |info| {
-			let name = match info.default_sink_name.as_ref() {
-				Some(name) => name,
-				None => {
-					log::error!("Failed to receive default sink name.");
-					return;
-				}
-			};
-			*result.borrow_mut() = Some(name.to_string());
-		})
-	};
-
-	// Wait for operation to finish.
-	loop {
-		match mainloop.borrow_mut().iterate(false) {
-			IterateResult::Quit(_) | IterateResult::Err(_) => {
-				log::error!("Failed to run pulseaudio main loop.");
-				return Err(());
-			},
-			IterateResult::Success(_) => {}
-		};
-		match operation.get_state() {
-			pulse::operation::State::Running => {}
-			pulse::operation::State::Cancelled => {
-				log::error!("Failed to get default sink name.");
-				return Err(());
-			}
-			pulse::operation::State::Done => break
-		}
-	}
+#[cfg(not(feature = "pipewire"))]
+mod pipewire {
+	use tokio::sync::mpsc::Sender;
 
-	result.take().ok_or_else(|| log::error!("Failed to get default sink name result."))
+	use super::{AudioFragment, CaptureFormat};
+
+	pub async fn start(_format: CaptureFormat, _target: Option<&str>, _audio_tx: Sender<AudioFragment>) -> Result<(), ()> {
+		log::warn!("Moonshine was built without PipeWire support, enable the 'pipewire' feature to capture audio with PipeWire.");
+		Err(())
+	}
 }
 
-pub struct AudioCapture {
-	sample_rate: u32,
-	channels: u8,
+/// Delay before the first attempt to reconnect to the audio server.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(100);
+
+/// Maximum delay between attempts to reconnect to the audio server.
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(5);
+
+/// Format of the captured audio, every fragment holds the interleaved signed 16-bit samples of a single packet.
+#[derive(Clone, Copy, Debug)]
+pub struct CaptureFormat {
+	pub sample_rate: u32,
+	pub channels: u8,
+
+	/// Duration of a single packet in milliseconds.
+	pub packet_duration: u32,
 }
 
-impl AudioCapture {
-	pub async fn new(audio_tx: Sender<Vec<i16>>) -> Result<Self, ()> {
-		let channels = 2u8;
-		let sample_rate = 48000u32;
-
-		let default_sink_name = match get_default_sink_name() {
-			Ok(name) => name,
-			Err(()) => {
-				return Err(());
-			}
-		};
-		let monitor_name = format!("{default_sink_name}.monitor");
-
-		let sample_spec = Spec {
-			format: pulse::sample::Format::S16le,
-			channels,
-			rate: sample_rate,
-		};
-
-		// Connect to the PulseAudio server.
-		let stream = pulse_simple::Simple::new(
-			None,                             // Use default server.
-			"Moonshine audio capture",        // Stream description.
-			pulse::stream::Direction::Record, // Direction of audio (recording vs playback).
-			Some(&monitor_name),              // Specify input device.
-			"moonshine",                      // Stream name.
-			&sample_spec,                     // Sample specification.
-			None,                             // Use default channel map.
-			Some(&BufferAttr {
-				maxlength: std::mem::size_of::<i16>() as u32 * sample_rate * channels as u32 * 5 / 1000,
-				tlength: std::u32::MAX,
-				prebuf: std::u32::MAX,
-				minreq: std::u32::MAX,
-				fragsize: std::u32::MAX,
-			}),
-		).map_err(|e| log::error!("Failed to create audio capture device: {e}"));
-
-		let stream = match stream {
-			Ok(stream) => stream,
-			Err(()) => {
-				return Err(());
-			},
-		};
+impl CaptureFormat {
+	/// Number of samples (of all channels combined) in a fragment.
+	pub fn fragment_samples(&self) -> usize {
+		(self.sample_rate * self.packet_duration / 1000) as usize * self.channels as usize
+	}
 
-		log::info!("Recording from source: {monitor_name}");
+	/// Size of a fragment in bytes.
+	pub fn fragment_size(&self) -> usize {
+		self.fragment_samples() * std::mem::size_of::<i16>()
+	}
 
-		let inner = AudioCaptureInner { audio_tx };
-		tokio::task::spawn_blocking(move || {
-			tokio::runtime::Handle::current().block_on(inner.run(stream))
-		});
+	/// Time it takes to play `samples` samples (of all channels combined).
+	pub fn duration(&self, samples: usize) -> Duration {
+		Duration::from_secs_f64(samples as f64 / self.channels.max(1) as f64 / self.sample_rate as f64)
+	}
 
-		Ok(Self { sample_rate, channels })
+	/// Time at which the first of `samples` samples was captured, if the last of them was just captured.
+	pub fn captured_at(&self, samples: usize) -> Instant {
+		let now = Instant::now();
+		now.checked_sub(self.duration(samples)).unwrap_or(now)
 	}
+}
 
-	pub fn sample_rate(&self) -> u32 {
-		self.sample_rate
+/// The audio of a single packet.
+pub struct AudioFragment {
+	/// Interleaved samples of all channels.
+	pub samples: Vec<i16>,
+
+	/// Time at which the first sample was captured.
+	pub captured_at: Instant,
+}
+
+/// Delay between attempts to reconnect to the audio server, which doubles after every failed attempt.
+struct Backoff {
+	delay: Duration,
+}
+
+impl Default for Backoff {
+	fn default() -> Self {
+		Self { delay: MIN_RECONNECT_DELAY }
 	}
+}
 
-	pub fn channels(&self) -> u8 {
-		self.channels
+impl Backoff {
+	fn next_delay(&mut self) -> Duration {
+		let delay = self.delay;
+		self.delay = (self.delay * 2).min(MAX_RECONNECT_DELAY);
+		delay
 	}
 }
 
-struct AudioCaptureInner {
-	/// Channel to communicate audio fragments over.
-	audio_tx: Sender<Vec<i16>>,
+pub struct AudioCapture {
+	sample_rate: u32,
 }
 
-impl AudioCaptureInner {
-	async fn run(self, stream: pulse_simple::Simple) -> Result<(), ()> {
-		// Start recording.
-		loop {
-			// Allocate buffer for recording.
-			let mut buffer = vec![0u8; 480];
-
-			match stream.read(&mut buffer) {
-				Ok(()) => {
-					// Convert Vec<u8> to Vec<i16>.
-					let samples = unsafe {
-						Vec::from_raw_parts(
-							buffer.as_ptr() as *mut i16,
-							buffer.len() / std::mem::size_of::<i16>(),
-							buffer.len() / std::mem::size_of::<i16>(),
-						)
-					};
-
-					// Forget about our buffer, ownership has been transferred to samples.
-					std::mem::forget(buffer);
-
-					match self.audio_tx.send(samples).await {
-						Ok(()) => {},
-						Err(e) => {
-							log::debug!("Received error while sending audio sample: {e}");
-							log::info!("Closing audio capture because the receiving end was dropped.");
-							return Err(());
-						},
-					}
-				},
-				Err(e) => {
-					log::error!("Failed to read audio data: {}", e);
-					return Err(());
+impl AudioCapture {
+	/// Capture `channels` channels (1, 2, 6 or 8) with the configured backend, in fragments of `packet_duration` milliseconds.
+	/// If a synthetic source is configured, the audio is generated instead.
+	///
+	/// Capturing stops once `audio_tx` is closed, until then the backends reconnect when the audio server goes away.
+	pub async fn new(config: &AudioStreamConfig, channels: u8, packet_duration: u32, audio_tx: Sender<AudioFragment>) -> Result<Self, ()> {
+		let format = CaptureFormat { sample_rate: 48000, channels, packet_duration };
+		let target = config.target.as_deref();
+
+		match (&config.source, config.backend) {
+			(AudioSourceConfig::Capture, AudioBackend::Auto) => {
+				if pipewire::start(format, target, audio_tx.clone()).await.is_err() {
+					log::info!("Failed to capture audio with PipeWire, falling back to PulseAudio.");
+					pulse::start(format, target, audio_tx).await?;
 				}
-			}
+			},
+			(AudioSourceConfig::Capture, AudioBackend::PipeWire) => pipewire::start(format, target, audio_tx).await?,
+			(AudioSourceConfig::Capture, AudioBackend::Pulse) => pulse::start(format, target, audio_tx).await?,
+			(source, _) => synthetic::start(format, source, audio_tx).await?,
 		}
+
+		Ok(Self { sample_rate: format.sample_rate })
+	}
+
+	pub fn sample_rate(&self) -> u32 {
+		self.sample_rate
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn fragments_hold_a_single_packet() {
+		let format = CaptureFormat { sample_rate: 48000, channels: 2, packet_duration: 5 };
+		assert_eq!(format.fragment_samples(), 480);
+		assert_eq!(format.fragment_size(), 960);
+		assert_eq!(format.duration(format.fragment_samples()), Duration::from_millis(5));
+		assert_eq!(CaptureFormat { channels: 8, packet_duration: 10, ..format }.fragment_samples(), 3840);
 	}
 }
@@ -0,0 +1,292 @@
+use std::{cell::Cell, rc::Rc, time::{Duration, Instant}};
+
+use pipewire as pw;
+use pw::spa;
+use tokio::sync::{mpsc::{error::TrySendError, Sender}, oneshot};
+
+use super::{AudioFragment, Backoff, CaptureFormat};
+
+/// Time to wait for a stream to connect to its target, before giving up on it.
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Capture from the PipeWire node with the name `target`, or from the default sink if no target is given.
+///
+/// The target can be any node that produces audio, such as the output stream of a single application.
+/// Returns once the stream is connected, or an error if it couldn't connect so that another backend can be used.
+pub async fn start(format: CaptureFormat, target: Option<&str>, audio_tx: Sender<AudioFragment>) -> Result<(), ()> {
+	let (ready_tx, ready_rx) = oneshot::channel();
+	let target = target.map(str::to_string);
+
+	// PipeWire objects can't be moved between threads, so they are created and used in a dedicated thread.
+	std::thread::Builder::new()
+		.name("pipewire-capture".to_string())
+		.spawn(move || run(format, target, audio_tx, ready_tx))
+		.map_err(|e| log::error!("Failed to start PipeWire capture thread: {e}"))?;
+
+	ready_rx.await
+		.map_err(|_| log::warn!("PipeWire capture stopped before the stream connected."))?
+}
+
+fn run(format: CaptureFormat, target: Option<String>, audio_tx: Sender<AudioFragment>, ready_tx: oneshot::Sender<Result<(), ()>>) {
+	pw::init();
+
+	let mut ready_tx = Some(ready_tx);
+	let mut backoff = Backoff::default();
+	while !audio_tx.is_closed() {
+		let first_attempt = ready_tx.is_some();
+		let connected = Rc::new(Cell::new(false));
+
+		// The caller is told whether the stream connected through `ready_tx`, if it is dropped before that it receives an error.
+		log::info!("Connecting to PipeWire node: {}", target.as_deref().unwrap_or("default sink"));
+		if let Ok(capture) = PipeWireCapture::new(format, target.as_deref(), audio_tx.clone(), ready_tx.take(), connected.clone()) {
+			capture.run(&audio_tx, &connected);
+		}
+
+		if connected.get() {
+			backoff = Backoff::default();
+		} else if first_attempt {
+			// If the first connection fails PipeWire is likely not running, so the caller chooses another backend.
+			return;
+		}
+
+		if !audio_tx.is_closed() {
+			// The encoder keeps running, so the stream continues where it left off once we are reconnected.
+			let delay = backoff.next_delay();
+			log::warn!("PipeWire audio capture stopped, reconnecting in {}ms.", delay.as_millis());
+			std::thread::sleep(delay);
+		}
+	}
+
+	log::debug!("PipeWire capture stopped.");
+}
+
+/// State that is shared with the callbacks of the stream.
+struct CaptureState {
+	/// Channel to communicate audio fragments over.
+	audio_tx: Sender<AudioFragment>,
+
+	/// Format of the captured audio, which determines the size and duration of a fragment.
+	format: CaptureFormat,
+
+	/// Samples that don't fill a complete fragment yet.
+	samples: Vec<i16>,
+
+	/// Tells the caller whether the stream connected, only for the first connection.
+	ready_tx: Option<oneshot::Sender<Result<(), ()>>>,
+
+	/// Set once the stream is connected to its target.
+	connected: Rc<Cell<bool>>,
+
+	mainloop: pw::main_loop::MainLoop,
+}
+
+/// The PipeWire objects that make up a capture stream, which are destroyed in the order of declaration.
+struct PipeWireCapture {
+	_listener: pw::stream::StreamListener<CaptureState>,
+	_stream: pw::stream::Stream,
+	_core: pw::core::Core,
+	_context: pw::context::Context,
+	mainloop: pw::main_loop::MainLoop,
+}
+
+impl PipeWireCapture {
+	fn new(
+		format: CaptureFormat,
+		target: Option<&str>,
+		audio_tx: Sender<AudioFragment>,
+		ready_tx: Option<oneshot::Sender<Result<(), ()>>>,
+		connected: Rc<Cell<bool>>,
+	) -> Result<Self, ()> {
+		let mainloop = pw::main_loop::MainLoop::new(None)
+			.map_err(|e| log::warn!("Failed to create PipeWire main loop: {e}"))?;
+		let context = pw::context::Context::new(&mainloop)
+			.map_err(|e| log::warn!("Failed to create PipeWire context: {e}"))?;
+		let core = context.connect(None)
+			.map_err(|e| log::warn!("Failed to connect to PipeWire: {e}"))?;
+
+		let mut properties = pw::properties::properties! {
+			*pw::keys::MEDIA_TYPE => "Audio",
+			*pw::keys::MEDIA_CATEGORY => "Capture",
+			*pw::keys::MEDIA_ROLE => "Game",
+			*pw::keys::APP_NAME => "Moonshine",
+		};
+
+		// Ask for a quantum of a single packet, to avoid buffering in PipeWire.
+		properties.insert(*pw::keys::NODE_LATENCY, format!("{}/{}", format.fragment_samples() / format.channels as usize, format.sample_rate));
+		match target {
+			// PulseAudio names the source that records a sink after the sink, PipeWire records the sink itself.
+			Some(target) if target.ends_with(".monitor") => {
+				properties.insert(*pw::keys::TARGET_OBJECT, target.trim_end_matches(".monitor"));
+				properties.insert(*pw::keys::STREAM_CAPTURE_SINK, "true");
+			},
+			Some(target) => properties.insert(*pw::keys::TARGET_OBJECT, target),
+			// Without a target, record what is played on the default sink instead of the default source.
+			None => properties.insert(*pw::keys::STREAM_CAPTURE_SINK, "true"),
+		}
+
+		let stream = pw::stream::Stream::new(&core, "Moonshine audio capture", properties)
+			.map_err(|e| log::error!("Failed to create PipeWire stream: {e}"))?;
+
+		let state = CaptureState {
+			audio_tx,
+			format,
+			samples: Vec::with_capacity(format.fragment_samples() * 2),
+			ready_tx,
+			connected,
+			mainloop: mainloop.clone(),
+		};
+
+		let listener = stream
+			.add_local_listener_with_user_data(state)
+			.state_changed(|_stream, state, old, new| {
+				log::debug!("PipeWire stream changed from {old:?} to {new:?}.");
+				match new {
+					// The stream is paused once PipeWire accepted it, and streams once its target produces audio.
+					pw::stream::StreamState::Paused | pw::stream::StreamState::Streaming => {
+						if !state.connected.replace(true) {
+							log::info!("PipeWire stream connected, recording audio.");
+						}
+						if let Some(ready_tx) = state.ready_tx.take() {
+							let _ = ready_tx.send(Ok(()));
+						}
+					},
+					pw::stream::StreamState::Error(e) => {
+						log::error!("PipeWire stream failed: {e}");
+						if let Some(ready_tx) = state.ready_tx.take() {
+							let _ = ready_tx.send(Err(()));
+						}
+						state.mainloop.quit();
+					},
+					// The stream only becomes unconnected when the connection to PipeWire is lost.
+					pw::stream::StreamState::Unconnected => {
+						log::warn!("PipeWire stream disconnected.");
+						state.mainloop.quit();
+					},
+					_ => {},
+				}
+			})
+			.process(|stream, state| {
+				let Some(mut buffer) = stream.dequeue_buffer() else {
+					log::trace!("No PipeWire buffer available.");
+					return;
+				};
+
+				let data = &mut buffer.datas_mut()[0];
+				let size = data.chunk().size() as usize;
+				let Some(bytes) = data.data() else {
+					return;
+				};
+
+				let bytes = &bytes[..size.min(bytes.len())];
+				state.samples.extend(bytes.chunks_exact(2).map(|sample| i16::from_le_bytes([sample[0], sample[1]])));
+
+				let fragment_samples = state.format.fragment_samples();
+				while state.samples.len() >= fragment_samples {
+					// The last buffered sample was just captured, so the fragment starts as long ago as the buffered samples take to play.
+					let captured_at = state.format.captured_at(state.samples.len());
+					let samples = state.samples.drain(..fragment_samples).collect();
+					match state.audio_tx.try_send(AudioFragment { samples, captured_at }) {
+						Ok(()) => {},
+						Err(TrySendError::Full(_)) => log::trace!("Audio channel is full, dropping audio fragment."),
+						Err(TrySendError::Closed(_)) => {
+							log::info!("Closing audio capture because the receiving end was dropped.");
+							state.mainloop.quit();
+							return;
+						},
+					}
+				}
+			})
+			.register()
+			.map_err(|e| log::error!("Failed to register PipeWire stream listener: {e}"))?;
+
+		let format_pod = format_pod(format)?;
+		let mut params = [
+			spa::pod::Pod::from_bytes(&format_pod)
+				.ok_or_else(|| log::error!("Failed to create PipeWire audio format."))?
+		];
+		stream.connect(
+			spa::utils::Direction::Input,
+			None,
+			pw::stream::StreamFlags::AUTOCONNECT | pw::stream::StreamFlags::MAP_BUFFERS,
+			&mut params,
+		)
+			.map_err(|e| log::error!("Failed to connect PipeWire stream: {e}"))?;
+
+		Ok(Self {
+			_listener: listener,
+			_stream: stream,
+			_core: core,
+			_context: context,
+			mainloop,
+		})
+	}
+
+	/// Capture until the stream fails, until it doesn't connect in time or until the receiving end of `audio_tx` is dropped.
+	fn run(&self, audio_tx: &Sender<AudioFragment>, connected: &Rc<Cell<bool>>) {
+		// The stream doesn't process anything while the node is idle, so check periodically whether capturing should stop.
+		let started = Instant::now();
+		let timer = self.mainloop.loop_().add_timer({
+			let mainloop = self.mainloop.clone();
+			let audio_tx = audio_tx.clone();
+			let connected = connected.clone();
+			move |_| {
+				if audio_tx.is_closed() {
+					log::info!("Closing audio capture because the receiving end was dropped.");
+					mainloop.quit();
+				} else if !connected.get() && started.elapsed() >= CONNECT_TIMEOUT {
+					log::warn!("PipeWire stream didn't connect within {} seconds.", CONNECT_TIMEOUT.as_secs());
+					mainloop.quit();
+				}
+			}
+		});
+		if let Err(e) = timer.update_timer(Some(Duration::from_secs(1)), Some(Duration::from_secs(1))).into_result() {
+			log::warn!("Failed to start PipeWire timer: {e}");
+		}
+
+		self.mainloop.run();
+	}
+}
+
+/// Serialize the raw audio format that is requested from PipeWire.
+fn format_pod(format: CaptureFormat) -> Result<Vec<u8>, ()> {
+	let positions: &[u32] = match format.channels {
+		1 => &[spa::sys::SPA_AUDIO_CHANNEL_MONO],
+		2 => &[spa::sys::SPA_AUDIO_CHANNEL_FL, spa::sys::SPA_AUDIO_CHANNEL_FR],
+		6 => &[
+			spa::sys::SPA_AUDIO_CHANNEL_FL, spa::sys::SPA_AUDIO_CHANNEL_FR,
+			spa::sys::SPA_AUDIO_CHANNEL_FC, spa::sys::SPA_AUDIO_CHANNEL_LFE,
+			spa::sys::SPA_AUDIO_CHANNEL_RL, spa::sys::SPA_AUDIO_CHANNEL_RR,
+		],
+		8 => &[
+			spa::sys::SPA_AUDIO_CHANNEL_FL, spa::sys::SPA_AUDIO_CHANNEL_FR,
+			spa::sys::SPA_AUDIO_CHANNEL_FC, spa::sys::SPA_AUDIO_CHANNEL_LFE,
+			spa::sys::SPA_AUDIO_CHANNEL_RL, spa::sys::SPA_AUDIO_CHANNEL_RR,
+			spa::sys::SPA_AUDIO_CHANNEL_SL, spa::sys::SPA_AUDIO_CHANNEL_SR,
+		],
+		channels => {
+			log::error!("Can't capture audio with {channels} channels.");
+			return Err(());
+		},
+	};
+
+	let mut position = [0; spa::param::audio::MAX_CHANNELS];
+	position[..positions.len()].copy_from_slice(positions);
+
+	// PipeWire remixes the node to this layout if it has a different layout.
+	let mut audio_info = spa::param::audio::AudioInfoRaw::new();
+	audio_info.set_format(spa::param::audio::AudioFormat::S16LE);
+	audio_info.set_rate(format.sample_rate);
+	audio_info.set_channels(format.channels as u32);
+	audio_info.set_position(position);
+
+	let object = spa::pod::Value::Object(spa::pod::Object {
+		type_: spa::sys::SPA_TYPE_OBJECT_Format,
+		id: spa::sys::SPA_PARAM_EnumFormat,
+		properties: audio_info.into(),
+	});
+
+	let (cursor, _) = spa::pod::serialize::PodSerializer::serialize(std::io::Cursor::new(Vec::new()), &object)
+		.map_err(|e| log::error!("Failed to serialize PipeWire audio format: {e:?}"))?;
+
+	Ok(cursor.into_inner())
+}
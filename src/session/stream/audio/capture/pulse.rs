@@ -0,0 +1,586 @@
+use std::{cell::{Cell, RefCell}, ops::Deref, rc::Rc, sync::{atomic::{AtomicBool, AtomicU32, Ordering}, Arc, Mutex}, thread::JoinHandle, time::Duration};
+
+use pulse::{
+	channelmap::Map,
+	context::{subscribe::InterestMaskSet, Context, FlagSet},
+	def::BufferAttr,
+	mainloop::standard::{IterateResult, Mainloop},
+	operation::Operation,
+	proplist::Proplist,
+	sample::Spec
+};
+use tokio::sync::mpsc::Sender;
+
+use super::{AudioFragment, Backoff, CaptureFormat};
+
+/// Number of virtual sinks that were created by this process, which makes the name of every sink unique.
+static VIRTUAL_SINK_COUNT: AtomicU32 = AtomicU32::new(0);
+
+/// Interval at which the default sink watcher handles server events.
+const DEFAULT_SINK_POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// A connection to the PulseAudio server, used to inspect and change the configuration of the server.
+struct PulseConnection {
+	mainloop: Rc<RefCell<Mainloop>>,
+	context: Rc<RefCell<Context>>,
+}
+
+impl PulseConnection {
+	fn new() -> Result<Self, ()> {
+		// Create a new PulseAudio context
+		let mainloop = Rc::new(RefCell::new(Mainloop::new()
+			.ok_or_else(|| log::error!("Failed to create pulseaudio client."))?));
+
+		let mut proplist = Proplist::new()
+			.ok_or_else(|| log::error!("Failed to create pulseaudio proplist."))?;
+		proplist.set_str(pulse::proplist::properties::APPLICATION_NAME, "Moonshine")
+			.map_err(|()| log::error!("Failed to set pulseaudio application name."))?;
+		let context = Rc::new(RefCell::new(
+			Context::new_with_proplist(mainloop.borrow().deref(), "Moonshine context", &proplist)
+				.ok_or_else(|| log::error!("Failed to create pulseaudio context."))?
+		));
+
+		context.borrow_mut().connect(None, FlagSet::NOFLAGS, None)
+			.map_err(|e| log::error!("Failed to connect to pulseaudio server: {e}"))?;
+
+		let connection = Self { mainloop, context };
+
+		// Wait for context to be ready.
+		loop {
+			connection.iterate(true)?;
+
+			match connection.context.borrow().get_state() {
+				pulse::context::State::Unconnected
+				| pulse::context::State::Connecting
+				| pulse::context::State::Authorizing
+				| pulse::context::State::SettingName => {}
+				pulse::context::State::Failed | pulse::context::State::Terminated => {
+					log::error!("Failed to run context.");
+					return Err(());
+				}
+				pulse::context::State::Ready => break
+			}
+		}
+
+		Ok(connection)
+	}
+
+	/// Run a single iteration of the main loop, which waits for events if `block` is set.
+	fn iterate(&self, block: bool) -> Result<(), ()> {
+		match self.mainloop.borrow_mut().iterate(block) {
+			IterateResult::Quit(_) | IterateResult::Err(_) => {
+				log::error!("Failed to run pulseaudio main loop.");
+				Err(())
+			},
+			IterateResult::Success(_) => Ok(()),
+		}
+	}
+
+	/// Run the main loop until `operation` is finished.
+	fn wait_for<G: ?Sized>(&self, operation: Operation<G>) -> Result<(), ()> {
+		loop {
+			self.iterate(true)?;
+
+			match operation.get_state() {
+				pulse::operation::State::Running => {}
+				pulse::operation::State::Cancelled => {
+					log::error!("Pulseaudio operation was cancelled.");
+					return Err(());
+				}
+				pulse::operation::State::Done => return Ok(()),
+			}
+		}
+	}
+
+	fn default_sink_name(&self) -> Result<String, ()> {
+		let result = Rc::new(RefCell::new(None));
+		let operation = {
+			let result = result.clone();
+			self.context.borrow().introspect().get_server_info(move |info| {
+				let name = match info.default_sink_name.as_ref() {
+					Some(name) => name,
+					None => {
+						log::error!("Failed to receive default sink name.");
+						return;
+					}
+				};
+				*result.borrow_mut() = Some(name.to_string());
+			})
+		};
+		self.wait_for(operation)?;
+
+		result.take().ok_or_else(|| log::error!("Failed to get default sink name result."))
+	}
+
+	fn set_default_sink(&self, name: &str) -> Result<(), ()> {
+		let result = Rc::new(RefCell::new(false));
+		let operation = {
+			let result = result.clone();
+			self.context.borrow_mut().set_default_sink(name, move |success| *result.borrow_mut() = success)
+		};
+		self.wait_for(operation)?;
+
+		if !result.take() {
+			log::error!("Failed to set the default sink to {name}.");
+			return Err(());
+		}
+
+		Ok(())
+	}
+
+	/// Load a module with the given arguments, returns the index of the loaded module.
+	fn load_module(&self, name: &str, arguments: &str) -> Result<u32, ()> {
+		let result = Rc::new(RefCell::new(None));
+		let operation = {
+			let result = result.clone();
+			self.context.borrow().introspect().load_module(name, arguments, move |index| *result.borrow_mut() = Some(index))
+		};
+		self.wait_for(operation)?;
+
+		match result.take() {
+			Some(index) if index != pulse::def::INVALID_INDEX => Ok(index),
+			_ => {
+				log::error!("Failed to load pulseaudio module {name}.");
+				Err(())
+			},
+		}
+	}
+
+	fn unload_module(&self, index: u32) -> Result<(), ()> {
+		let result = Rc::new(RefCell::new(false));
+		let operation = {
+			let result = result.clone();
+			self.context.borrow().introspect().unload_module(index, move |success| *result.borrow_mut() = success)
+		};
+		self.wait_for(operation)?;
+
+		if !result.take() {
+			log::error!("Failed to unload pulseaudio module {index}.");
+			return Err(());
+		}
+
+		Ok(())
+	}
+}
+
+impl Drop for PulseConnection {
+	fn drop(&mut self) {
+		self.context.borrow_mut().disconnect();
+	}
+}
+
+/// Channel positions for the given number of channels, in the order in which Moonlight outputs them.
+fn channel_positions(channels: u8) -> Result<&'static str, ()> {
+	match channels {
+		1 => Ok("mono"),
+		2 => Ok("front-left,front-right"),
+		6 => Ok("front-left,front-right,front-center,lfe,rear-left,rear-right"),
+		8 => Ok("front-left,front-right,front-center,lfe,rear-left,rear-right,side-left,side-right"),
+		_ => {
+			log::error!("Can't capture audio with {channels} channels.");
+			Err(())
+		},
+	}
+}
+
+/// Channel map for the given number of channels, in the order in which Moonlight outputs them.
+fn channel_map(channels: u8) -> Result<Map, ()> {
+	Map::new_from_string(channel_positions(channels)?)
+		.map_err(|()| log::error!("Failed to create channel map for {channels} channels."))
+}
+
+/// A null sink that exists for the duration of a session, so that streamed audio doesn't play on the speakers of the host.
+///
+/// This works with PulseAudio as well as with the PulseAudio server of PipeWire.
+pub struct VirtualSink {
+	/// Name of the sink, which is unique for every session.
+	name: String,
+
+	/// Index of the module that provides the sink.
+	module_index: u32,
+
+	/// Default sink before the virtual sink became the default, which is restored when the virtual sink is removed.
+	///
+	/// If another sink becomes the default during the session, that sink is restored instead.
+	previous_default_sink: Arc<Mutex<Option<String>>>,
+
+	/// Stops the thread that keeps the virtual sink the default sink.
+	stop_watcher: Arc<AtomicBool>,
+
+	/// Thread that keeps the virtual sink the default sink, if it was made the default.
+	watcher: Option<JoinHandle<()>>,
+}
+
+impl VirtualSink {
+	/// Create a sink with `channels` channels, which becomes the default sink if `make_default` is set.
+	pub async fn new(channels: u8, make_default: bool) -> Result<Self, ()> {
+		// Talking to PulseAudio blocks, so don't do it on the runtime.
+		tokio::task::spawn_blocking(move || Self::create(channels, make_default))
+			.await
+			.map_err(|e| log::error!("Failed to wait for the virtual sink to be created: {e}"))?
+	}
+
+	fn create(channels: u8, make_default: bool) -> Result<Self, ()> {
+		let connection = PulseConnection::new()?;
+
+		// A unique name avoids a conflict with a sink that was left behind, for example by a crashed process.
+		let name = format!("moonshine-{}-{}", std::process::id(), VIRTUAL_SINK_COUNT.fetch_add(1, Ordering::Relaxed));
+		let arguments = format!(
+			"sink_name={name} sink_properties=device.description=Moonshine rate=48000 channels={channels} channel_map={}",
+			channel_positions(channels)?,
+		);
+		let module_index = connection.load_module("module-null-sink", &arguments)?;
+		log::info!("Created virtual sink {name} with {channels} channels.");
+
+		let mut sink = Self {
+			name,
+			module_index,
+			previous_default_sink: Arc::new(Mutex::new(None)),
+			stop_watcher: Arc::new(AtomicBool::new(false)),
+			watcher: None,
+		};
+		if make_default {
+			// Without a previous default sink there is nothing to restore, but the virtual sink can still be the default.
+			*sink.previous_default_sink.lock()
+				.map_err(|e| log::error!("Failed to lock previous default sink: {e}"))? = connection.default_sink_name().ok();
+			connection.set_default_sink(&sink.name)?;
+			sink.watcher = Some(sink.keep_default()?);
+		}
+
+		Ok(sink)
+	}
+
+	/// Make the virtual sink the default sink again whenever another sink becomes the default, for example when headphones are plugged in.
+	///
+	/// Otherwise the application would play on the new default sink, which isn't streamed.
+	fn keep_default(&self) -> Result<JoinHandle<()>, ()> {
+		let name = self.name.clone();
+		let previous_default_sink = self.previous_default_sink.clone();
+		let stop_watcher = self.stop_watcher.clone();
+
+		spawn_default_sink_watcher(
+			"virtual-sink-watcher",
+			{
+				let stop_watcher = stop_watcher.clone();
+				move || stop_watcher.load(Ordering::Relaxed)
+			},
+			move |connection, default_sink| {
+				if default_sink == name || stop_watcher.load(Ordering::Relaxed) {
+					return;
+				}
+
+				log::info!("Default sink changed to {default_sink}, making virtual sink {name} the default sink again.");
+
+				// The new default sink is the one the user wants after the session.
+				if let Ok(mut previous_default_sink) = previous_default_sink.lock() {
+					*previous_default_sink = Some(default_sink.to_string());
+				}
+				let _ = connection.set_default_sink(&name);
+			},
+		)
+	}
+
+	pub fn name(&self) -> &str {
+		&self.name
+	}
+
+	/// Name of the source that records the audio played on this sink.
+	pub fn monitor_name(&self) -> String {
+		format!("{}.monitor", self.name)
+	}
+}
+
+impl Drop for VirtualSink {
+	fn drop(&mut self) {
+		let name = std::mem::take(&mut self.name);
+		let module_index = self.module_index;
+		let previous_default_sink = self.previous_default_sink.clone();
+		let watcher = self.watcher.take();
+		self.stop_watcher.store(true, Ordering::Relaxed);
+
+		let remove = move || {
+			// Stop keeping the virtual sink the default before the previous default sink is restored.
+			if let Some(watcher) = watcher {
+				let _ = watcher.join();
+			}
+
+			let previous_default_sink = previous_default_sink.lock().ok().and_then(|mut sink| sink.take());
+			remove_virtual_sink(&name, module_index, previous_default_sink.as_deref());
+		};
+
+		// Talking to PulseAudio blocks, so don't do it on the runtime if there is one.
+		match tokio::runtime::Handle::try_current() {
+			Ok(runtime) => drop(runtime.spawn_blocking(remove)),
+			Err(_) => remove(),
+		}
+	}
+}
+
+/// Unload the module of a virtual sink, after restoring the default sink to `previous_default_sink`.
+fn remove_virtual_sink(name: &str, module_index: u32, previous_default_sink: Option<&str>) {
+	let Ok(connection) = PulseConnection::new() else {
+		log::error!("Failed to remove virtual sink {name}.");
+		return;
+	};
+
+	if let Some(previous_default_sink) = previous_default_sink {
+		if connection.set_default_sink(previous_default_sink).is_ok() {
+			log::debug!("Restored default sink {previous_default_sink}.");
+		}
+	}
+
+	if connection.unload_module(module_index).is_ok() {
+		log::info!("Removed virtual sink {name}.");
+	}
+}
+
+/// Capture from the PulseAudio source with the name `source`, or from the monitor of the default sink if no source is given.
+///
+/// Without a source, capturing follows the default sink when it changes.
+/// Capturing reconnects when the stream fails, for example when the server restarts.
+pub async fn start(format: CaptureFormat, source: Option<&str>, audio_tx: Sender<AudioFragment>) -> Result<(), ()> {
+	// Connect once before starting the capture thread, so that the caller knows whether PulseAudio is available.
+	let (stream, source_name) = connect_stream(format, source)?;
+
+	let default_sink = match source {
+		Some(_) => None,
+		None => Some(DefaultSinkWatcher::new(audio_tx.clone())?),
+	};
+
+	let inner = PulseCaptureInner { audio_tx, format, source: source.map(str::to_string), default_sink };
+	tokio::task::spawn_blocking(move || {
+		tokio::runtime::Handle::current().block_on(inner.run(stream, source_name))
+	});
+
+	Ok(())
+}
+
+/// Open a record stream on `source`, or on the monitor of the default sink if no source is given.
+///
+/// Returns the stream and the name of the source it records.
+fn connect_stream(format: CaptureFormat, source: Option<&str>) -> Result<(pulse_simple::Simple, String), ()> {
+	let fragment_size = format.fragment_size();
+
+	let source_name = match source {
+		Some(source) => source.to_string(),
+		None => format!("{}.monitor", PulseConnection::new()?.default_sink_name()?),
+	};
+
+	// PulseAudio remixes the source to this channel map if the source has a different layout.
+	let channel_map = channel_map(format.channels)?;
+
+	let sample_spec = Spec {
+		format: pulse::sample::Format::S16le,
+		channels: format.channels,
+		rate: format.sample_rate,
+	};
+
+	// Connect to the PulseAudio server.
+	let stream = pulse_simple::Simple::new(
+		None,                             // Use default server.
+		"Moonshine audio capture",        // Stream description.
+		pulse::stream::Direction::Record, // Direction of audio (recording vs playback).
+		Some(&source_name),               // Specify input device.
+		"moonshine",                      // Stream name.
+		&sample_spec,                     // Sample specification.
+		Some(&channel_map),               // Channel map.
+		Some(&BufferAttr {
+			maxlength: fragment_size as u32 * 2,
+			tlength: std::u32::MAX,
+			prebuf: std::u32::MAX,
+			minreq: std::u32::MAX,
+			fragsize: fragment_size as u32,
+		}),
+	).map_err(|e| log::error!("Failed to create audio capture device: {e}"))?;
+
+	log::info!("Recording from PulseAudio source: {source_name}");
+
+	Ok((stream, source_name))
+}
+
+struct PulseCaptureInner {
+	/// Channel to communicate audio fragments over.
+	audio_tx: Sender<AudioFragment>,
+
+	format: CaptureFormat,
+
+	/// Source to record, the monitor of the default sink is recorded if this is not set.
+	source: Option<String>,
+
+	/// Reports changes of the default sink, if the default sink is recorded.
+	default_sink: Option<DefaultSinkWatcher>,
+}
+
+impl PulseCaptureInner {
+	async fn run(self, mut stream: pulse_simple::Simple, mut source_name: String) -> Result<(), ()> {
+		// Start recording.
+		loop {
+			// Allocate buffer for recording, which holds the audio for a single packet.
+			let mut buffer = vec![0u8; self.format.fragment_size()];
+
+			match stream.read(&mut buffer) {
+				Ok(()) => {
+					// The read returns once the last sample of the fragment is captured.
+					let captured_at = self.format.captured_at(self.format.fragment_samples());
+
+					// Convert Vec<u8> to Vec<i16>.
+					let samples = unsafe {
+						Vec::from_raw_parts(
+							buffer.as_ptr() as *mut i16,
+							buffer.len() / std::mem::size_of::<i16>(),
+							buffer.len() / std::mem::size_of::<i16>(),
+						)
+					};
+
+					// Forget about our buffer, ownership has been transferred to samples.
+					std::mem::forget(buffer);
+
+					match self.audio_tx.send(AudioFragment { samples, captured_at }).await {
+						Ok(()) => {},
+						Err(e) => {
+							log::debug!("Received error while sending audio sample: {e}");
+							log::info!("Closing audio capture because the receiving end was dropped.");
+							return Err(());
+						},
+					}
+				},
+				Err(e) => {
+					// The encoder keeps running, so the stream continues where it left off once we are reconnected.
+					log::warn!("Failed to read audio data from {source_name}: {e}, reconnecting.");
+					(stream, source_name) = self.reconnect().await?;
+					continue;
+				}
+			}
+
+			if let Some(default_sink) = self.default_sink.as_ref().and_then(DefaultSinkWatcher::take_change) {
+				if format!("{default_sink}.monitor") != source_name {
+					log::info!("Default sink changed to {default_sink}, switching audio capture.");
+					(stream, source_name) = self.reconnect().await?;
+				}
+			}
+		}
+	}
+
+	/// Connect a new stream, retrying until it succeeds or until the receiving end is dropped.
+	async fn reconnect(&self) -> Result<(pulse_simple::Simple, String), ()> {
+		let mut backoff = Backoff::default();
+		loop {
+			if self.audio_tx.is_closed() {
+				log::info!("Closing audio capture because the receiving end was dropped.");
+				return Err(());
+			}
+
+			match connect_stream(self.format, self.source.as_deref()) {
+				Ok(result) => return Ok(result),
+				Err(()) => {
+					let delay = backoff.next_delay();
+					log::warn!("Failed to reconnect audio capture, retrying in {}ms.", delay.as_millis());
+					tokio::time::sleep(delay).await;
+				},
+			}
+		}
+	}
+}
+
+/// Watches the PulseAudio server for changes of the default sink, in a thread of its own.
+///
+/// The watcher stops once the receiving end of the audio channel is dropped.
+struct DefaultSinkWatcher {
+	/// Name of the new default sink, if it changed since it was last taken.
+	changed: Arc<Mutex<Option<String>>>,
+}
+
+impl DefaultSinkWatcher {
+	fn new(audio_tx: Sender<AudioFragment>) -> Result<Self, ()> {
+		let changed = Arc::new(Mutex::new(None));
+
+		spawn_default_sink_watcher(
+			"default-sink-watcher",
+			move || audio_tx.is_closed(),
+			{
+				let changed = changed.clone();
+				move |_connection, default_sink| {
+					if let Ok(mut changed) = changed.lock() {
+						*changed = Some(default_sink.to_string());
+					}
+				}
+			},
+		)?;
+
+		Ok(Self { changed })
+	}
+
+	/// Take the name of the new default sink, if it changed.
+	fn take_change(&self) -> Option<String> {
+		self.changed.lock().ok()?.take()
+	}
+}
+
+/// Call `on_change` with the name of the new default sink whenever it changes, in a thread named `thread_name`.
+///
+/// The thread reconnects when it loses the connection to the server, and stops once `stopped` returns true.
+fn spawn_default_sink_watcher<S, C>(thread_name: &str, stopped: S, mut on_change: C) -> Result<JoinHandle<()>, ()>
+where
+	S: Fn() -> bool + Send + 'static,
+	C: FnMut(&PulseConnection, &str) + Send + 'static,
+{
+	std::thread::Builder::new()
+		.name(thread_name.to_string())
+		.spawn(move || {
+			let mut backoff = Backoff::default();
+			while !stopped() {
+				if watch_default_sink(&stopped, &mut on_change, &mut backoff).is_err() && !stopped() {
+					let delay = backoff.next_delay();
+					log::warn!("Lost connection to the PulseAudio server, watching the default sink again in {}ms.", delay.as_millis());
+					std::thread::sleep(delay);
+				}
+			}
+
+			log::debug!("Stopped watching the default sink.");
+		})
+		.map_err(|e| log::error!("Failed to start default sink watcher: {e}"))
+}
+
+/// Report changes of the default sink until the connection fails or until `stopped` returns true.
+fn watch_default_sink(
+	stopped: &dyn Fn() -> bool,
+	on_change: &mut dyn FnMut(&PulseConnection, &str),
+	backoff: &mut Backoff,
+) -> Result<(), ()> {
+	let connection = PulseConnection::new()?;
+	let mut default_sink = connection.default_sink_name()?;
+
+	// Any change to the server or to one of its sinks may have changed the default sink.
+	let dirty = Rc::new(Cell::new(false));
+	connection.context.borrow_mut().set_subscribe_callback(Some(Box::new({
+		let dirty = dirty.clone();
+		move |_facility, _operation, _index| dirty.set(true)
+	})));
+	let operation = connection.context.borrow_mut().subscribe(InterestMaskSet::SERVER | InterestMaskSet::SINK, |success| {
+		if !success {
+			log::error!("Failed to subscribe to pulseaudio server events.");
+		}
+	});
+	connection.wait_for(operation)?;
+
+	// A working connection resets the backoff for the next connection failure.
+	*backoff = Backoff::default();
+
+	// Iterate without blocking, so that we notice when we should stop.
+	while !stopped() {
+		connection.iterate(false)?;
+
+		if dirty.take() {
+			let new_default_sink = connection.default_sink_name()?;
+			if new_default_sink != default_sink {
+				log::debug!("Default sink changed from {default_sink} to {new_default_sink}.");
+				on_change(&connection, &new_default_sink);
+				default_sink = new_default_sink;
+			}
+		}
+
+		std::thread::sleep(DEFAULT_SINK_POLL_INTERVAL);
+	}
+
+	Ok(())
+}
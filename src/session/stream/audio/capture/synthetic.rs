@@ -0,0 +1,411 @@
+use std::{f64::consts::TAU, path::Path, time::Instant};
+
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+
+use crate::config::AudioSourceConfig;
+
+use super::{AudioFragment, CaptureFormat};
+
+/// Amplitude of the generated sine waves, relative to full scale (-12 dBFS).
+const AMPLITUDE: f64 = 0.25;
+
+/// Generate audio from `source` in real time, instead of capturing it.
+///
+/// This doesn't require an audio system, which makes it useful for testing.
+pub async fn start(format: CaptureFormat, source: &AudioSourceConfig, audio_tx: Sender<AudioFragment>) -> Result<(), ()> {
+	let signal = match source {
+		AudioSourceConfig::Capture => {
+			log::error!("Can't generate audio for a captured audio source.");
+			return Err(());
+		},
+		AudioSourceConfig::Tone { frequency } => Signal::Sweep {
+			start_frequency: *frequency,
+			end_frequency: *frequency,
+			duration: 1.0,
+		},
+		AudioSourceConfig::Sweep { start_frequency, end_frequency, duration } => {
+			if *duration <= 0.0 {
+				log::error!("Duration of the audio sweep must be positive, but it is {duration} seconds.");
+				return Err(());
+			}
+
+			Signal::Sweep { start_frequency: *start_frequency, end_frequency: *end_frequency, duration: *duration }
+		},
+		AudioSourceConfig::Wav { path } => {
+			let path = shellexpand::full(&path.to_string_lossy())
+				.map_err(|e| log::error!("Failed to expand WAV file path: {e}"))?
+				.to_string();
+			Signal::Samples(read_wav(Path::new(&path), format)?)
+		},
+	};
+
+	log::info!("Generating synthetic audio: {signal}");
+	let generator = SyntheticAudio { format, signal, phase: 0.0, position: 0 };
+	std::thread::Builder::new()
+		.name("synthetic-audio".to_string())
+		.spawn(move || generator.run(audio_tx))
+		.map_err(|e| log::error!("Failed to start synthetic audio thread: {e}"))?;
+
+	Ok(())
+}
+
+/// The signal that is generated.
+enum Signal {
+	/// A sine wave that sweeps linearly from `start_frequency` to `end_frequency` Hz in `duration` seconds.
+	///
+	/// A tone is a sweep with equal start and end frequencies.
+	Sweep { start_frequency: f64, end_frequency: f64, duration: f64 },
+
+	/// Interleaved samples in the capture format, which are played in a loop.
+	Samples(Vec<i16>),
+}
+
+impl std::fmt::Display for Signal {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match self {
+			Self::Sweep { start_frequency, end_frequency, .. } if start_frequency == end_frequency => {
+				write!(f, "{start_frequency} Hz tone")
+			},
+			Self::Sweep { start_frequency, end_frequency, duration } => {
+				write!(f, "sweep from {start_frequency} Hz to {end_frequency} Hz in {duration} seconds")
+			},
+			Self::Samples(samples) => write!(f, "looped WAV file of {} samples", samples.len()),
+		}
+	}
+}
+
+struct SyntheticAudio {
+	format: CaptureFormat,
+	signal: Signal,
+
+	/// Phase of the sine wave, in radians.
+	phase: f64,
+
+	/// Number of frames of a sweep that were generated, or the position of the next sample in a looped file.
+	position: usize,
+}
+
+impl SyntheticAudio {
+	/// Generate a fragment every packet duration, until the receiving end of `audio_tx` is dropped.
+	fn run(mut self, audio_tx: Sender<AudioFragment>) {
+		let fragment_duration = self.format.duration(self.format.fragment_samples());
+
+		// Like a real capture, a fragment is only available after it has played.
+		let mut next_fragment_time = Instant::now() + fragment_duration;
+		loop {
+			let now = Instant::now();
+			if next_fragment_time > now {
+				std::thread::sleep(next_fragment_time - now);
+			} else if now - next_fragment_time > fragment_duration {
+				// We are lagging behind, don't try to catch up.
+				next_fragment_time = now;
+			}
+
+			let samples = self.next_fragment();
+			let captured_at = next_fragment_time.checked_sub(fragment_duration).unwrap_or(next_fragment_time);
+			match audio_tx.try_send(AudioFragment { samples, captured_at }) {
+				Ok(()) => {},
+				Err(TrySendError::Full(_)) => log::trace!("Audio channel is full, dropping audio fragment."),
+				Err(TrySendError::Closed(_)) => {
+					log::info!("Closing synthetic audio because the receiving end was dropped.");
+					break;
+				},
+			}
+
+			next_fragment_time += fragment_duration;
+		}
+	}
+
+	fn next_fragment(&mut self) -> Vec<i16> {
+		let channels = self.format.channels as usize;
+		let sample_rate = self.format.sample_rate as f64;
+		let mut samples = Vec::with_capacity(self.format.fragment_samples());
+
+		match &self.signal {
+			Signal::Sweep { start_frequency, end_frequency, duration } => {
+				for _ in 0..self.format.fragment_samples() / channels {
+					let time = (self.position as f64 / sample_rate) % duration;
+					let frequency = start_frequency + (end_frequency - start_frequency) * time / duration;
+
+					let value = (self.phase.sin() * AMPLITUDE * i16::MAX as f64) as i16;
+					samples.resize(samples.len() + channels, value);
+
+					self.phase = (self.phase + TAU * frequency / sample_rate) % TAU;
+					self.position += 1;
+				}
+			},
+			Signal::Samples(looped) => {
+				for _ in 0..self.format.fragment_samples() {
+					samples.push(looped[self.position]);
+					self.position = (self.position + 1) % looped.len();
+				}
+			},
+		}
+
+		samples
+	}
+}
+
+/// Read a 16-bit PCM WAV file and convert it to the sample rate and channels of `format`.
+fn read_wav(path: &Path, format: CaptureFormat) -> Result<Vec<i16>, ()> {
+	let data = std::fs::read(path)
+		.map_err(|e| log::error!("Failed to read WAV file {path:?}: {e}"))?;
+	if data.len() < 12 || &data[0..4] != b"RIFF" || &data[8..12] != b"WAVE" {
+		log::error!("File {path:?} is not a WAV file.");
+		return Err(());
+	}
+
+	// Find the format and the samples in the chunks of the file.
+	let mut wav_format = None;
+	let mut wav_samples = None;
+	let mut offset = 12;
+	while offset + 8 <= data.len() {
+		let id = &data[offset..offset + 4];
+		let size = u32::from_le_bytes([data[offset + 4], data[offset + 5], data[offset + 6], data[offset + 7]]) as usize;
+		let body = &data[offset + 8..(offset + 8).saturating_add(size).min(data.len())];
+
+		match id {
+			b"fmt " if body.len() >= 16 => {
+				let format_tag = u16::from_le_bytes([body[0], body[1]]);
+				let channels = u16::from_le_bytes([body[2], body[3]]) as usize;
+				let sample_rate = u32::from_le_bytes([body[4], body[5], body[6], body[7]]);
+				let bits_per_sample = u16::from_le_bytes([body[14], body[15]]);
+				wav_format = Some((format_tag, channels, sample_rate, bits_per_sample));
+			},
+			b"data" => wav_samples = Some(body),
+			_ => {},
+		}
+
+		// Chunks are padded to an even size.
+		offset = offset.saturating_add(8 + size + size % 2);
+	}
+
+	let Some((format_tag, wav_channels, wav_sample_rate, bits_per_sample)) = wav_format else {
+		log::error!("WAV file {path:?} has no format.");
+		return Err(());
+	};
+
+	// 0xFFFE is WAVE_FORMAT_EXTENSIBLE, which is used for files with more than two channels.
+	if !matches!(format_tag, 1 | 0xFFFE) || bits_per_sample != 16 || wav_channels == 0 || wav_sample_rate == 0 {
+		log::error!("WAV file {path:?} has an unsupported format, only 16-bit PCM is supported.");
+		return Err(());
+	}
+
+	let wav_samples: Vec<i16> = wav_samples.unwrap_or_default()
+		.chunks_exact(2)
+		.map(|sample| i16::from_le_bytes([sample[0], sample[1]]))
+		.collect();
+	let wav_frames = wav_samples.len() / wav_channels;
+	if wav_frames == 0 {
+		log::error!("WAV file {path:?} contains no samples.");
+		return Err(());
+	}
+
+	// WAV files use the same channel order as we do, so channels are mapped by index.
+	let channel = |frame: usize, index: usize| -> f64 {
+		match wav_channels {
+			1 => wav_samples[frame] as f64,
+			_ if index < wav_channels => wav_samples[frame * wav_channels + index] as f64,
+			_ => 0.0,
+		}
+	};
+
+	// Resample with linear interpolation, wrapping around at the end so that the loop is seamless.
+	let channels = format.channels as usize;
+	let frames = (wav_frames as u64 * format.sample_rate as u64 / wav_sample_rate as u64).max(1) as usize;
+	let mut samples = Vec::with_capacity(frames * channels);
+	for frame in 0..frames {
+		let position = frame as f64 * wav_sample_rate as f64 / format.sample_rate as f64;
+		let first = (position as usize).min(wav_frames - 1);
+		let second = (first + 1) % wav_frames;
+		let fraction = position - first as f64;
+
+		for index in 0..channels {
+			let value = channel(first, index) + (channel(second, index) - channel(first, index)) * fraction;
+			samples.push(value.round().clamp(i16::MIN as f64, i16::MAX as f64) as i16);
+		}
+	}
+
+	log::debug!(
+		"Loaded WAV file {path:?} with {wav_channels} channels at {wav_sample_rate} Hz, {:.1} seconds long.",
+		wav_frames as f64 / wav_sample_rate as f64,
+	);
+
+	Ok(samples)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::ffi::c_int;
+
+	use audiopus_sys as ffi;
+
+	use crate::session::stream::audio::opus::{OpusConfiguration, OpusEncoder};
+	use super::*;
+
+	const STEREO: CaptureFormat = CaptureFormat { sample_rate: 48000, channels: 2, packet_duration: 10 };
+
+	/// A WAV file with a single format chunk and a single data chunk.
+	fn wav(format_tag: u16, channels: u16, sample_rate: u32, bits_per_sample: u16, samples: &[i16]) -> Vec<u8> {
+		let block_align = channels * bits_per_sample / 8;
+		let data: Vec<u8> = samples.iter().flat_map(|sample| sample.to_le_bytes()).collect();
+
+		let mut wav = Vec::new();
+		wav.extend_from_slice(b"RIFF");
+		wav.extend_from_slice(&(4 + 8 + 16 + 8 + data.len() as u32).to_le_bytes());
+		wav.extend_from_slice(b"WAVE");
+		wav.extend_from_slice(b"fmt ");
+		wav.extend_from_slice(&16u32.to_le_bytes());
+		wav.extend_from_slice(&format_tag.to_le_bytes());
+		wav.extend_from_slice(&channels.to_le_bytes());
+		wav.extend_from_slice(&sample_rate.to_le_bytes());
+		wav.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+		wav.extend_from_slice(&block_align.to_le_bytes());
+		wav.extend_from_slice(&bits_per_sample.to_le_bytes());
+		wav.extend_from_slice(b"data");
+		wav.extend_from_slice(&(data.len() as u32).to_le_bytes());
+		wav.extend_from_slice(&data);
+		wav
+	}
+
+	/// Write `contents` to a temporary file and read it as a WAV file in `format`.
+	fn read_temporary_wav(name: &str, contents: &[u8], format: CaptureFormat) -> Result<Vec<i16>, ()> {
+		let path = std::env::temp_dir().join(format!("moonshine-{}-{name}.wav", std::process::id()));
+		std::fs::write(&path, contents).unwrap();
+		let result = read_wav(&path, format);
+		std::fs::remove_file(&path).unwrap();
+		result
+	}
+
+	#[test]
+	fn mono_wav_plays_on_every_channel() {
+		let samples = read_temporary_wav("mono", &wav(1, 1, 48000, 16, &[0, 100, 200, 300]), STEREO).unwrap();
+		assert_eq!(samples, [0, 0, 100, 100, 200, 200, 300, 300]);
+	}
+
+	#[test]
+	fn missing_wav_channels_are_silent() {
+		let format = CaptureFormat { channels: 6, ..STEREO };
+		let samples = read_temporary_wav("stereo", &wav(1, 2, 48000, 16, &[100, -100, 200, -200]), format).unwrap();
+		assert_eq!(samples, [100, -100, 0, 0, 0, 0, 200, -200, 0, 0, 0, 0]);
+	}
+
+	#[test]
+	fn wav_is_resampled_with_linear_interpolation() {
+		let format = CaptureFormat { channels: 1, ..STEREO };
+		let samples = read_temporary_wav("resample", &wav(1, 1, 24000, 16, &[0, 100, 200, 300]), format).unwrap();
+
+		// The last sample interpolates towards the first, so that the loop is seamless.
+		assert_eq!(samples, [0, 50, 100, 150, 200, 250, 300, 150]);
+	}
+
+	#[test]
+	fn unsupported_wav_files_are_rejected() {
+		// 8-bit PCM.
+		assert!(read_temporary_wav("8bit", &wav(1, 1, 48000, 8, &[0, 0]), STEREO).is_err());
+
+		// 32-bit float.
+		assert!(read_temporary_wav("float", &wav(3, 1, 48000, 16, &[0, 0]), STEREO).is_err());
+
+		assert!(read_temporary_wav("empty", &wav(1, 1, 48000, 16, &[]), STEREO).is_err());
+		assert!(read_temporary_wav("garbage", b"not a wav file", STEREO).is_err());
+	}
+
+	#[test]
+	fn generated_fragments_hold_a_single_packet() {
+		let mut tone = SyntheticAudio {
+			format: STEREO,
+			signal: Signal::Sweep { start_frequency: 1000.0, end_frequency: 1000.0, duration: 1.0 },
+			phase: 0.0,
+			position: 0,
+		};
+		for _ in 0..3 {
+			assert_eq!(tone.next_fragment().len(), STEREO.fragment_samples());
+		}
+
+		// Looped samples that are shorter than a fragment wrap around.
+		let mut looped = SyntheticAudio { format: STEREO, signal: Signal::Samples(vec![1, 2, 3]), phase: 0.0, position: 0 };
+		let fragment = looped.next_fragment();
+		assert_eq!(fragment.len(), STEREO.fragment_samples());
+		assert_eq!(fragment[..7], [1, 2, 3, 1, 2, 3, 1]);
+	}
+
+	#[test]
+	fn tone_is_the_same_on_every_channel() {
+		let mut tone = SyntheticAudio {
+			format: STEREO,
+			signal: Signal::Sweep { start_frequency: 1000.0, end_frequency: 1000.0, duration: 1.0 },
+			phase: 0.0,
+			position: 0,
+		};
+		let fragment = tone.next_fragment();
+
+		let peak = (AMPLITUDE * i16::MAX as f64) as i16;
+		assert!(fragment.chunks_exact(2).all(|frame| frame[0] == frame[1]));
+		assert!(fragment.iter().all(|sample| sample.abs() <= peak));
+		assert!((18..=21).contains(&zero_crossings(&fragment, 2)));
+	}
+
+	#[test]
+	fn encoded_tone_decodes_to_the_same_tone() {
+		let configuration = OpusConfiguration::select(2, false);
+		let mut encoder = OpusEncoder::new(STEREO.sample_rate, &configuration, 10).unwrap();
+
+		let mut error = 0;
+		let decoder = unsafe {
+			ffi::opus_multistream_decoder_create(
+				STEREO.sample_rate as ffi::opus_int32,
+				configuration.channels as c_int,
+				configuration.streams as c_int,
+				configuration.coupled_streams as c_int,
+				configuration.mapping.as_ptr(),
+				&mut error,
+			)
+		};
+		assert!(!decoder.is_null() && error == ffi::OPUS_OK as c_int);
+
+		let mut tone = SyntheticAudio {
+			format: STEREO,
+			signal: Signal::Sweep { start_frequency: 1000.0, end_frequency: 1000.0, duration: 1.0 },
+			phase: 0.0,
+			position: 0,
+		};
+
+		// Give the codec time to settle, then compare the last fragment.
+		let mut decoded = vec![0i16; STEREO.fragment_samples()];
+		let mut original = Vec::new();
+		for _ in 0..50 {
+			original = tone.next_fragment();
+			let packet = encoder.encode(&original, 1400).unwrap();
+			assert!(!packet.is_empty() && packet.len() <= 1400);
+
+			let frames = unsafe {
+				ffi::opus_multistream_decode(
+					decoder,
+					packet.as_ptr(),
+					packet.len() as ffi::opus_int32,
+					decoded.as_mut_ptr(),
+					(decoded.len() / STEREO.channels as usize) as c_int,
+					0,
+				)
+			};
+			assert_eq!(frames as usize, decoded.len() / STEREO.channels as usize);
+		}
+		unsafe { ffi::opus_multistream_decoder_destroy(decoder) };
+
+		// The codec delays the signal, so compare the level and the frequency instead of the samples.
+		let level = rms(&decoded) / rms(&original);
+		assert!((0.8..1.2).contains(&level), "decoded level is {level} of the original");
+		assert!((18..=21).contains(&zero_crossings(&decoded, 2)));
+	}
+
+	/// Number of sign changes of the first channel of interleaved `samples`.
+	fn zero_crossings(samples: &[i16], channels: usize) -> usize {
+		let first_channel: Vec<i16> = samples.iter().step_by(channels).copied().collect();
+		first_channel.windows(2).filter(|pair| (pair[0] < 0) != (pair[1] < 0)).count()
+	}
+
+	fn rms(samples: &[i16]) -> f64 {
+		(samples.iter().map(|&sample| (sample as f64).powi(2)).sum::<f64>() / samples.len() as f64).sqrt()
+	}
+}
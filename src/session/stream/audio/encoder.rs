@@ -2,7 +2,9 @@ use openssl::cipher::Cipher;
 use reed_solomon_erasure::{galois_8, ReedSolomon};
 use tokio::sync::mpsc;
 
-use crate::{crypto::encrypt, session::{stream::RtpHeader, SessionKeys}};
+use crate::{crypto::encrypt, session::{recorder::{EncodedPacket, Recorder, StreamParameters}, stream::{MediaClock, RtpHeader}, SessionKeys}};
+
+use super::{capture::AudioFragment, opus::{OpusConfiguration, OpusEncoder}};
 
 #[derive(Debug)]
 #[repr(C)]
@@ -14,6 +16,21 @@ struct AudioFecHeader {
 	pub ssrc: u32,
 }
 
+const MAX_SHARD_SIZE: usize = ((2048 + 15) / 16) * 16; // Where does this come from?
+
+/// Maximum size of an encoded packet.
+///
+/// Encoded packets have to fit in a shard, together with the headers and the padding that encryption adds.
+pub(super) const MAX_PACKET_SIZE: usize = MAX_SHARD_SIZE - std::mem::size_of::<RtpHeader>() - std::mem::size_of::<AudioFecHeader>() - 16;
+
+/// A packet that is ready to be sent.
+pub struct AudioPacket {
+	pub data: Vec<u8>,
+
+	/// Time at which the audio in this packet was captured.
+	pub captured_at: std::time::Instant,
+}
+
 enum AudioEncoderCommand {
 	UpdateKeys(SessionKeys),
 }
@@ -25,26 +42,37 @@ pub struct AudioEncoder {
 impl AudioEncoder {
 	pub fn new(
 		sample_rate: u32,
-		channels: u8,
-		audio_rx: mpsc::Receiver<Vec<i16>>,
+		configuration: OpusConfiguration,
+		complexity: u8,
+		encrypted: bool,
+		clock: MediaClock,
+		audio_rx: mpsc::Receiver<AudioFragment>,
 		keys: SessionKeys,
-		packet_tx: mpsc::Sender<Vec<u8>>
+		packet_tx: mpsc::Sender<AudioPacket>,
+		recorder: Option<Recorder>,
 	) -> Result<Self, ()> {
-		log::debug!("Creating audio encoder with sample rate {} and {} channels.", sample_rate, channels);
-		let mut encoder = opus::Encoder::new(
+		log::debug!(
+			"Creating audio encoder with sample rate {} and {} channels in {} streams at {} bps.",
 			sample_rate,
-			if channels > 1 { opus::Channels::Stereo } else { opus::Channels::Mono },
-			opus::Application::LowDelay,
-		)
-			.map_err(|e| log::error!("Failed to create audio encoder: {e}"))?;
-
-		// Moonlight expects a constant bitrate.
-		encoder.set_vbr(false)
-			.map_err(|e| log::error!("Failed to disable variable bitrate: {e}"))?;
+			configuration.channels,
+			configuration.streams,
+			configuration.bitrate,
+		);
+		let encoder = OpusEncoder::new(sample_rate, &configuration, complexity)?;
+
+		if let Some(recorder) = &recorder {
+			recorder.set_audio_parameters(StreamParameters::Audio {
+				sample_rate,
+				channels: configuration.channels,
+				streams: configuration.streams,
+				coupled_streams: configuration.coupled_streams,
+				mapping: configuration.mapping.to_vec(),
+			});
+		}
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = AudioEncoderInner { };
-		tokio::spawn(inner.run(command_rx, audio_rx, encoder, keys, packet_tx));
+		let inner = AudioEncoderInner { encrypted, clock };
+		tokio::spawn(inner.run(command_rx, audio_rx, encoder, keys, packet_tx, recorder));
 
 		Ok(Self { command_tx })
 	}
@@ -56,24 +84,29 @@ impl AudioEncoder {
 }
 
 struct AudioEncoderInner {
+	/// Encrypt audio packets, as negotiated with the client.
+	encrypted: bool,
+
+	/// Clock of the session, which converts capture times to timestamps.
+	clock: MediaClock,
 }
 
 impl AudioEncoderInner {
 	async fn run(
 		self,
 		mut command_rx: mpsc::Receiver<AudioEncoderCommand>,
-		mut audio_rx: mpsc::Receiver<Vec<i16>>,
-		mut encoder: opus::Encoder,
+		mut audio_rx: mpsc::Receiver<AudioFragment>,
+		mut encoder: OpusEncoder,
 		mut keys: SessionKeys,
-		packet_tx: mpsc::Sender<Vec<u8>>,
+		packet_tx: mpsc::Sender<AudioPacket>,
+		recorder: Option<Recorder>,
 	) -> Result<(), ()> {
 		let mut sequence_number = 0u16;
-		let stream_start_time = std::time::Instant::now();
 
 		const NR_DATA_SHARDS: usize = 4;
 		const NR_PARITY_SHARDS: usize = 2;
 		const NR_TOTAL_SHARDS: usize = NR_DATA_SHARDS + NR_PARITY_SHARDS;
-		const MAX_SHARD_SIZE: usize = ((2048 + 15) / 16) * 16; // Where does this come from?
+
 		let mut fec_encoder = ReedSolomon::<galois_8::Field>::new(NR_DATA_SHARDS, NR_PARITY_SHARDS)
 			.map_err(|e| log::error!("Failed to create FEC encoder: {e}"))?;
 
@@ -115,8 +148,9 @@ impl AudioEncoderInner {
 						break;
 					};
 
-					let timestamp = ((std::time::Instant::now() - stream_start_time).as_micros() / (1000 / 90)) as u32;
-					let encoded = match encoder.encode_vec(&audio_fragment, audio_fragment.len()) {
+					let captured_at = audio_fragment.captured_at;
+					let timestamp = self.clock.rtp_timestamp(captured_at);
+					let encoded = match encoder.encode(&audio_fragment.samples, MAX_PACKET_SIZE) {
 						Ok(encoded) => encoded,
 						Err(e) => {
 							log::warn!("Failed to encode audio: {e}");
@@ -125,17 +159,28 @@ impl AudioEncoderInner {
 						}
 					};
 
-					// Encrypt the audio data.
-					// TODO: Check if we should, some clients (ie. Steam Link) don't support this.
-					let iv = keys.remote_input_key_id as u32 + sequence_number as u32;
-					let mut iv = iv.to_be_bytes().to_vec();
-					iv.extend([0u8; 12]);
-					let payload = match encrypt(Cipher::aes_128_cbc(), &encoded, Some(&keys.remote_input_key), Some(&iv), true) {
-						Ok(payload) => payload,
-						Err(e) => {
-							log::error!("Failed to encrypt audio: {e}");
-							continue;
-						},
+					if let Some(recorder) = &recorder {
+						recorder.record_audio(EncodedPacket {
+							data: encoded.clone(),
+							timestamp: captured_at,
+							key_frame: true,
+						});
+					}
+
+					// Encrypt the audio data, if the client negotiated encryption.
+					let payload = if self.encrypted {
+						let iv = keys.remote_input_key_id as u32 + sequence_number as u32;
+						let mut iv = iv.to_be_bytes().to_vec();
+						iv.extend([0u8; 12]);
+						match encrypt(Cipher::aes_128_cbc(), &encoded, Some(&keys.remote_input_key), Some(&iv), true) {
+							Ok(payload) => payload,
+							Err(e) => {
+								log::error!("Failed to encrypt audio: {e}");
+								continue;
+							},
+						}
+					} else {
+						encoded
 					};
 
 					let shard = &mut shards[sequence_number as usize % NR_DATA_SHARDS];
@@ -172,7 +217,7 @@ impl AudioEncoderInner {
 					let data_shard_size = std::mem::size_of::<RtpHeader>() + payload.len();
 					let data_shard = shard[..data_shard_size].to_vec(); // TODO: Can we avoid this copy?
 
-					if packet_tx.send(data_shard).await.is_err() {
+					if packet_tx.send(AudioPacket { data: data_shard, captured_at }).await.is_err() {
 						log::debug!("Failed to send packet over channel, channel is likely closed.");
 						break;
 					}
@@ -223,7 +268,7 @@ impl AudioEncoderInner {
 							let parity_shard_size = std::mem::size_of::<RtpHeader>() + std::mem::size_of::<AudioFecHeader>() + payload.len();
 							let parity_shard = shard[..parity_shard_size].to_vec(); // TODO: Can we avoid this copy?
 
-							if packet_tx.send(parity_shard).await.is_err() {
+							if packet_tx.send(AudioPacket { data: parity_shard, captured_at }).await.is_err() {
 								log::debug!("Failed to send packet over channel, channel is likely closed.");
 								break;
 							}
@@ -237,3 +282,113 @@ impl AudioEncoderInner {
 		Ok(())
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use crate::{
+		config::{AudioSourceConfig, AudioStreamConfig},
+		session::stream::audio::capture::AudioCapture,
+	};
+	use super::*;
+
+	/// Packets of the first three FEC blocks, encoded from a synthetic tone.
+	async fn encode_tone(encrypted: bool, keys: &SessionKeys) -> Vec<Vec<u8>> {
+		let config = AudioStreamConfig {
+			source: AudioSourceConfig::Tone { frequency: 1000.0 },
+			..Default::default()
+		};
+
+		let (audio_tx, audio_rx) = mpsc::channel(10);
+		let capture = AudioCapture::new(&config, 2, 5, audio_tx).await.unwrap();
+
+		let (packet_tx, mut packet_rx) = mpsc::channel(100);
+		let _encoder = AudioEncoder::new(
+			capture.sample_rate(),
+			OpusConfiguration::select(2, false),
+			10,
+			encrypted,
+			MediaClock::new(),
+			audio_rx,
+			keys.clone(),
+			packet_tx,
+			None,
+		).unwrap();
+
+		let mut packets = Vec::new();
+		while packets.len() < 3 * 6 {
+			packets.push(packet_rx.recv().await.unwrap().data);
+		}
+
+		// Dropping the receiver stops the encoder, which stops the capture.
+		packets
+	}
+
+	fn keys() -> SessionKeys {
+		SessionKeys { remote_input_key: (0..16).collect(), remote_input_key_id: 1234 }
+	}
+
+	fn sequence_number(packet: &[u8]) -> u16 {
+		u16::from_be_bytes([packet[2], packet[3]])
+	}
+
+	fn timestamp(packet: &[u8]) -> u32 {
+		u32::from_be_bytes([packet[4], packet[5], packet[6], packet[7]])
+	}
+
+	#[tokio::test]
+	async fn parity_shards_follow_every_four_data_shards() {
+		let packets = encode_tone(false, &keys()).await;
+		let rtp_header_size = std::mem::size_of::<RtpHeader>();
+
+		let mut previous_timestamp = None;
+		for (block, packets) in packets.chunks_exact(6).enumerate() {
+			let base_sequence_number = block as u16 * 4;
+
+			for (index, packet) in packets[..4].iter().enumerate() {
+				assert_eq!(packet[0], 0x80);
+				assert_eq!(packet[1], 97);
+				assert_eq!(sequence_number(packet), base_sequence_number + index as u16);
+				assert!(packet.len() > rtp_header_size);
+
+				// Fragments are captured 5ms (450 ticks of 90kHz) apart.
+				let timestamp = timestamp(packet);
+				if let Some(previous_timestamp) = previous_timestamp {
+					assert!(timestamp > previous_timestamp, "timestamp {timestamp} doesn't follow {previous_timestamp}");
+				}
+				previous_timestamp = Some(timestamp);
+			}
+
+			for (index, packet) in packets[4..].iter().enumerate() {
+				assert_eq!(packet[1], 127);
+				assert_eq!(sequence_number(packet), base_sequence_number + 4 + index as u16);
+
+				let fec_header = &packet[rtp_header_size..rtp_header_size + std::mem::size_of::<AudioFecHeader>()];
+				assert_eq!(fec_header[0], index as u8);
+				assert_eq!(fec_header[1], 97);
+				assert_eq!(u16::from_be_bytes([fec_header[2], fec_header[3]]), base_sequence_number);
+				assert_eq!(u32::from_be_bytes([fec_header[4], fec_header[5], fec_header[6], fec_header[7]]), timestamp(&packets[0]));
+			}
+		}
+	}
+
+	#[tokio::test]
+	async fn encrypted_payload_decrypts_with_the_session_key() {
+		let keys = keys();
+		let packets = encode_tone(true, &keys).await;
+
+		for packet in packets.iter().filter(|packet| packet[1] == 97) {
+			let sequence_number = sequence_number(packet);
+			let mut iv = (keys.remote_input_key_id as u32 + sequence_number as u32).to_be_bytes().to_vec();
+			iv.extend([0u8; 12]);
+
+			let payload = &packet[std::mem::size_of::<RtpHeader>()..];
+			assert_eq!(payload.len() % 16, 0);
+			let decrypted = openssl::symm::decrypt(openssl::symm::Cipher::aes_128_cbc(), &keys.remote_input_key, Some(&iv), payload)
+				.unwrap_or_else(|e| panic!("Failed to decrypt packet {sequence_number}: {e}"));
+
+			// The decrypted payload is an Opus packet of 5ms.
+			let samples = unsafe { audiopus_sys::opus_packet_get_nb_samples(decrypted.as_ptr(), decrypted.len() as i32, 48000) };
+			assert_eq!(samples, 240);
+		}
+	}
+}
@@ -1,17 +1,30 @@
 use async_shutdown::ShutdownManager;
 use tokio::{net::UdpSocket, sync::mpsc};
 
-use crate::{config::Config, session::SessionKeys};
+use crate::{config::Config, session::{recorder::Recorder, SessionKeys}};
 
-use self::{capture::AudioCapture, encoder::AudioEncoder};
+use self::{capture::AudioCapture, encoder::{AudioEncoder, AudioPacket, MAX_PACKET_SIZE}};
+use super::clock::{LatencyMonitor, MediaClock};
+
+pub use self::{capture::VirtualSink, opus::OpusConfiguration};
 
 mod capture;
 mod encoder;
+mod opus;
 
 #[derive(Clone, Default)]
 pub struct AudioStreamContext {
 	pub packet_duration: u32,
 	pub qos: bool,
+
+	/// Number of channels requested by the client.
+	pub channels: u8,
+
+	/// Whether the client requested high quality surround sound.
+	pub high_quality: bool,
+
+	/// Encrypt audio packets, as negotiated with the client.
+	pub encrypted: bool,
 }
 
 enum AudioStreamCommand {
@@ -35,6 +48,8 @@ impl AudioStream {
 	pub fn new(
 		config: Config,
 		context: AudioStreamContext,
+		clock: MediaClock,
+		recorder: Option<Recorder>,
 		stop_signal: ShutdownManager<()>,
 	) -> Self {
 		let (command_tx, command_rx) = mpsc::channel(10);
@@ -42,7 +57,9 @@ impl AudioStream {
 		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
 			config,
 			context,
+			clock,
 			command_rx,
+			recorder,
 			stop_signal.clone(),
 		))));
 
@@ -65,7 +82,9 @@ impl AudioStreamInner {
 		mut self,
 		config: Config,
 		audio_stream_context: AudioStreamContext,
+		clock: MediaClock,
 		mut command_rx: mpsc::Receiver<AudioStreamCommand>,
+		recorder: Option<Recorder>,
 		_stop_signal: ShutdownManager<()>,
 	) -> Result<(), ()> {
 		let socket = UdpSocket::bind((config.address, config.stream.audio.port)).await
@@ -84,10 +103,11 @@ impl AudioStreamInner {
 			.map_err(|e| log::error!("Failed to get local address associated with control socket: {e}"))?
 		);
 
-		let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(10);
+		let (packet_tx, mut packet_rx) = mpsc::channel::<AudioPacket>(10);
 		tokio::spawn(async move {
 			let mut buf = [0; 1024];
 			let mut client_address = None;
+			let mut latency = LatencyMonitor::new("audio");
 
 			loop {
 				tokio::select! {
@@ -95,8 +115,9 @@ impl AudioStreamInner {
 						match packet {
 							Some(packet) => {
 								if let Some(client_address) = client_address {
-									if let Err(e) = socket.send_to(packet.as_slice(), client_address).await {
-										log::warn!("Failed to send packet to client: {e}");
+									match socket.send_to(packet.data.as_slice(), client_address).await {
+										Ok(_) => latency.record(packet.captured_at, std::time::Instant::now()),
+										Err(e) => log::warn!("Failed to send packet to client: {e}"),
 									}
 								}
 							},
@@ -132,18 +153,50 @@ impl AudioStreamInner {
 				AudioStreamCommand::Start(keys) => {
 					log::info!("Starting audio stream.");
 
+					let mut configuration = OpusConfiguration::select(
+						audio_stream_context.channels.min(config.stream.audio.channels),
+						audio_stream_context.high_quality,
+					);
+					if let Some(bitrate) = config.stream.audio.bitrate {
+						configuration.bitrate = bitrate as i32 * 1000;
+					}
+
+					// Moonlight asks for 5ms or 10ms, longer packets wouldn't fit in a shard at the bitrates of the high quality configurations.
+					let packet_duration = match audio_stream_context.packet_duration {
+						packet_duration @ (5 | 10) => packet_duration,
+						packet_duration => {
+							log::warn!("Client requested an audio packet duration of {packet_duration}ms, which isn't supported, using 5ms.");
+							5
+						},
+					};
+
+					// Packets are encoded at a constant bitrate, so the bitrate determines their size.
+					let max_bitrate = (MAX_PACKET_SIZE * 8 * 1000 / packet_duration as usize) as i32;
+					if configuration.bitrate > max_bitrate {
+						log::warn!(
+							"Audio bitrate of {} kbps doesn't fit in packets of {packet_duration}ms, using {} kbps.",
+							configuration.bitrate / 1000,
+							max_bitrate / 1000,
+						);
+						configuration.bitrate = max_bitrate;
+					}
+
 					let (audio_tx, audio_rx) = mpsc::channel(10);
-					let capture = match AudioCapture::new(audio_tx).await {
+					let capture = match AudioCapture::new(&config.stream.audio, configuration.channels, packet_duration, audio_tx).await {
 						Ok(capture) => capture,
 						Err(()) => continue,
 					};
 
 					let encoder = match AudioEncoder::new(
 						capture.sample_rate(),
-						capture.channels(),
+						configuration,
+						config.stream.audio.complexity,
+						audio_stream_context.encrypted,
+						clock,
 						audio_rx,
 						keys.clone(),
-						packet_tx.clone()
+						packet_tx.clone(),
+						recorder.clone(),
 					) {
 						Ok(encoder) => encoder,
 						Err(()) => continue,
@@ -0,0 +1,181 @@
+use std::ffi::{c_int, CStr};
+
+use audiopus_sys as ffi;
+
+/// Layout of an Opus multistream, which is how Moonlight receives every channel configuration.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OpusConfiguration {
+	pub channels: u8,
+
+	/// Total number of Opus streams in a packet.
+	pub streams: u8,
+
+	/// Number of streams that encode two channels, the remaining streams encode a single channel.
+	pub coupled_streams: u8,
+
+	/// Stream channel in which every input channel is encoded.
+	pub mapping: &'static [u8],
+
+	/// Bitrate in bits per second, which can be replaced by `stream.audio.bitrate`.
+	pub bitrate: i32,
+
+	/// High quality configurations encode every channel in a separate stream at a higher bitrate.
+	pub high_quality: bool,
+}
+
+impl OpusConfiguration {
+	const MONO: Self = Self { channels: 1, streams: 1, coupled_streams: 0, mapping: &[0], bitrate: 64000, high_quality: false };
+	const STEREO: Self = Self { channels: 2, streams: 1, coupled_streams: 1, mapping: &[0, 1], bitrate: 96000, high_quality: false };
+	const STEREO_HIGH_QUALITY: Self = Self { channels: 2, streams: 1, coupled_streams: 1, mapping: &[0, 1], bitrate: 512000, high_quality: true };
+	// Normal quality surround couples the front and the back channels, using the same layout as GFE and Sunshine.
+	const SURROUND51: Self = Self { channels: 6, streams: 4, coupled_streams: 2, mapping: &[0, 1, 4, 5, 2, 3], bitrate: 256000, high_quality: false };
+	const SURROUND51_HIGH_QUALITY: Self = Self { channels: 6, streams: 6, coupled_streams: 0, mapping: &[0, 1, 2, 3, 4, 5], bitrate: 1536000, high_quality: true };
+	const SURROUND71: Self = Self { channels: 8, streams: 5, coupled_streams: 3, mapping: &[0, 1, 4, 5, 2, 3, 6, 7], bitrate: 450000, high_quality: false };
+	const SURROUND71_HIGH_QUALITY: Self = Self { channels: 8, streams: 8, coupled_streams: 0, mapping: &[0, 1, 2, 3, 4, 5, 6, 7], bitrate: 2048000, high_quality: true };
+
+	/// Configurations that are advertised to clients, in the order they are advertised.
+	const ADVERTISED: [Self; 6] = [
+		Self::STEREO,
+		Self::STEREO_HIGH_QUALITY,
+		Self::SURROUND51,
+		Self::SURROUND51_HIGH_QUALITY,
+		Self::SURROUND71,
+		Self::SURROUND71_HIGH_QUALITY,
+	];
+
+	/// Select the configuration with the most channels, up to `channels`.
+	pub fn select(channels: u8, high_quality: bool) -> Self {
+		match channels {
+			0 | 1 => Self::MONO,
+			2..=5 if high_quality => Self::STEREO_HIGH_QUALITY,
+			2..=5 => Self::STEREO,
+			6 | 7 if high_quality => Self::SURROUND51_HIGH_QUALITY,
+			6 | 7 => Self::SURROUND51,
+			_ if high_quality => Self::SURROUND71_HIGH_QUALITY,
+			_ => Self::SURROUND71,
+		}
+	}
+
+	/// The `surround-params` of every configuration with at most `max_channels` channels, as advertised in the DESCRIBE response.
+	pub fn surround_params(max_channels: u8) -> Vec<String> {
+		Self::ADVERTISED.iter()
+			.filter(|configuration| configuration.channels <= max_channels)
+			.map(|configuration| {
+				let mut mapping = configuration.mapping.to_vec();
+
+				// GFE advertises the wrong mapping for normal quality surround, so Moonlight rotates the channels from index 3 to the right.
+				// Rotate them to the left to undo this.
+				if configuration.channels > 2 && !configuration.high_quality {
+					mapping[3..6].rotate_left(1);
+				}
+
+				let mapping: String = mapping.iter().map(|channel| channel.to_string()).collect();
+				format!("{}{}{}{}", configuration.channels, configuration.streams, configuration.coupled_streams, mapping)
+			})
+			.collect()
+	}
+}
+
+/// Opus multistream encoder, which encodes interleaved samples in the channel order of the configuration.
+pub struct OpusEncoder {
+	encoder: *mut ffi::OpusMSEncoder,
+	channels: u8,
+}
+
+// The encoder is only used from one thread at a time.
+unsafe impl Send for OpusEncoder { }
+
+impl OpusEncoder {
+	pub fn new(sample_rate: u32, configuration: &OpusConfiguration, complexity: u8) -> Result<Self, ()> {
+		let mut error = 0;
+		let encoder = unsafe {
+			ffi::opus_multistream_encoder_create(
+				sample_rate as ffi::opus_int32,
+				configuration.channels as c_int,
+				configuration.streams as c_int,
+				configuration.coupled_streams as c_int,
+				configuration.mapping.as_ptr(),
+				ffi::OPUS_APPLICATION_RESTRICTED_LOWDELAY as c_int,
+				&mut error,
+			)
+		};
+		if encoder.is_null() || error != ffi::OPUS_OK as c_int {
+			log::error!("Failed to create audio encoder: {}", error_string(error));
+			return Err(());
+		}
+
+		let mut encoder = Self { encoder, channels: configuration.channels };
+
+		// Moonlight expects a constant bitrate.
+		encoder.ctl(ffi::OPUS_SET_VBR_REQUEST as c_int, 0)
+			.map_err(|e| log::error!("Failed to disable variable bitrate: {e}"))?;
+		encoder.ctl(ffi::OPUS_SET_BITRATE_REQUEST as c_int, configuration.bitrate)
+			.map_err(|e| log::error!("Failed to set audio bitrate: {e}"))?;
+		encoder.ctl(ffi::OPUS_SET_COMPLEXITY_REQUEST as c_int, complexity.min(10) as i32)
+			.map_err(|e| log::error!("Failed to set audio encoder complexity: {e}"))?;
+
+		Ok(encoder)
+	}
+
+	/// Encode interleaved samples in to a packet of at most `max_size` bytes.
+	pub fn encode(&mut self, samples: &[i16], max_size: usize) -> Result<Vec<u8>, String> {
+		let mut output = vec![0u8; max_size];
+		let result = unsafe {
+			ffi::opus_multistream_encode(
+				self.encoder,
+				samples.as_ptr(),
+				(samples.len() / self.channels as usize) as c_int,
+				output.as_mut_ptr(),
+				output.len() as ffi::opus_int32,
+			)
+		};
+		if result < 0 {
+			return Err(error_string(result));
+		}
+
+		output.truncate(result as usize);
+		Ok(output)
+	}
+
+	pub fn reset_state(&mut self) -> Result<(), String> {
+		let result = unsafe { ffi::opus_multistream_encoder_ctl(self.encoder, ffi::OPUS_RESET_STATE as c_int) };
+		if result != ffi::OPUS_OK as c_int {
+			return Err(error_string(result));
+		}
+
+		Ok(())
+	}
+
+	fn ctl(&mut self, request: c_int, value: i32) -> Result<(), String> {
+		let result = unsafe { ffi::opus_multistream_encoder_ctl(self.encoder, request, value as ffi::opus_int32) };
+		if result != ffi::OPUS_OK as c_int {
+			return Err(error_string(result));
+		}
+
+		Ok(())
+	}
+}
+
+impl Drop for OpusEncoder {
+	fn drop(&mut self) {
+		unsafe { ffi::opus_multistream_encoder_destroy(self.encoder) };
+	}
+}
+
+fn error_string(error: c_int) -> String {
+	unsafe { CStr::from_ptr(ffi::opus_strerror(error)) }.to_string_lossy().into_owned()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn surround_params_match_sunshine() {
+		assert_eq!(OpusConfiguration::surround_params(2), ["21101", "21101"]);
+		assert_eq!(
+			OpusConfiguration::surround_params(8),
+			["21101", "21101", "642014235", "660012345", "85301423567", "88001234567"],
+		);
+	}
+}
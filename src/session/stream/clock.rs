@@ -0,0 +1,73 @@
+use std::time::{Duration, Instant};
+
+/// Interval at which the capture-to-send latency of a stream is logged.
+const LATENCY_REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Clock that is shared by the streams of a session, so that audio and video timestamps have the same origin.
+///
+/// Media is stamped with the time it was captured, which the clock converts to RTP timestamps.
+#[derive(Clone, Copy, Debug)]
+pub struct MediaClock {
+	/// Time the session started streaming, RTP timestamps are relative to this time.
+	origin: Instant,
+}
+
+impl MediaClock {
+	pub fn new() -> Self {
+		Self { origin: Instant::now() }
+	}
+
+	/// The RTP timestamp (in units of 90kHz) of media that was captured at `captured_at`.
+	pub fn rtp_timestamp(&self, captured_at: Instant) -> u32 {
+		(captured_at.saturating_duration_since(self.origin).as_micros() * 90 / 1000) as u32
+	}
+}
+
+/// Measures the time between capturing media and sending it to the client, and logs it periodically.
+///
+/// Media that was dropped instead of sent is reported as well.
+pub struct LatencyMonitor {
+	/// Name of the stream that is measured, used in the logs.
+	name: &'static str,
+
+	total: Duration,
+	max: Duration,
+	count: u32,
+	dropped: u32,
+	last_report: Instant,
+}
+
+impl LatencyMonitor {
+	pub fn new(name: &'static str) -> Self {
+		Self { name, total: Duration::ZERO, max: Duration::ZERO, count: 0, dropped: 0, last_report: Instant::now() }
+	}
+
+	/// Record that `count` frames or packets were dropped instead of sent.
+	pub fn record_dropped(&mut self, count: u32) {
+		self.dropped += count;
+	}
+
+	/// Record that media captured at `captured_at` was sent at `sent_at`.
+	pub fn record(&mut self, captured_at: Instant, sent_at: Instant) {
+		let latency = sent_at.saturating_duration_since(captured_at);
+		self.total += latency;
+		self.max = self.max.max(latency);
+		self.count += 1;
+
+		if sent_at.saturating_duration_since(self.last_report) >= LATENCY_REPORT_INTERVAL {
+			// Dropping media affects the client, so that is worth reporting at a higher level.
+			let level = if self.dropped > 0 { log::Level::Info } else { log::Level::Debug };
+			log::log!(
+				level,
+				"Capture to send latency of the {} stream: {:.1}ms average, {:.1}ms max over {} sent, {} dropped.",
+				self.name,
+				(self.total / self.count).as_secs_f64() * 1000.0,
+				self.max.as_secs_f64() * 1000.0,
+				self.count,
+				self.dropped,
+			);
+
+			*self = Self { last_report: sent_at, ..Self::new(self.name) };
+		}
+	}
+}
@@ -1,7 +1,7 @@
 use strum_macros::FromRepr;
 use tokio::sync::mpsc;
 
-use crate::session::stream::control::input::gamepad::Gamepad;
+use crate::{config::InputDeviceType, session::{recorder::Recorder, stream::control::input::gamepad::Gamepad}};
 
 use self::{
 	mouse::{
@@ -82,12 +82,21 @@ pub struct InputHandler {
 }
 
 impl InputHandler {
-	pub fn new() -> Result<Self, ()> {
-		let mouse = Mouse::new()?;
-		let keyboard = Keyboard::new()?;
+	/// Create the virtual input devices of the enabled types, the recorder (if any) is used for the replay hotkey.
+	pub fn new(input_devices: &[InputDeviceType], recorder: Option<Recorder>) -> Result<Self, ()> {
+		let mouse = if input_devices.contains(&InputDeviceType::Mouse) { Some(Mouse::new()?) } else { None };
+		let keyboard = if input_devices.contains(&InputDeviceType::Keyboard) { Some(Keyboard::new()?) } else { None };
+		let gamepads_enabled = input_devices.contains(&InputDeviceType::Gamepad);
+		log::debug!("Enabled input devices: {input_devices:?}");
 
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = InputHandlerInner { mouse, keyboard };
+		let inner = InputHandlerInner {
+			mouse,
+			keyboard,
+			gamepads_enabled,
+			recorder,
+			modifiers: Default::default(),
+		};
 		tokio::spawn(inner.run(command_rx));
 
 		Ok(Self { command_tx })
@@ -104,9 +113,39 @@ impl InputHandler {
 	}
 }
 
+/// Modifier keys that are held down, to detect hotkeys.
+#[derive(Default)]
+struct Modifiers {
+	control: bool,
+	alt: bool,
+	shift: bool,
+}
+
+impl Modifiers {
+	fn update(&mut self, key: &Key, pressed: bool) {
+		match key {
+			Key::Control | Key::LeftControl | Key::RightControl => self.control = pressed,
+			Key::Alt | Key::LeftAlt | Key::RightAlt => self.alt = pressed,
+			Key::Shift | Key::LeftShift | Key::RightShift => self.shift = pressed,
+			_ => { },
+		}
+	}
+
+	fn all(&self) -> bool {
+		self.control && self.alt && self.shift
+	}
+}
+
 struct InputHandlerInner {
-	mouse: Mouse,
-	keyboard: Keyboard,
+	/// Virtual mouse, or `None` if mouse input is disabled.
+	mouse: Option<Mouse>,
+
+	/// Virtual keyboard, or `None` if keyboard input is disabled.
+	keyboard: Option<Keyboard>,
+
+	gamepads_enabled: bool,
+	recorder: Option<Recorder>,
+	modifiers: Modifiers,
 }
 
 impl InputHandlerInner {
@@ -117,38 +156,71 @@ impl InputHandlerInner {
 			match command {
 				InputEvent::KeyDown(key) => {
 					log::trace!("Pressing key: {key:?}");
-					let _ = self.keyboard.key_down(key);
+					self.modifiers.update(&key, true);
+
+					// Ctrl+Alt+Shift+R saves a replay, instead of being passed on to the host.
+					if key == Key::R && self.modifiers.all() {
+						if let Some(recorder) = &self.recorder {
+							log::info!("Replay hotkey pressed, saving replay.");
+							let _ = recorder.save_replay().await;
+							continue;
+						}
+					}
+
+					if let Some(keyboard) = &mut self.keyboard {
+						let _ = keyboard.key_down(key);
+					}
 				},
 				InputEvent::KeyUp(key) => {
 					log::trace!("Releasing key: {key:?}");
-					let _ = self.keyboard.key_up(key);
+					self.modifiers.update(&key, false);
+					if let Some(keyboard) = &mut self.keyboard {
+						let _ = keyboard.key_up(key);
+					}
 				},
 				InputEvent::MouseMoveAbsolute(event) => {
 					log::trace!("Absolute mouse movement: {event:?}");
-					let _ = self.mouse.move_absolute(event.x as i32, event.y as i32);
+					if let Some(mouse) = &mut self.mouse {
+						let _ = mouse.move_absolute(event.x as i32, event.y as i32);
+					}
 				},
 				InputEvent::MouseMoveRelative(event) => {
 					log::trace!("Moving mouse relative: {event:?}");
-					let _ = self.mouse.move_relative(event.x as i32, event.y as i32);
+					if let Some(mouse) = &mut self.mouse {
+						let _ = mouse.move_relative(event.x as i32, event.y as i32);
+					}
 				},
 				InputEvent::MouseButtonDown(button) => {
 					log::trace!("Pressing mouse button: {button:?}");
-					let _ = self.mouse.button_down(button);
+					if let Some(mouse) = &mut self.mouse {
+						let _ = mouse.button_down(button);
+					}
 				},
 				InputEvent::MouseButtonUp(button) => {
 					log::trace!("Releasing mouse button: {button:?}");
-					let _ = self.mouse.button_up(button);
+					if let Some(mouse) = &mut self.mouse {
+						let _ = mouse.button_up(button);
+					}
 				},
 				InputEvent::MouseScrollVertical(event) => {
 					log::trace!("Scrolling vertically: {event:?}");
-					let _ = self.mouse.scroll_vertical(event.amount);
+					if let Some(mouse) = &mut self.mouse {
+						let _ = mouse.scroll_vertical(event.amount);
+					}
 				},
 				InputEvent::MouseScrollHorizontal(event) => {
 					log::trace!("Scrolling horizontally: {event:?}");
-					let _ = self.mouse.scroll_horizontal(event.amount);
+					if let Some(mouse) = &mut self.mouse {
+						let _ = mouse.scroll_horizontal(event.amount);
+					}
 				},
 				InputEvent::GamepadInfo(gamepad) => {
 					log::debug!("Gamepad info: {gamepad:?}");
+					if !self.gamepads_enabled {
+						log::debug!("Ignoring gamepad, gamepad input is disabled.");
+						continue;
+					}
+
 					if let Ok(gamepad) = Gamepad::new(gamepad) {
 						gamepads.push(gamepad);
 					}
@@ -1,3 +1,5 @@
+use std::time::Duration;
+
 use async_shutdown::ShutdownManager;
 use enet::{
 	Address,
@@ -5,13 +7,17 @@ use enet::{
 	ChannelLimit,
 	Enet,
 	Event,
+	Host,
+	Packet,
+	PacketMode,
+	PeerState,
 };
 use openssl::symm::Cipher;
 use tokio::sync::mpsc::{self, error::TryRecvError};
 
-use crate::{session::{SessionContext, SessionKeys}, config::Config};
+use crate::{session::{recorder::Recorder, SessionContext, SessionKeys}, config::Config};
 use self::input::InputHandler;
-use super::{VideoStream, AudioStream};
+use super::{VideoStream, AudioStream, video::{LossStats, SDR_WHITE_NITS}};
 
 mod input;
 
@@ -25,6 +31,8 @@ enum ControlMessageType {
 	Ping = 0x0200,
 	Termination = 0x0100,
 	RumbleData = 0x010b,
+	/// Only sent to the client, so it is never parsed from incoming messages.
+	HdrMode = 0x010e,
 	LossStats = 0x0201,
 	FrameStats = 0x0204,
 	InputData = 0x0206,
@@ -61,10 +69,10 @@ enum ControlMessage<'a> {
 	Ping,
 	Termination,
 	RumbleData,
-	LossStats,
+	LossStats(LossStats),
 	FrameStats,
 	InputData(&'a [u8]),
-	InvalidateReferenceFrames,
+	InvalidateReferenceFrames { first_frame: i64, last_frame: i64 },
 	RequestIdrFrame,
 	StartA,
 	StartB,
@@ -108,7 +116,22 @@ impl<'a> ControlMessage<'a> {
 			ControlMessageType::Ping => Ok(Self::Ping),
 			ControlMessageType::Termination => Ok(Self::Termination),
 			ControlMessageType::RumbleData => Ok(Self::RumbleData),
-			ControlMessageType::LossStats => Ok(Self::LossStats),
+			// Never parsed from incoming messages, because clients don't send it.
+			ControlMessageType::HdrMode => Err(()),
+			ControlMessageType::LossStats => {
+				// Loss count, report interval (ms), a constant 1000 and the last good frame, followed by unused values.
+				if buffer.len() < 4 + 20 {
+					log::info!("Expected loss stats message of at least {} bytes, got {} bytes.", 4 + 20, buffer.len());
+					return Err(());
+				}
+
+				Ok(Self::LossStats(LossStats {
+					loss_count: u32::from_le_bytes(buffer[4..8].try_into().unwrap()),
+					interval: Duration::from_millis(u32::from_le_bytes(buffer[8..12].try_into().unwrap()) as u64),
+					last_good_frame: u64::from_le_bytes(buffer[16..24].try_into().unwrap()),
+				}))
+			},
+			// Moonlight lists frame statistics as unused and never sends them, so their layout is unknown.
 			ControlMessageType::FrameStats => Ok(Self::FrameStats),
 			ControlMessageType::InputData => {
 				// Length of the input event, excluding the length itself.
@@ -120,7 +143,18 @@ impl<'a> ControlMessage<'a> {
 
 				Ok(Self::InputData(&buffer[8..]))
 			},
-			ControlMessageType::InvalidateReferenceFrames => Ok(Self::InvalidateReferenceFrames),
+			ControlMessageType::InvalidateReferenceFrames => {
+				// The payload contains the first and last lost frame, followed by an unused value.
+				if buffer.len() < 4 + 16 {
+					log::info!("Expected reference frame invalidation message of at least {} bytes, got {} bytes.", 4 + 16, buffer.len());
+					return Err(());
+				}
+
+				Ok(Self::InvalidateReferenceFrames {
+					first_frame: i64::from_le_bytes(buffer[4..12].try_into().unwrap()),
+					last_frame: i64::from_le_bytes(buffer[12..20].try_into().unwrap()),
+				})
+			},
 			ControlMessageType::RequestIdrFrame => Ok(Self::RequestIdrFrame),
 			ControlMessageType::StartA => Ok(Self::StartA),
 			ControlMessageType::StartB => Ok(Self::StartB),
@@ -136,6 +170,53 @@ struct EncryptedControlMessage {
 	payload: Vec<u8>,
 }
 
+/// HDR metadata of the stream, sent to the client together with the HDR mode.
+///
+/// Chromaticity coordinates are in units of 0.00002, luminance values are in nits unless stated otherwise.
+#[derive(Debug)]
+struct HdrMetadata {
+	/// Red, green and blue primaries of the mastering display.
+	display_primaries: [(u16, u16); 3],
+	white_point: (u16, u16),
+	max_display_luminance: u16,
+
+	/// Minimum display luminance in units of 0.0001 nits.
+	min_display_luminance: u16,
+	max_content_light_level: u16,
+	max_frame_average_light_level: u16,
+	max_full_frame_luminance: u16,
+}
+
+impl HdrMetadata {
+	/// Metadata for SDR content that is mapped to HDR, which has BT.709 primaries and peaks at SDR white.
+	fn sdr_in_hdr() -> Self {
+		let sdr_white = SDR_WHITE_NITS as u16;
+		Self {
+			display_primaries: [(32000, 16500), (15000, 30000), (7500, 3000)],
+			white_point: (15635, 16450),
+			max_display_luminance: sdr_white,
+			min_display_luminance: 1,
+			max_content_light_level: sdr_white,
+			max_frame_average_light_level: sdr_white,
+			max_full_frame_luminance: sdr_white,
+		}
+	}
+
+	fn serialize(&self, buffer: &mut Vec<u8>) {
+		for (x, y) in self.display_primaries {
+			buffer.extend(x.to_le_bytes());
+			buffer.extend(y.to_le_bytes());
+		}
+		buffer.extend(self.white_point.0.to_le_bytes());
+		buffer.extend(self.white_point.1.to_le_bytes());
+		buffer.extend(self.max_display_luminance.to_le_bytes());
+		buffer.extend(self.min_display_luminance.to_le_bytes());
+		buffer.extend(self.max_content_light_level.to_le_bytes());
+		buffer.extend(self.max_frame_average_light_level.to_le_bytes());
+		buffer.extend(self.max_full_frame_luminance.to_le_bytes());
+	}
+}
+
 enum ControlStreamCommand {
 	UpdateKeys(SessionKeys),
 }
@@ -146,15 +227,18 @@ pub struct ControlStream {
 
 impl ControlStream {
 	#[allow(clippy::result_unit_err)]
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(
 		config: Config,
 		video_stream: VideoStream,
 		audio_stream: AudioStream,
+		recorder: Option<Recorder>,
 		context: SessionContext,
+		hdr: bool,
 		enet: Enet,
 		stop_signal: ShutdownManager<()>,
 	) -> Result<Self, ()> {
-		let input_handler = InputHandler::new()?;
+		let input_handler = InputHandler::new(&config.stream.control.input_devices, recorder)?;
 
 		let (command_tx, command_rx) = mpsc::channel(10);
 		let inner = ControlStreamInner { };
@@ -167,6 +251,7 @@ impl ControlStream {
 						video_stream,
 						audio_stream,
 						context,
+						hdr,
 						enet,
 						input_handler,
 					)))
@@ -195,6 +280,7 @@ impl ControlStreamInner {
 		video_stream: VideoStream,
 		audio_stream: AudioStream,
 		mut context: SessionContext,
+		hdr: bool,
 		enet: Enet,
 		input_handler: InputHandler,
 	) -> Result<(), ()> {
@@ -217,6 +303,10 @@ impl ControlStreamInner {
 
 		let mut stop_deadline = std::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
 
+		// Messages to send to the client, these are sent once we are done handling incoming events.
+		let mut outgoing_messages = Vec::new();
+		let mut sequence_number = 0u32;
+
 		loop {
 			// Check if we received a command.
 			let command = command_rx.try_recv();
@@ -243,7 +333,14 @@ impl ControlStreamInner {
 			}
 
 			match host.service(1000).map_err(|e| log::error!("Failure in enet host: {e}"))? {
-				Some(Event::Connect(_)) => {},
+				Some(Event::Connect(_)) => {
+					// Let the client know that the stream is HDR, so that it switches its display to HDR.
+					if hdr {
+						let mut payload = vec![1u8];
+						HdrMetadata::sdr_in_hdr().serialize(&mut payload);
+						outgoing_messages.push((ControlMessageType::HdrMode, payload));
+					}
+				},
 				Some(Event::Disconnect(..)) => {},
 				Some(Event::Receive {
 					ref packet,
@@ -285,12 +382,18 @@ impl ControlStreamInner {
 
 					match control_message {
 						ControlMessage::Encrypted(_) => unreachable!("Encrypted control messages should be decrypted already."),
-						ControlMessage::RequestIdrFrame | ControlMessage::InvalidateReferenceFrames => {
+						ControlMessage::RequestIdrFrame => {
 							video_stream.request_idr_frame().await?;
 						},
+						ControlMessage::InvalidateReferenceFrames { first_frame, last_frame } => {
+							video_stream.invalidate_reference_frames(first_frame, last_frame).await?;
+						},
+						ControlMessage::LossStats(stats) => {
+							video_stream.report_loss_stats(stats).await?;
+						},
 						ControlMessage::StartB => {
 							audio_stream.start(context.keys.clone()).await?;
-							video_stream.start().await?;
+							video_stream.start(context.keys.clone()).await?;
 						},
 						ControlMessage::Ping => {
 							stop_deadline = std::time::Instant::now() + std::time::Duration::from_secs(config.stream_timeout);
@@ -305,9 +408,71 @@ impl ControlStreamInner {
 				}
 				_ => (),
 			}
+
+			for (message_type, payload) in outgoing_messages.drain(..) {
+				send_encrypted_message(&mut host, &context.keys, &mut sequence_number, message_type, &payload);
+			}
 		}
 
 		log::debug!("Control stream closing.");
 		Ok(())
 	}
 }
+
+/// Encrypt a control message and send it to all connected clients.
+fn send_encrypted_message(
+	host: &mut Host<()>,
+	keys: &SessionKeys,
+	sequence_number: &mut u32,
+	message_type: ControlMessageType,
+	payload: &[u8],
+) {
+	let mut message = Vec::with_capacity(4 + payload.len());
+	message.extend((message_type as u16).to_le_bytes());
+	message.extend((payload.len() as u16).to_le_bytes());
+	message.extend(payload);
+
+	let mut initialization_vector = [0u8; 16];
+	initialization_vector[0] = *sequence_number as u8;
+
+	let mut tag = [0u8; ENCRYPTION_TAG_LENGTH];
+	let encrypted = match openssl::symm::encrypt_aead(
+		Cipher::aes_128_gcm(),
+		&keys.remote_input_key,
+		Some(&initialization_vector),
+		&[],
+		&message,
+		&mut tag,
+	) {
+		Ok(encrypted) => encrypted,
+		Err(e) => {
+			log::error!("Failed to encrypt control message: {:?}", e.errors());
+			return;
+		},
+	};
+
+	let mut buffer = Vec::with_capacity(MINIMUM_ENCRYPTED_LENGTH + encrypted.len());
+	buffer.extend((ControlMessageType::Encrypted as u16).to_le_bytes());
+	buffer.extend(((4 + ENCRYPTION_TAG_LENGTH + encrypted.len()) as u16).to_le_bytes());
+	buffer.extend(sequence_number.to_le_bytes());
+	buffer.extend(tag);
+	buffer.extend(encrypted);
+	*sequence_number = sequence_number.wrapping_add(1);
+
+	for mut peer in host.peers() {
+		if !matches!(peer.state(), PeerState::Connected) {
+			continue;
+		}
+
+		let packet = match Packet::new(&buffer, PacketMode::ReliableSequenced) {
+			Ok(packet) => packet,
+			Err(e) => {
+				log::error!("Failed to create control packet: {e}");
+				return;
+			},
+		};
+		if let Err(e) = peer.send_packet(packet, 0) {
+			log::warn!("Failed to send control message to client: {e}");
+		}
+	}
+}
@@ -0,0 +1,133 @@
+use crate::config::Config;
+
+/// Encryption flag (`x-ss-general.encryption*`) for video packets.
+const SS_ENC_VIDEO: u32 = 0x02;
+
+/// Encryption flag (`x-ss-general.encryption*`) for audio packets.
+const SS_ENC_AUDIO: u32 = 0x04;
+
+/// Feature flags (`x-ss-general.featureFlags`) that we support.
+///
+/// Moonlight knows pen touch events (0x01) and controller touch events (0x02), neither is supported yet.
+const SUPPORTED_FEATURE_FLAGS: u32 = 0;
+
+/// Features that are negotiated with the client during the RTSP handshake.
+///
+/// We advertise our features in the DESCRIBE response, the client enables the ones it supports in its ANNOUNCE request.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct StreamFeatures {
+	/// Feature flags that both we and the client support.
+	pub feature_flags: u32,
+
+	/// Encryption flags of the streams that are encrypted.
+	pub encryption: u32,
+}
+
+impl StreamFeatures {
+	/// The SDP attributes that advertise our features, for the DESCRIBE response.
+	pub fn description(config: &Config) -> String {
+		let mut encryption_requested = 0;
+		if config.stream.video.encryption {
+			encryption_requested |= SS_ENC_VIDEO;
+		}
+		if config.stream.audio.encryption {
+			encryption_requested |= SS_ENC_AUDIO;
+		}
+
+		let mut description = format!("\na=x-ss-general.featureFlags:{SUPPORTED_FEATURE_FLAGS}");
+		description += &format!("\na=x-ss-general.encryptionSupported:{}", SS_ENC_AUDIO | SS_ENC_VIDEO);
+		description += &format!("\na=x-ss-general.encryptionRequested:{encryption_requested}");
+		description
+	}
+
+	/// Negotiate features from the attributes in the ANNOUNCE request of the client.
+	///
+	/// `encryption_enabled` is `None` for clients that don't negotiate encryption.
+	pub fn negotiate(config: &Config, feature_flags: u32, encryption_enabled: Option<u32>) -> Self {
+		let encryption = match encryption_enabled {
+			Some(encryption_enabled) => {
+				if config.stream.video.encryption && encryption_enabled & SS_ENC_VIDEO == 0 {
+					log::warn!("Client doesn't support video encryption, streaming unencrypted video.");
+				}
+				if config.stream.audio.encryption && encryption_enabled & SS_ENC_AUDIO == 0 {
+					log::warn!("Client doesn't support audio encryption, streaming unencrypted audio.");
+				}
+
+				encryption_enabled & (SS_ENC_AUDIO | SS_ENC_VIDEO)
+			},
+
+			// Clients that don't negotiate encryption can't encrypt video, audio is encrypted unless it is disabled.
+			None => {
+				if config.stream.video.encryption {
+					log::warn!("Client doesn't support video encryption, streaming unencrypted video.");
+				}
+
+				if config.stream.audio.encryption { SS_ENC_AUDIO } else { 0 }
+			},
+		};
+
+		Self {
+			feature_flags: feature_flags & SUPPORTED_FEATURE_FLAGS,
+			encryption,
+		}
+	}
+
+	pub fn video_encrypted(&self) -> bool {
+		self.encryption & SS_ENC_VIDEO != 0
+	}
+
+	pub fn audio_encrypted(&self) -> bool {
+		self.encryption & SS_ENC_AUDIO != 0
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn config(video_encryption: bool, audio_encryption: bool) -> Config {
+		let mut config = Config::default();
+		config.stream.video.encryption = video_encryption;
+		config.stream.audio.encryption = audio_encryption;
+		config
+	}
+
+	#[test]
+	fn without_encryption_negotiation_only_audio_is_encrypted() {
+		let features = StreamFeatures::negotiate(&config(true, true), 0, None);
+		assert!(!features.video_encrypted());
+		assert!(features.audio_encrypted());
+
+		let features = StreamFeatures::negotiate(&config(true, false), 0, None);
+		assert!(!features.video_encrypted());
+		assert!(!features.audio_encrypted());
+	}
+
+	#[test]
+	fn encryption_follows_the_client() {
+		let features = StreamFeatures::negotiate(&config(true, true), 0, Some(SS_ENC_VIDEO | SS_ENC_AUDIO));
+		assert!(features.video_encrypted());
+		assert!(features.audio_encrypted());
+
+		let features = StreamFeatures::negotiate(&config(true, true), 0, Some(SS_ENC_AUDIO));
+		assert!(!features.video_encrypted());
+		assert!(features.audio_encrypted());
+
+		let features = StreamFeatures::negotiate(&config(true, true), 0, Some(0));
+		assert_eq!(features.encryption, 0);
+	}
+
+	#[test]
+	fn unknown_flags_are_ignored() {
+		let features = StreamFeatures::negotiate(&config(true, true), 0x03, Some(0x01 | SS_ENC_VIDEO));
+		assert_eq!(features.feature_flags, SUPPORTED_FEATURE_FLAGS);
+		assert_eq!(features.encryption, SS_ENC_VIDEO);
+	}
+
+	#[test]
+	fn description_requests_configured_encryption() {
+		let description = StreamFeatures::description(&config(false, true));
+		assert!(description.contains("a=x-ss-general.encryptionSupported:6"));
+		assert!(description.contains(&format!("a=x-ss-general.encryptionRequested:{SS_ENC_AUDIO}")));
+	}
+}
@@ -1,11 +1,15 @@
 pub use self::{
-	audio::{AudioStreamContext, AudioStream},
-	video::{VideoStreamContext, VideoStream},
+	audio::{AudioStreamContext, AudioStream, OpusConfiguration, VirtualSink},
+	video::{VideoCapabilities, VideoFormat, VideoStreamContext, VideoStream},
+	clock::MediaClock,
 	control::ControlStream,
+	features::StreamFeatures,
 };
 
 mod audio;
+mod clock;
 mod control;
+mod features;
 mod video;
 
 #[derive(Debug)]
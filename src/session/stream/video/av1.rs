@@ -0,0 +1,115 @@
+//! Helpers for handling AV1 bitstreams, which consist of a sequence of OBUs (Open Bitstream Units).
+//!
+//! See section 5.3 of the AV1 specification for the layout of OBUs.
+
+const OBU_SEQUENCE_HEADER: u8 = 1;
+const OBU_TEMPORAL_DELIMITER: u8 = 2;
+
+/// Size of the header of an `AV1CodecConfigurationRecord`, after which the configuration OBUs follow.
+const AV1C_HEADER_SIZE: usize = 4;
+
+#[derive(Debug)]
+struct Obu<'a> {
+	obu_type: u8,
+
+	/// The entire OBU, including its header.
+	data: &'a [u8],
+}
+
+/// Read a variable length unsigned integer, returning the value and the number of bytes read.
+fn read_leb128(data: &[u8]) -> Option<(u64, usize)> {
+	let mut value = 0u64;
+	for (index, byte) in data.iter().take(8).enumerate() {
+		value |= ((byte & 0x7f) as u64) << (index * 7);
+		if byte & 0x80 == 0 {
+			return Some((value, index + 1));
+		}
+	}
+
+	None
+}
+
+/// Split a buffer in the low overhead bitstream format in to OBUs.
+fn parse_obus(mut data: &[u8]) -> Result<Vec<Obu>, ()> {
+	let mut obus = Vec::new();
+
+	while !data.is_empty() {
+		let header = data[0];
+		let obu_type = (header >> 3) & 0x0f;
+		let has_extension = (header >> 2) & 0x01 == 1;
+		let has_size = (header >> 1) & 0x01 == 1;
+		if !has_size {
+			log::warn!("Received AV1 OBU without size field, which is not supported.");
+			return Err(());
+		}
+
+		let header_size = 1 + has_extension as usize;
+		let (payload_size, leb128_size) = data.get(header_size..)
+			.and_then(read_leb128)
+			.ok_or_else(|| log::warn!("Failed to read size of AV1 OBU."))?;
+
+		let obu_size = header_size + leb128_size + payload_size as usize;
+		if obu_size > data.len() {
+			log::warn!("AV1 OBU of {obu_size} bytes exceeds the remaining {} bytes.", data.len());
+			return Err(());
+		}
+
+		obus.push(Obu { obu_type, data: &data[..obu_size] });
+		data = &data[obu_size..];
+	}
+
+	Ok(obus)
+}
+
+/// Makes sure every keyframe carries a sequence header.
+///
+/// Moonlight needs the sequence header to (re)initialize its decoder on every keyframe,
+/// but not every encoder repeats it when a keyframe is forced.
+#[derive(Default)]
+pub struct SequenceHeaderInjector {
+	sequence_header: Option<Vec<u8>>,
+}
+
+impl SequenceHeaderInjector {
+	/// Initialize the sequence header from the extradata of an encoder, if it has any.
+	pub fn from_extradata(extradata: &[u8]) -> Self {
+		let sequence_header = extradata.get(AV1C_HEADER_SIZE..)
+			.and_then(|config_obus| parse_obus(config_obus).ok())
+			.and_then(|obus| obus.into_iter().find(|obu| obu.obu_type == OBU_SEQUENCE_HEADER))
+			.map(|obu| obu.data.to_vec());
+
+		Self { sequence_header }
+	}
+
+	/// Returns the frame with a sequence header inserted if it is a keyframe that is missing one.
+	pub fn process(&mut self, data: &[u8], key_frame: bool) -> Vec<u8> {
+		let obus = match parse_obus(data) {
+			Ok(obus) => obus,
+			Err(()) => return data.to_vec(),
+		};
+
+		if let Some(sequence_header) = obus.iter().find(|obu| obu.obu_type == OBU_SEQUENCE_HEADER) {
+			self.sequence_header = Some(sequence_header.data.to_vec());
+			return data.to_vec();
+		}
+
+		let Some(sequence_header) = self.sequence_header.as_ref().filter(|_| key_frame) else {
+			return data.to_vec();
+		};
+
+		log::trace!("Inserting AV1 sequence header in keyframe.");
+
+		// The sequence header has to follow the temporal delimiter, if there is one.
+		let mut output = Vec::with_capacity(data.len() + sequence_header.len());
+		let mut obus = obus.into_iter().peekable();
+		if let Some(temporal_delimiter) = obus.next_if(|obu| obu.obu_type == OBU_TEMPORAL_DELIMITER) {
+			output.extend(temporal_delimiter.data);
+		}
+		output.extend(sequence_header);
+		for obu in obus {
+			output.extend(obu.data);
+		}
+
+		output
+	}
+}
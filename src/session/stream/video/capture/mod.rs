@@ -0,0 +1,99 @@
+use std::{sync::{Arc, Mutex}, time::Instant};
+
+use async_shutdown::ShutdownManager;
+use ffmpeg::Frame;
+
+mod nvfbc;
+pub use self::nvfbc::NvFbcSource;
+
+mod test_pattern;
+pub use self::test_pattern::TestPatternSource;
+
+/// A source of frames that can be streamed to a client.
+///
+/// Frames are always produced in a packed BGRA format.
+pub trait FrameSource: Send {
+	/// The width and height of the frames produced by this source.
+	///
+	/// This can change while capturing, in which case `capture` returns `false` until the capture is restarted.
+	fn size(&self) -> (u32, u32);
+
+	/// Prepare the source for capturing, this is called from the capture thread.
+	fn start(&mut self, framerate: u32) -> Result<(), ()>;
+
+	/// Capture the next frame in to `frame`.
+	///
+	/// Returns `false` if no frame was captured and the frame should not be used.
+	fn capture(&mut self, frame: &mut Frame) -> Result<bool, ()>;
+}
+
+/// The most recently captured frame, shared between the capture thread and the encode thread.
+pub struct CapturedFrame {
+	pub frame: Frame,
+
+	/// Whether this frame was captured after the encoder last took a frame.
+	pub is_new: bool,
+
+	/// Time at which the frame was captured.
+	pub captured_at: Instant,
+}
+
+impl CapturedFrame {
+	pub fn new(frame: Frame) -> Self {
+		Self { frame, is_new: false, captured_at: Instant::now() }
+	}
+}
+
+pub struct FrameCapturer {
+	source: Box<dyn FrameSource>,
+}
+
+impl FrameCapturer {
+	pub fn new(source: Box<dyn FrameSource>) -> Self {
+		Self { source }
+	}
+
+	pub fn size(&self) -> (u32, u32) {
+		self.source.size()
+	}
+
+	/// Capture frames until stopped or until the size of the source changes.
+	///
+	/// The capturer is returned so that capturing can be restarted with buffers of the new size.
+	pub fn run(
+		mut self,
+		framerate: u32,
+		mut capture_buffer: Frame,
+		intermediate_buffer: Arc<Mutex<CapturedFrame>>,
+		stop_signal: ShutdownManager<()>,
+	) -> Result<Self, ()> {
+		let size = self.source.size();
+		self.source.start(framerate)?;
+		log::info!("Started frame capture.");
+
+		while !stop_signal.is_shutdown_triggered() {
+			if !self.source.capture(&mut capture_buffer)? {
+				let new_size = self.source.size();
+				if new_size != size {
+					log::info!("Capture size changed from {}x{} to {}x{}, stopping capture.", size.0, size.1, new_size.0, new_size.1);
+					return Ok(self);
+				}
+
+				continue;
+			}
+			let captured_at = Instant::now();
+
+			// Swap the intermediate buffer with the output buffer and mark that we have a new frame.
+			// Note that the lock is only held while swapping buffers, to minimize wait time for others locking the buffer.
+			let mut lock = intermediate_buffer.lock()
+				.map_err(|e| log::error!("Failed to lock intermediate buffer: {e}"))?;
+			std::mem::swap(&mut lock.frame, &mut capture_buffer);
+			lock.is_new = true;
+			lock.captured_at = captured_at;
+		}
+
+		log::debug!("Received stop signal.");
+
+		Ok(self)
+	}
+}
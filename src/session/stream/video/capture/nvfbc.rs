@@ -0,0 +1,100 @@
+use ffmpeg::{format::Pixel, Frame};
+use ::nvfbc::{CudaCapturer, BufferFormat, cuda::CaptureMethod};
+
+use super::FrameSource;
+
+/// Captures the desktop using NvFBC, directly in to CUDA memory.
+pub struct NvFbcSource {
+	capturer: CudaCapturer,
+	size: (u32, u32),
+	staging_buffer: Vec<u8>,
+}
+
+impl NvFbcSource {
+	pub fn new() -> Result<Self, ()> {
+		let capturer = CudaCapturer::new()
+			.map_err(|e| log::error!("Failed to create CUDA capture device: {e}"))?;
+		let status = capturer.status()
+			.map_err(|e| log::error!("Failed to get NvFBC status: {e}"))?;
+		capturer.release_context()
+			.map_err(|e| log::error!("Failed to release frame capturer CUDA context: {e}"))?;
+
+		Ok(Self { capturer, size: (status.screen_size.w, status.screen_size.h), staging_buffer: Vec::new() })
+	}
+}
+
+impl FrameSource for NvFbcSource {
+	fn size(&self) -> (u32, u32) {
+		self.size
+	}
+
+	fn start(&mut self, framerate: u32) -> Result<(), ()> {
+		self.capturer.bind_context()
+			.map_err(|e| log::error!("Failed to bind frame capturer CUDA context: {e}"))?;
+		self.capturer.start(BufferFormat::Bgra, framerate)
+			.map_err(|e| log::error!("Failed to start CUDA capture device: {e}"))
+	}
+
+	fn capture(&mut self, frame: &mut Frame) -> Result<bool, ()> {
+		let frame_info = match self.capturer.next_frame(CaptureMethod::NoWaitIfNewFrame) {
+			Ok(frame_info) => frame_info,
+			Err(e) => {
+				// NvFBC can't capture anymore after the screen resolution changed, check if that is what happened.
+				let status = self.capturer.status()
+					.map_err(|e| log::error!("Failed to get NvFBC status: {e}"))?;
+				let size = (status.screen_size.w, status.screen_size.h);
+				if size == self.size {
+					log::error!("Failed to wait for new CUDA frame: {e}");
+					return Err(());
+				}
+
+				// Stop capturing, so that capturing can be started again with the new size.
+				self.capturer.stop()
+					.map_err(|e| log::error!("Failed to stop CUDA capture device: {e}"))?;
+				self.capturer.release_context()
+					.map_err(|e| log::error!("Failed to release frame capturer CUDA context: {e}"))?;
+				self.size = size;
+
+				return Ok(false);
+			},
+		};
+		log::trace!("Frame info: {:#?}", frame_info);
+
+		unsafe {
+			let data = (*frame.as_mut_ptr()).data[0];
+			let result = if (*frame.as_ptr()).format == ffmpeg::sys::AVPixelFormat::from(Pixel::CUDA) as i32 {
+				cudarc::driver::result::memcpy_dtod_sync(
+					data as cudarc::driver::sys::CUdeviceptr,
+					frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr,
+					frame_info.device_buffer_len as usize
+				)
+			} else {
+				// Frames in system memory may have padding at the end of each row, so download to
+				// a staging buffer first if the row sizes don't match.
+				let stride = self.size.0 as usize * 4;
+				let linesize = (*frame.as_ptr()).linesize[0] as usize;
+				if linesize == stride {
+					let buffer = std::slice::from_raw_parts_mut(data, frame_info.device_buffer_len as usize);
+					cudarc::driver::result::memcpy_dtoh_sync(buffer, frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr)
+				} else {
+					self.staging_buffer.resize(frame_info.device_buffer_len as usize, 0);
+					let result = cudarc::driver::result::memcpy_dtoh_sync(
+						self.staging_buffer.as_mut_slice(),
+						frame_info.device_buffer as cudarc::driver::sys::CUdeviceptr,
+					);
+					for (y, row) in self.staging_buffer.chunks_exact(stride).enumerate() {
+						std::ptr::copy_nonoverlapping(row.as_ptr(), data.add(y * linesize), stride);
+					}
+					result
+				}
+			};
+
+			if let Err(e) = result {
+				log::error!("Failed to copy CUDA memory: {e}");
+				return Ok(false);
+			}
+		}
+
+		Ok(true)
+	}
+}
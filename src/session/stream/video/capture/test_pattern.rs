@@ -0,0 +1,165 @@
+use std::time::{Duration, Instant};
+
+use ffmpeg::{format::Pixel, Frame};
+
+use super::FrameSource;
+
+/// Colours of the bars in the test pattern, in BGRA.
+const BARS: [[u8; 4]; 8] = [
+	[0xff, 0xff, 0xff, 0xff], // White
+	[0x00, 0xff, 0xff, 0xff], // Yellow
+	[0xff, 0xff, 0x00, 0xff], // Cyan
+	[0x00, 0xff, 0x00, 0xff], // Green
+	[0xff, 0x00, 0xff, 0xff], // Magenta
+	[0x00, 0x00, 0xff, 0xff], // Red
+	[0xff, 0x00, 0x00, 0xff], // Blue
+	[0x00, 0x00, 0x00, 0xff], // Black
+];
+
+/// Bitmaps for the digits 0 to 9, each row is 3 bits wide (MSB is the left pixel).
+const DIGITS: [[u8; 5]; 10] = [
+	[0b111, 0b101, 0b101, 0b101, 0b111],
+	[0b010, 0b110, 0b010, 0b010, 0b111],
+	[0b111, 0b001, 0b111, 0b100, 0b111],
+	[0b111, 0b001, 0b111, 0b001, 0b111],
+	[0b101, 0b101, 0b111, 0b001, 0b001],
+	[0b111, 0b100, 0b111, 0b001, 0b111],
+	[0b111, 0b100, 0b111, 0b101, 0b111],
+	[0b111, 0b001, 0b010, 0b010, 0b010],
+	[0b111, 0b101, 0b111, 0b101, 0b111],
+	[0b111, 0b101, 0b111, 0b001, 0b111],
+];
+
+/// Number of pixels the bars move every frame.
+const SCROLL_SPEED: u32 = 4;
+
+/// Generates moving colour bars with the frame number burned in.
+///
+/// This source doesn't require any specific hardware, which makes it useful for testing.
+pub struct TestPatternSource {
+	width: u32,
+	height: u32,
+	frame_number: u64,
+	frame_interval: Duration,
+	next_frame_time: Instant,
+	buffer: Vec<u8>,
+}
+
+impl TestPatternSource {
+	pub fn new(width: u32, height: u32) -> Self {
+		Self {
+			width,
+			height,
+			frame_number: 0,
+			frame_interval: Duration::from_secs(1),
+			next_frame_time: Instant::now(),
+			buffer: vec![0u8; width as usize * height as usize * 4],
+		}
+	}
+
+	fn render(&mut self) {
+		let stride = self.width as usize * 4;
+		let bar_width = (self.width / BARS.len() as u32).max(1);
+		let offset = (self.frame_number as u32).wrapping_mul(SCROLL_SPEED);
+
+		// The bars only depend on the horizontal position, so render a single row and copy it to the other rows.
+		for x in 0..self.width {
+			let bar = ((x.wrapping_add(offset) / bar_width) as usize) % BARS.len();
+			self.buffer[x as usize * 4..x as usize * 4 + 4].copy_from_slice(&BARS[bar]);
+		}
+		for y in 1..self.height as usize {
+			self.buffer.copy_within(..stride, y * stride);
+		}
+
+		// Burn the frame number in to the top left corner, on a black background.
+		let digits = self.frame_number.to_string();
+		let scale = (self.height as usize / 60).max(2);
+		let margin = scale * 2;
+		let digit_advance = 4 * scale;
+		let background_width = (digits.len() * digit_advance + margin).min(self.width as usize);
+		let background_height = (5 * scale + 2 * margin).min(self.height as usize);
+		for y in 0..background_height {
+			self.buffer[y * stride..y * stride + background_width * 4].fill(0);
+		}
+
+		for (index, digit) in digits.bytes().enumerate() {
+			let bitmap = &DIGITS[(digit - b'0') as usize];
+			for (row, bits) in bitmap.iter().enumerate() {
+				for column in 0..3 {
+					if bits & (0b100 >> column) == 0 {
+						continue;
+					}
+
+					let x = margin + index * digit_advance + column * scale;
+					let y = margin + row * scale;
+					for y in y..(y + scale).min(self.height as usize) {
+						let start = x.min(self.width as usize);
+						let end = (x + scale).min(self.width as usize);
+						self.buffer[y * stride + start * 4..y * stride + end * 4].fill(0xff);
+					}
+				}
+			}
+		}
+	}
+
+	fn copy_to_frame(&self, frame: &mut Frame) -> Result<(), ()> {
+		let stride = self.width as usize * 4;
+
+		unsafe {
+			let linesize = (*frame.as_ptr()).linesize[0] as usize;
+			let data = (*frame.as_mut_ptr()).data[0];
+
+			if (*frame.as_ptr()).format == ffmpeg::sys::AVPixelFormat::from(Pixel::CUDA) as i32 {
+				// Frame lives in CUDA memory, upload it row by row (or at once if the rows are contiguous).
+				if linesize == stride {
+					cudarc::driver::result::memcpy_htod_sync(data as cudarc::driver::sys::CUdeviceptr, self.buffer.as_slice())
+						.map_err(|e| log::error!("Failed to upload test pattern to CUDA memory: {e}"))?;
+				} else {
+					for (y, row) in self.buffer.chunks_exact(stride).enumerate() {
+						cudarc::driver::result::memcpy_htod_sync(
+							data.add(y * linesize) as cudarc::driver::sys::CUdeviceptr,
+							row,
+						)
+							.map_err(|e| log::error!("Failed to upload test pattern to CUDA memory: {e}"))?;
+					}
+				}
+			} else {
+				for (y, row) in self.buffer.chunks_exact(stride).enumerate() {
+					std::ptr::copy_nonoverlapping(row.as_ptr(), data.add(y * linesize), stride);
+				}
+			}
+		}
+
+		Ok(())
+	}
+}
+
+impl FrameSource for TestPatternSource {
+	fn size(&self) -> (u32, u32) {
+		(self.width, self.height)
+	}
+
+	fn start(&mut self, framerate: u32) -> Result<(), ()> {
+		self.frame_interval = Duration::from_secs(1) / framerate.max(1);
+		self.next_frame_time = Instant::now();
+		Ok(())
+	}
+
+	fn capture(&mut self, frame: &mut Frame) -> Result<bool, ()> {
+		// Wait until it is time to produce the next frame.
+		let now = Instant::now();
+		if self.next_frame_time > now {
+			std::thread::sleep(self.next_frame_time - now);
+			self.next_frame_time += self.frame_interval;
+		} else {
+			// We are lagging behind, don't try to catch up.
+			self.next_frame_time = now + self.frame_interval;
+		}
+
+		self.render();
+		self.copy_to_frame(frame)?;
+		self.frame_number += 1;
+
+		Ok(true)
+	}
+}
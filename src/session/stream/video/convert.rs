@@ -0,0 +1,592 @@
+use std::{ptr::null_mut, sync::{Arc, OnceLock}};
+
+use cudarc::{driver::{CudaDevice, CudaFunction, DeviceRepr, LaunchAsync, LaunchConfig}, nvrtc::Ptx};
+use ffmpeg::{format::Pixel, Frame};
+
+use crate::ffmpeg::check_ret;
+
+/// Brightness (in nits) that SDR white is mapped to in HDR, as recommended by ITU-R BT.2408.
+pub const SDR_WHITE_NITS: f32 = 203.0;
+
+/// Peak brightness (in nits) of the PQ transfer function.
+const PQ_MAX_NITS: f32 = 10000.0;
+
+/// Converts linear BT.709 RGB to linear BT.2020 RGB.
+const BT709_TO_BT2020: [[f32; 3]; 3] = [
+	[0.6274, 0.3293, 0.0433],
+	[0.0691, 0.9195, 0.0114],
+	[0.0164, 0.0880, 0.8956],
+];
+
+/// Number of entries in the lookup table for the PQ transfer function.
+const PQ_LUT_SIZE: usize = 1 << 16;
+
+const CUDA_MODULE_NAME: &str = "convert";
+const CUDA_SCALE_BGRA: &str = "scale_bgra";
+const CUDA_BGRA_TO_P010: &str = "bgra_to_p010";
+const CUDA_BGRA_TO_YUV444: &str = "bgra_to_yuv444";
+
+/// Kernels that scale BGRA (sRGB) frames, optionally converting them to P010 (BT.2020, PQ, limited range)
+/// or planar YUV 4:4:4 (BT.709, limited range).
+///
+/// The source is scaled in to a rectangle of the destination, everything outside of that rectangle is black.
+const CUDA_SOURCE: &str = r#"
+struct Geometry {
+	int src_width;
+	int src_height;
+	int rect_x;
+	int rect_y;
+	int rect_width;
+	int rect_height;
+};
+
+__device__ float3 fetch(const unsigned char* src, int src_pitch, int x, int y) {
+	const unsigned char* pixel = src + y * src_pitch + x * 4;
+	return make_float3(pixel[2] / 255.0f, pixel[1] / 255.0f, pixel[0] / 255.0f);
+}
+
+__device__ float3 lerp3(float3 a, float3 b, float t) {
+	return make_float3(a.x + (b.x - a.x) * t, a.y + (b.y - a.y) * t, a.z + (b.z - a.z) * t);
+}
+
+/// Sample the source with bilinear filtering for a destination pixel, returns black outside of the destination rectangle.
+__device__ float3 sample(const unsigned char* src, int src_pitch, Geometry g, int x, int y) {
+	if (x < g.rect_x || x >= g.rect_x + g.rect_width || y < g.rect_y || y >= g.rect_y + g.rect_height) {
+		return make_float3(0.0f, 0.0f, 0.0f);
+	}
+
+	float sx = fminf(fmaxf((x - g.rect_x + 0.5f) * g.src_width / g.rect_width - 0.5f, 0.0f), g.src_width - 1.0f);
+	float sy = fminf(fmaxf((y - g.rect_y + 0.5f) * g.src_height / g.rect_height - 0.5f, 0.0f), g.src_height - 1.0f);
+	int x0 = (int)sx;
+	int y0 = (int)sy;
+	int x1 = min(x0 + 1, g.src_width - 1);
+	int y1 = min(y0 + 1, g.src_height - 1);
+
+	float3 top = lerp3(fetch(src, src_pitch, x0, y0), fetch(src, src_pitch, x1, y0), sx - x0);
+	float3 bottom = lerp3(fetch(src, src_pitch, x0, y1), fetch(src, src_pitch, x1, y1), sx - x0);
+	return lerp3(top, bottom, sy - y0);
+}
+
+__device__ float srgb_to_linear(float c) {
+	return c <= 0.04045f ? c / 12.92f : powf((c + 0.055f) / 1.055f, 2.4f);
+}
+
+__device__ float linear_to_pq(float l) {
+	const float m1 = 0.1593017578125f;
+	const float m2 = 78.84375f;
+	const float c1 = 0.8359375f;
+	const float c2 = 18.8515625f;
+	const float c3 = 18.6875f;
+
+	float y = powf(fmaxf(l, 0.0f), m1);
+	return powf((c1 + c2 * y) / (1.0f + c3 * y), m2);
+}
+
+__device__ unsigned short to_p010(float value, float scale, float offset) {
+	return (unsigned short)(fminf(fmaxf(value * scale + offset + 0.5f, 0.0f), 1023.0f)) << 6;
+}
+
+__device__ unsigned char to_u8(float value, float scale, float offset) {
+	return (unsigned char)fminf(fmaxf(value * scale + offset + 0.5f, 0.0f), 255.0f);
+}
+
+/// Every thread converts a single pixel.
+extern "C" __global__ void scale_bgra(
+	const unsigned char* src, int src_pitch,
+	unsigned char* dst, int dst_pitch,
+	int width, int height, Geometry geometry
+) {
+	int x = blockIdx.x * blockDim.x + threadIdx.x;
+	int y = blockIdx.y * blockDim.y + threadIdx.y;
+	if (x >= width || y >= height) {
+		return;
+	}
+
+	float3 c = sample(src, src_pitch, geometry, x, y);
+	unsigned char* pixel = dst + y * dst_pitch + x * 4;
+	pixel[0] = (unsigned char)(c.z * 255.0f + 0.5f);
+	pixel[1] = (unsigned char)(c.y * 255.0f + 0.5f);
+	pixel[2] = (unsigned char)(c.x * 255.0f + 0.5f);
+	pixel[3] = 255;
+}
+
+/// Every thread converts a block of 2x2 pixels, which share the same chroma sample.
+extern "C" __global__ void bgra_to_p010(
+	const unsigned char* src, int src_pitch,
+	unsigned char* dst_y, int y_pitch,
+	unsigned char* dst_uv, int uv_pitch,
+	int width, int height, Geometry geometry, float white
+) {
+	int x = (blockIdx.x * blockDim.x + threadIdx.x) * 2;
+	int y = (blockIdx.y * blockDim.y + threadIdx.y) * 2;
+	if (x >= width || y >= height) {
+		return;
+	}
+
+	float cb = 0.0f;
+	float cr = 0.0f;
+	for (int dy = 0; dy < 2; dy++) {
+		for (int dx = 0; dx < 2; dx++) {
+			int px = min(x + dx, width - 1);
+			int py = min(y + dy, height - 1);
+			float3 c = sample(src, src_pitch, geometry, px, py);
+
+			float r = srgb_to_linear(c.x);
+			float g = srgb_to_linear(c.y);
+			float b = srgb_to_linear(c.z);
+
+			float r2 = linear_to_pq((0.6274f * r + 0.3293f * g + 0.0433f * b) * white);
+			float g2 = linear_to_pq((0.0691f * r + 0.9195f * g + 0.0114f * b) * white);
+			float b2 = linear_to_pq((0.0164f * r + 0.0880f * g + 0.8956f * b) * white);
+
+			float luma = 0.2627f * r2 + 0.6780f * g2 + 0.0593f * b2;
+			cb += (b2 - luma) / 1.8814f;
+			cr += (r2 - luma) / 1.4746f;
+
+			*(unsigned short*)(dst_y + py * y_pitch + px * 2) = to_p010(luma, 876.0f, 64.0f);
+		}
+	}
+
+	unsigned short* uv = (unsigned short*)(dst_uv + (y / 2) * uv_pitch) + x;
+	uv[0] = to_p010(cb / 4.0f, 896.0f, 512.0f);
+	uv[1] = to_p010(cr / 4.0f, 896.0f, 512.0f);
+}
+
+/// Every thread converts a single pixel, chroma isn't subsampled so every pixel has its own chroma sample.
+extern "C" __global__ void bgra_to_yuv444(
+	const unsigned char* src, int src_pitch,
+	unsigned char* dst_y, int y_pitch,
+	unsigned char* dst_u, int u_pitch,
+	unsigned char* dst_v, int v_pitch,
+	int width, int height, Geometry geometry
+) {
+	int x = blockIdx.x * blockDim.x + threadIdx.x;
+	int y = blockIdx.y * blockDim.y + threadIdx.y;
+	if (x >= width || y >= height) {
+		return;
+	}
+
+	float3 c = sample(src, src_pitch, geometry, x, y);
+	float luma = 0.2126f * c.x + 0.7152f * c.y + 0.0722f * c.z;
+	dst_y[y * y_pitch + x] = to_u8(luma, 219.0f, 16.0f);
+	dst_u[y * u_pitch + x] = to_u8((c.z - luma) / 1.8556f, 224.0f, 128.0f);
+	dst_v[y * v_pitch + x] = to_u8((c.x - luma) / 1.5748f, 224.0f, 128.0f);
+}
+"#;
+
+/// Area of the output frame that the input frame is scaled to.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+	pub x: u32,
+	pub y: u32,
+	pub width: u32,
+	pub height: u32,
+}
+
+impl Rect {
+	/// Fit `input` in `output` while preserving the aspect ratio, centering it with black bars on the sides.
+	///
+	/// All values are even, so that the rectangle aligns with subsampled chroma planes.
+	pub fn letterbox(input: (u32, u32), output: (u32, u32)) -> Self {
+		let (width, height) = if input.0 as u64 * output.1 as u64 > input.1 as u64 * output.0 as u64 {
+			// Input is wider than the output, so add bars above and below.
+			(output.0, (input.1 as u64 * output.0 as u64 / input.0 as u64) as u32)
+		} else {
+			(((input.0 as u64 * output.1 as u64) / input.1 as u64) as u32, output.1)
+		};
+		let width = (width & !1).max(2).min(output.0);
+		let height = (height & !1).max(2).min(output.1);
+
+		Self {
+			x: ((output.0 - width) / 2) & !1,
+			y: ((output.1 - height) / 2) & !1,
+			width,
+			height,
+		}
+	}
+}
+
+/// Geometry of the scaling operation, matches the `Geometry` struct in the CUDA kernels.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+struct Geometry {
+	src_width: i32,
+	src_height: i32,
+	rect_x: i32,
+	rect_y: i32,
+	rect_width: i32,
+	rect_height: i32,
+}
+
+unsafe impl DeviceRepr for Geometry { }
+
+/// Converts frames in system memory to the pixel format and resolution of a software encoder.
+pub struct SoftwareConverter {
+	context: *mut ffmpeg::sys::SwsContext,
+	pq_transform: Option<PqTransform>,
+	output: Frame,
+	input_height: u32,
+	rect: Rect,
+
+	/// Factor by which the chroma planes of the output are subsampled, in both directions.
+	chroma_subsampling: u32,
+}
+
+unsafe impl Send for SoftwareConverter { }
+
+impl SoftwareConverter {
+	/// Create a converter from `input_format` to `output_format`, scaling the input to fit the output.
+	///
+	/// When `hdr` is set the input format must be `Pixel::BGRZ`, it is then converted to BT.2020 with the PQ transfer function.
+	pub fn new(
+		input_size: (u32, u32),
+		output_size: (u32, u32),
+		input_format: Pixel,
+		output_format: Pixel,
+		hdr: bool,
+	) -> Result<Self, ()> {
+		let (pq_transform, input_format) = if hdr {
+			(Some(PqTransform::new(input_size.0, input_size.1)?), Pixel::RGB48LE)
+		} else {
+			(None, input_format)
+		};
+
+		let rect = Rect::letterbox(input_size, output_size);
+		let context = unsafe {
+			ffmpeg::sys::sws_getContext(
+				input_size.0 as i32,
+				input_size.1 as i32,
+				input_format.into(),
+				rect.width as i32,
+				rect.height as i32,
+				output_format.into(),
+				ffmpeg::sys::SWS_BILINEAR,
+				null_mut(),
+				null_mut(),
+				std::ptr::null(),
+			)
+		};
+		if context.is_null() {
+			log::error!("Failed to create conversion context from {input_format:?} to {output_format:?}.");
+			return Err(());
+		}
+
+		// Convert from full range RGB to limited range BT.709 (or BT.2020 for HDR), which is what we signal to the client.
+		unsafe {
+			let colorspace = if hdr { ffmpeg::sys::SWS_CS_BT2020 } else { ffmpeg::sys::SWS_CS_ITU709 };
+			let coefficients = ffmpeg::sys::sws_getCoefficients(colorspace);
+			ffmpeg::sys::sws_setColorspaceDetails(context, coefficients, 1, coefficients, 0, 0, 1 << 16, 1 << 16);
+		}
+
+		// Only the letterbox rectangle is written when converting, so the bars only have to be drawn once.
+		let mut output = allocate_frame(output_size.0, output_size.1, output_format)
+			.map_err(|e| log::error!("Failed to allocate conversion frame: {e}"))?;
+		fill_black(&mut output, output_format)?;
+
+		let chroma_subsampling = if output_format == Pixel::YUV444P { 1 } else { 2 };
+
+		Ok(Self { context, pq_transform, output, input_height: input_size.1, rect, chroma_subsampling })
+	}
+
+	/// Convert `input` and return a reference to the converted frame.
+	pub fn convert(&mut self, input: &Frame) -> Result<&mut Frame, ()> {
+		let input = match &mut self.pq_transform {
+			Some(pq_transform) => pq_transform.apply(input),
+			None => input,
+		};
+
+		let result = unsafe {
+			// Point the output planes to the top left corner of the letterbox rectangle.
+			let output = &*self.output.as_ptr();
+			let bytes_per_sample = if output.format == ffmpeg::sys::AVPixelFormat::from(Pixel::YUV420P10LE) as i32 { 2 } else { 1 };
+			let mut data = output.data;
+			for (plane, pointer) in data.iter_mut().enumerate().take(3) {
+				if pointer.is_null() {
+					continue;
+				}
+
+				let subsampling = if plane == 0 { 1 } else { self.chroma_subsampling };
+				*pointer = pointer.add(
+					(self.rect.y / subsampling) as usize * output.linesize[plane] as usize
+					+ (self.rect.x / subsampling) as usize * bytes_per_sample
+				);
+			}
+
+			ffmpeg::sys::sws_scale(
+				self.context,
+				(*input.as_ptr()).data.as_ptr() as *const *const _,
+				(*input.as_ptr()).linesize.as_ptr() as *const _,
+				0,
+				self.input_height as i32,
+				data.as_ptr(),
+				output.linesize.as_ptr() as *mut _,
+			)
+		};
+		if result < 0 {
+			log::error!("Failed to convert frame: {}", ffmpeg::Error::from(result));
+			return Err(());
+		}
+
+		Ok(&mut self.output)
+	}
+}
+
+impl Drop for SoftwareConverter {
+	fn drop(&mut self) {
+		unsafe { ffmpeg::sys::sws_freeContext(self.context) };
+	}
+}
+
+/// Converts BGRZ frames (sRGB) to RGB48 frames in BT.2020 with the PQ transfer function.
+///
+/// Scaling is done by swscale afterwards, but swscale doesn't know how to change transfer functions or primaries.
+struct PqTransform {
+	/// Maps an sRGB value to linear light.
+	linear: [f32; 256],
+
+	/// Maps linear light (relative to SDR white) to PQ encoded values.
+	pq: Vec<u16>,
+
+	output: Frame,
+	width: u32,
+	height: u32,
+}
+
+impl PqTransform {
+	fn new(width: u32, height: u32) -> Result<Self, ()> {
+		let linear = std::array::from_fn(|value| srgb_to_linear(value as f32 / 255.0));
+		let pq = (0..PQ_LUT_SIZE)
+			.map(|index| {
+				let linear = index as f32 / (PQ_LUT_SIZE - 1) as f32;
+				(linear_to_pq(linear * SDR_WHITE_NITS / PQ_MAX_NITS) * u16::MAX as f32).round() as u16
+			})
+			.collect();
+
+		let output = allocate_frame(width, height, Pixel::RGB48LE)
+			.map_err(|e| log::error!("Failed to allocate HDR conversion frame: {e}"))?;
+
+		Ok(Self { linear, pq, output, width, height })
+	}
+
+	fn apply(&mut self, input: &Frame) -> &Frame {
+		unsafe {
+			let input_data = (*input.as_ptr()).data[0];
+			let input_linesize = (*input.as_ptr()).linesize[0] as usize;
+			let output_data = (*self.output.as_mut_ptr()).data[0];
+			let output_linesize = (*self.output.as_ptr()).linesize[0] as usize;
+
+			for y in 0..self.height as usize {
+				let input_row = std::slice::from_raw_parts(input_data.add(y * input_linesize), self.width as usize * 4);
+				let output_row = std::slice::from_raw_parts_mut(output_data.add(y * output_linesize) as *mut u16, self.width as usize * 3);
+
+				for (pixel, output) in input_row.chunks_exact(4).zip(output_row.chunks_exact_mut(3)) {
+					let rgb = [self.linear[pixel[2] as usize], self.linear[pixel[1] as usize], self.linear[pixel[0] as usize]];
+					for (output, row) in output.iter_mut().zip(BT709_TO_BT2020) {
+						let linear = row[0] * rgb[0] + row[1] * rgb[1] + row[2] * rgb[2];
+						let index = (linear.clamp(0.0, 1.0) * (PQ_LUT_SIZE - 1) as f32).round() as usize;
+						*output = self.pq[index].to_le();
+					}
+				}
+			}
+		}
+
+		&self.output
+	}
+}
+
+/// The conversion kernels compiled to PTX, compiled once because compiling takes a while.
+static CUDA_PTX: OnceLock<Result<Ptx, String>> = OnceLock::new();
+
+/// Load the conversion kernels on `device`, unless an earlier converter loaded them already.
+fn load_kernels(device: &Arc<CudaDevice>) -> Result<(), ()> {
+	if device.has_func(CUDA_MODULE_NAME, CUDA_SCALE_BGRA) {
+		return Ok(());
+	}
+
+	let ptx = CUDA_PTX.get_or_init(|| cudarc::nvrtc::compile_ptx(CUDA_SOURCE).map_err(|e| format!("{e:?}")))
+		.as_ref()
+		.map_err(|e| log::error!("Failed to compile CUDA conversion kernels: {e}"))?;
+	device.load_ptx(ptx.clone(), CUDA_MODULE_NAME, &[CUDA_SCALE_BGRA, CUDA_BGRA_TO_P010, CUDA_BGRA_TO_YUV444])
+		.map_err(|e| log::error!("Failed to load CUDA conversion kernels: {e}"))
+}
+
+/// Converts frames in CUDA memory to the pixel format and resolution of a hardware encoder.
+///
+/// BGRA frames are either scaled, or scaled and converted to P010 frames for HDR streaming or YUV444P frames for 4:4:4 streaming.
+pub struct CudaConverter {
+	device: Arc<CudaDevice>,
+	function: CudaFunction,
+	output: Frame,
+	output_size: (u32, u32),
+	geometry: Geometry,
+	output_format: Pixel,
+}
+
+impl CudaConverter {
+	/// Create a converter that writes in to `output`, which should be a frame in CUDA memory.
+	///
+	/// The output frame should be in `output_format`, which is either `Pixel::P010LE`, `Pixel::YUV444P` or `Pixel::ZRGB32`.
+	pub fn new(
+		device: &Arc<CudaDevice>,
+		output: Frame,
+		input_size: (u32, u32),
+		output_size: (u32, u32),
+		output_format: Pixel,
+	) -> Result<Self, ()> {
+		load_kernels(device)?;
+
+		let function_name = match output_format {
+			Pixel::P010LE => CUDA_BGRA_TO_P010,
+			Pixel::YUV444P => CUDA_BGRA_TO_YUV444,
+			_ => CUDA_SCALE_BGRA,
+		};
+		let function = device.get_func(CUDA_MODULE_NAME, function_name)
+			.ok_or_else(|| log::error!("Failed to find CUDA conversion kernel '{function_name}'."))?;
+
+		let rect = Rect::letterbox(input_size, output_size);
+		let geometry = Geometry {
+			src_width: input_size.0 as i32,
+			src_height: input_size.1 as i32,
+			rect_x: rect.x as i32,
+			rect_y: rect.y as i32,
+			rect_width: rect.width as i32,
+			rect_height: rect.height as i32,
+		};
+
+		Ok(Self { device: device.clone(), function, output, output_size, geometry, output_format })
+	}
+
+	/// Convert `input` and return a reference to the converted frame.
+	pub fn convert(&mut self, input: &Frame) -> Result<&mut Frame, ()> {
+		const BLOCK_SIZE: u32 = 16;
+		let (width, height) = self.output_size;
+
+		let result = unsafe {
+			let input = &*input.as_ptr();
+			let output = &*self.output.as_ptr();
+			if self.output_format == Pixel::P010LE {
+				// Every thread handles a 2x2 block of pixels.
+				let config = LaunchConfig {
+					grid_dim: (width.div_ceil(BLOCK_SIZE * 2), height.div_ceil(BLOCK_SIZE * 2), 1),
+					block_dim: (BLOCK_SIZE, BLOCK_SIZE, 1),
+					shared_mem_bytes: 0,
+				};
+				self.function.clone().launch(config, (
+					input.data[0] as u64,
+					input.linesize[0],
+					output.data[0] as u64,
+					output.linesize[0],
+					output.data[1] as u64,
+					output.linesize[1],
+					width as i32,
+					height as i32,
+					self.geometry,
+					SDR_WHITE_NITS / PQ_MAX_NITS,
+				))
+			} else if self.output_format == Pixel::YUV444P {
+				let config = LaunchConfig {
+					grid_dim: (width.div_ceil(BLOCK_SIZE), height.div_ceil(BLOCK_SIZE), 1),
+					block_dim: (BLOCK_SIZE, BLOCK_SIZE, 1),
+					shared_mem_bytes: 0,
+				};
+				self.function.clone().launch(config, (
+					input.data[0] as u64,
+					input.linesize[0],
+					output.data[0] as u64,
+					output.linesize[0],
+					output.data[1] as u64,
+					output.linesize[1],
+					output.data[2] as u64,
+					output.linesize[2],
+					width as i32,
+					height as i32,
+					self.geometry,
+				))
+			} else {
+				let config = LaunchConfig {
+					grid_dim: (width.div_ceil(BLOCK_SIZE), height.div_ceil(BLOCK_SIZE), 1),
+					block_dim: (BLOCK_SIZE, BLOCK_SIZE, 1),
+					shared_mem_bytes: 0,
+				};
+				self.function.clone().launch(config, (
+					input.data[0] as u64,
+					input.linesize[0],
+					output.data[0] as u64,
+					output.linesize[0],
+					width as i32,
+					height as i32,
+					self.geometry,
+				))
+			}
+		};
+		result.map_err(|e| log::error!("Failed to launch CUDA conversion kernel: {e}"))?;
+
+		self.device.synchronize()
+			.map_err(|e| log::error!("Failed to synchronize CUDA conversion kernel: {e}"))?;
+
+		Ok(&mut self.output)
+	}
+}
+
+/// Allocate a frame in system memory.
+fn allocate_frame(width: u32, height: u32, format: Pixel) -> Result<Frame, ffmpeg::Error> {
+	unsafe {
+		let mut frame = Frame::empty();
+		(*frame.as_mut_ptr()).format = ffmpeg::sys::AVPixelFormat::from(format) as i32;
+		(*frame.as_mut_ptr()).width = width as i32;
+		(*frame.as_mut_ptr()).height = height as i32;
+		check_ret(ffmpeg::sys::av_frame_get_buffer(frame.as_mut_ptr(), 0))?;
+
+		Ok(frame)
+	}
+}
+
+/// Fill a YUV frame with black (in limited range).
+fn fill_black(frame: &mut Frame, format: Pixel) -> Result<(), ()> {
+	let (black, bytes_per_sample): ([u16; 3], usize) = match format {
+		Pixel::YUV420P => ([16, 128, 128], 1),
+		Pixel::YUV420P10LE => ([64, 512, 512], 2),
+		Pixel::YUV444P => ([16, 128, 128], 1),
+		format => {
+			log::error!("Don't know how to fill a frame of format {format:?} with black.");
+			return Err(());
+		},
+	};
+
+	unsafe {
+		let frame = &mut *frame.as_mut_ptr();
+		for (plane, value) in black.into_iter().enumerate() {
+			let height = if plane == 0 || format == Pixel::YUV444P { frame.height as usize } else { (frame.height as usize).div_ceil(2) };
+			let plane = std::slice::from_raw_parts_mut(frame.data[plane], frame.linesize[plane] as usize * height);
+			if bytes_per_sample == 1 {
+				plane.fill(value as u8);
+			} else {
+				for sample in plane.chunks_exact_mut(2) {
+					sample.copy_from_slice(&value.to_le_bytes());
+				}
+			}
+		}
+	}
+
+	Ok(())
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+	if value <= 0.04045 {
+		value / 12.92
+	} else {
+		((value + 0.055) / 1.055).powf(2.4)
+	}
+}
+
+/// The inverse of the PQ EOTF (SMPTE ST 2084), where `value` is relative to the peak brightness of 10000 nits.
+fn linear_to_pq(value: f32) -> f32 {
+	const M1: f32 = 2610.0 / 16384.0;
+	const M2: f32 = 2523.0 / 4096.0 * 128.0;
+	const C1: f32 = 3424.0 / 4096.0;
+	const C2: f32 = 2413.0 / 4096.0 * 32.0;
+	const C3: f32 = 2392.0 / 4096.0 * 32.0;
+
+	let y = value.max(0.0).powf(M1);
+	((C1 + C2 * y) / (1.0 + C3 * y)).powf(M2)
+}
@@ -0,0 +1,137 @@
+use reed_solomon_erasure::{galois_8, ReedSolomon};
+
+use super::packetizer::{NvVideoPacket, VideoFrameHeader, MAX_BLOCKS, PAYLOAD_OFFSET};
+
+/// A FEC block of a frame that is being reassembled.
+struct Block {
+	nr_data_shards: usize,
+	nr_parity_shards: usize,
+	shards: Vec<Option<Vec<u8>>>,
+
+	/// Payload of the data shards, once enough shards were received to (re)construct them.
+	payload: Option<Vec<u8>>,
+}
+
+impl Block {
+	fn new(nr_data_shards: usize, nr_parity_shards: usize) -> Self {
+		Self {
+			nr_data_shards,
+			nr_parity_shards,
+			shards: vec![None; nr_data_shards + nr_parity_shards],
+			payload: None,
+		}
+	}
+
+	fn insert(&mut self, shard_index: usize, shard: &[u8]) -> Result<(), ()> {
+		if self.payload.is_some() {
+			// We already have everything we need from this block.
+			return Ok(());
+		}
+
+		let slot = self.shards.get_mut(shard_index)
+			.ok_or_else(|| log::error!("Received shard {shard_index}, but block only has {} shards.", self.nr_data_shards + self.nr_parity_shards))?;
+		*slot = Some(shard.to_vec());
+
+		let nr_received_shards = self.shards.iter().filter(|shard| shard.is_some()).count();
+		if nr_received_shards < self.nr_data_shards {
+			return Ok(());
+		}
+
+		// Recover missing data shards from the parity shards.
+		if self.shards[..self.nr_data_shards].iter().any(Option::is_none) {
+			let decoder = ReedSolomon::<galois_8::Field>::new(self.nr_data_shards, self.nr_parity_shards)
+				.map_err(|e| log::error!("Failed to create FEC decoder: {e}"))?;
+			decoder.reconstruct_data(&mut self.shards)
+				.map_err(|e| log::error!("Failed to reconstruct data shards: {e}"))?;
+		}
+
+		// The headers of reconstructed shards are garbage, because the headers of the parity shards
+		// are overwritten after encoding, but the payload is intact.
+		let mut payload = Vec::new();
+		for shard in self.shards[..self.nr_data_shards].iter().flatten() {
+			payload.extend(&shard[PAYLOAD_OFFSET..]);
+		}
+		self.payload = Some(payload);
+		self.shards.clear();
+
+		Ok(())
+	}
+}
+
+/// Reassembles frames from the shards created by the `Packetizer`, like Moonlight does.
+///
+/// Dropped shards are recovered using the parity shards in their block.
+#[derive(Default)]
+pub struct Depacketizer {
+	frame_index: Option<u32>,
+	completed: bool,
+	last_block_index: usize,
+	blocks: [Option<Block>; MAX_BLOCKS],
+}
+
+impl Depacketizer {
+	/// Process a received shard, returns the frame when it is complete.
+	///
+	/// The returned frame includes the zero padding of the last data shard, since its length isn't sent.
+	pub fn push(&mut self, shard: &[u8]) -> Result<Option<Vec<u8>>, ()> {
+		let header = NvVideoPacket::from_shard(shard)
+			.ok_or_else(|| log::error!("Received shard of {} bytes, which is too small to contain a video packet header.", shard.len()))?;
+
+		match self.frame_index {
+			Some(frame_index) if frame_index == header.frame_index => {
+				if self.completed {
+					log::trace!("Ignoring shard of completed frame {frame_index}.");
+					return Ok(None);
+				}
+			},
+			Some(frame_index) if (header.frame_index.wrapping_sub(frame_index) as i32) < 0 => {
+				log::trace!("Ignoring shard of old frame {}.", header.frame_index);
+				return Ok(None);
+			},
+			frame_index => {
+				if let Some(frame_index) = frame_index.filter(|_| !self.completed) {
+					log::debug!("Dropping incomplete frame {frame_index}.");
+				}
+				self.reset(header.frame_index);
+			},
+		}
+
+		let block_index = ((header.multi_fec_blocks >> 4) & 0x3) as usize;
+		let shard_index = ((header.fec_info >> 12) & 0x3FF) as usize;
+		let nr_data_shards = ((header.fec_info >> 22) & 0x3FF) as usize;
+		let fec_percentage = ((header.fec_info >> 4) & 0xFF) as usize;
+
+		// The number of parity shards isn't sent, so compute it the same way Moonlight does.
+		let nr_parity_shards = (nr_data_shards * fec_percentage).div_ceil(100);
+
+		self.last_block_index = (header.multi_fec_blocks >> 6) as usize;
+		self.blocks[block_index]
+			.get_or_insert_with(|| Block::new(nr_data_shards, nr_parity_shards))
+			.insert(shard_index, shard)?;
+
+		let complete = self.blocks[..=self.last_block_index]
+			.iter()
+			.all(|block| block.as_ref().is_some_and(|block| block.payload.is_some()));
+		if !complete {
+			return Ok(None);
+		}
+
+		let mut frame = Vec::new();
+		for block in self.blocks[..=self.last_block_index].iter_mut().flatten() {
+			frame.extend(block.payload.take().unwrap_or_default());
+		}
+		self.completed = true;
+
+		// Strip the VideoFrameHeader that prefixes every frame.
+		frame.drain(..VideoFrameHeader::SIZE.min(frame.len()));
+
+		Ok(Some(frame))
+	}
+
+	fn reset(&mut self, frame_index: u32) {
+		self.frame_index = Some(frame_index);
+		self.completed = false;
+		self.last_block_index = 0;
+		self.blocks = Default::default();
+	}
+}
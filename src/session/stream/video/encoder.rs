@@ -9,93 +9,101 @@ use ffmpeg::{
 	Frame,
 	Packet,
 };
-use reed_solomon_erasure::{galois_8, ReedSolomon};
 
-use crate::{ffmpeg::{hwdevice::CudaDeviceContextBuilder, hwframe::{HwFrameContext, HwFrameContextBuilder}}, session::stream::RtpHeader};
+use crate::{
+	ffmpeg::{check_ret, hwdevice::CudaDeviceContextBuilder, hwframe::{HwFrameContext, HwFrameContextBuilder}},
+	session::{recorder::{EncodedPacket, Recorder, StreamParameters}, stream::MediaClock},
+};
 
-/// Maximum allowed number of shards in the encoder (data + parity).
-pub const MAX_SHARDS: usize = 255;
+use super::{
+	av1::SequenceHeaderInjector,
+	capture::CapturedFrame,
+	convert::{CudaConverter, SoftwareConverter},
+	encryption::VideoCipher,
+	packetizer::Packetizer,
+	rate_control::StreamSettings,
+	send_queue::PacketizedFrame,
+};
 
-#[repr(u8)]
-enum RtpFlag {
-	ContainsPicData = 0x1,
-	EndOfFrame = 0x2,
-	StartOfFrame = 0x4,
-}
+/// Minimum time between key frames requested because the recorder dropped packets.
+const RECORDER_KEYFRAME_RETRY: std::time::Duration = std::time::Duration::from_secs(1);
 
-#[derive(Debug)]
-#[repr(C)]
-struct VideoFrameHeader {
-	header_type: u8,
-	padding1: u16,
-	frame_type: u8,
-	padding2: u32,
-}
+/// Requests from the client to recover from lost frames.
+#[derive(Clone, Debug)]
+pub enum RecoveryRequest {
+	/// Encode the next frame as an IDR frame.
+	IdrFrame,
 
-impl VideoFrameHeader {
-	fn serialize(&self, buffer: &mut Vec<u8>) {
-		buffer.extend(self.header_type.to_le_bytes());
-		buffer.extend(self.padding1.to_le_bytes());
-		buffer.extend(self.frame_type.to_le_bytes());
-		buffer.extend(self.padding2.to_le_bytes());
-	}
+	/// The client lost the frames from `first_frame` up to and including `last_frame`.
+	InvalidateReferenceFrames { first_frame: i64, last_frame: i64 },
 }
 
-#[derive(Debug)]
-#[repr(C)]
-struct NvVideoPacket {
-	stream_packet_index: u32,
-	frame_index: u32,
-	flags: u8,
-	reserved: u8,
-	multi_fec_flags: u8,
-	multi_fec_blocks: u8,
-	fec_info: u32,
+/// Position in the stream, which continues when the encoder is recreated so that the client sees a single stream.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamPosition {
+	/// Number of the last frame that was encoded.
+	pub frame_number: u32,
+
+	/// Sequence number of the next packet.
+	pub sequence_number: u32,
+
+	/// Timestamp of the last frame that was encoded, timestamps never go back when the encoder is recreated.
+	pub frame_time: std::time::Instant,
 }
 
-impl NvVideoPacket {
-	fn serialize(&self, buffer: &mut Vec<u8>) {
-		buffer.extend(self.stream_packet_index.to_le_bytes());
-		buffer.extend(self.frame_index.to_le_bytes());
-		buffer.extend(self.flags.to_le_bytes());
-		buffer.extend(self.reserved.to_le_bytes());
-		buffer.extend(self.multi_fec_flags.to_le_bytes());
-		buffer.extend(self.multi_fec_blocks.to_le_bytes());
-		buffer.extend(self.fec_info.to_le_bytes());
+impl StreamPosition {
+	pub fn new() -> Self {
+		Self { frame_number: 0, sequence_number: 0, frame_time: std::time::Instant::now() }
 	}
 }
 
 pub struct Encoder {
 	encoder: ffmpeg::encoder::Video,
-	pub hw_frame_context: HwFrameContext,
+	backend: EncoderBackend,
+	/// Resolution of the captured frames, which may differ from the resolution of the encoded frames.
+	capture_size: (u32, u32),
+	framerate: u32,
+
+	/// Only set when encoding AV1, to make sure keyframes can be decoded on their own.
+	sequence_header_injector: Option<SequenceHeaderInjector>,
+}
+
+/// Describes where the frames for the encoder live and how they are prepared.
+enum EncoderBackend {
+	/// Frames are in CUDA memory and passed to a hardware encoder, optionally converted on the GPU first.
+	Cuda {
+		/// Context from which the captured frames are allocated.
+		frame_context: HwFrameContext,
+		converter: Option<CudaConverter>,
+	},
+
+	/// Frames are in system memory and converted before they are passed to a software encoder.
+	Software(SoftwareConverter),
 }
 
 impl Encoder {
+	/// Create an encoder for frames of `width` by `height`.
+	///
+	/// Captured frames of `capture_size` are scaled to fit this resolution, with black bars if the aspect ratio differs.
+	/// With `yuv444` the chroma isn't subsampled, which is ignored for HDR.
+	#[allow(clippy::too_many_arguments)]
 	pub fn new(
-		cuda_device: &CudaDevice,
+		cuda_device: Option<&Arc<CudaDevice>>,
 		codec_name: &str,
+		capture_size: (u32, u32),
 		width: u32,
 		height: u32,
 		framerate: u32,
 		bitrate: usize,
+		hdr: bool,
+		yuv444: bool,
+		slices_per_frame: u32,
+		intra_refresh: bool,
 	) -> Result<Self, ()> {
-		let cuda_device_context = CudaDeviceContextBuilder::new()
-			.map_err(|e| log::error!("Failed to create CUDA device context: {e}"))?
-			.set_cuda_context((*cuda_device.cu_primary_ctx()) as *mut _)
-			.build()
-			.map_err(|e| log::error!("Failed to build CUDA device context: {e}"))?
-		;
-
-		let mut hw_frame_context = HwFrameContextBuilder::new(cuda_device_context)
-			.map_err(|e| log::error!("Failed to create CUDA frame context: {e}"))?
-			.set_width(width)
-			.set_height(height)
-			.set_sw_format(Pixel::ZRGB32)
-			.set_format(Pixel::CUDA)
-			.build()
-			.map_err(|e| log::error!("Failed to build CUDA frame context: {e}"))?
-		;
-
+		let yuv444 = yuv444 && !hdr;
+		if yuv444 {
+			log::info!("Encoding without chroma subsampling (YUV 4:4:4).");
+		}
 		log::info!("Using codec with name '{codec_name}'.");
 		let codec = ffmpeg::encoder::find_by_name(codec_name)
 			.ok_or_else(|| log::error!("Failed to find codec by name '{codec_name}'."))?;
@@ -113,113 +121,359 @@ impl Encoder {
 		encoder.set_bit_rate(bitrate);
 		encoder.set_gop(i32::max_value() as u32);
 		unsafe {
-			(*encoder.as_mut_ptr()).pix_fmt = Pixel::CUDA.into();
-			(*encoder.as_mut_ptr()).hw_frames_ctx = hw_frame_context.as_raw_mut();
 			(*encoder.as_mut_ptr()).delay = 0;
 			(*encoder.as_mut_ptr()).refs = 1;
+			(*encoder.as_mut_ptr()).slices = slices_per_frame as i32;
+		}
+
+		let backend = match cuda_device {
+			Some(cuda_device) => {
+				// Captured frames are always BGRA, for HDR and 4:4:4 they are converted to YUV on the GPU.
+				let sw_format = if hdr {
+					Pixel::P010LE
+				} else if yuv444 {
+					Pixel::YUV444P
+				} else {
+					Pixel::ZRGB32
+				};
+				let mut hw_frame_context = create_hw_frame_context(cuda_device, width, height, sw_format)?;
+
+				unsafe {
+					(*encoder.as_mut_ptr()).pix_fmt = Pixel::CUDA.into();
+					(*encoder.as_mut_ptr()).hw_frames_ctx = hw_frame_context.as_raw_mut();
+				}
+				encoder.set_str("preset", "fast")
+					.map_err(|e| log::error!("Failed to set preset for encoder: {e}"))?;
+				encoder.set_str("tune", "ull")
+					.map_err(|e| log::error!("Failed to set tuning option for encoder: {e}"))?;
+				encoder.set_str("forced-idr", "1")
+					.map_err(|e| log::error!("Failed to set forced-idr for encoder: {e}"))?;
+
+				if hdr || yuv444 {
+					set_color_properties(&mut encoder, hdr);
+				}
+
+				if hdr || yuv444 || capture_size != (width, height) {
+					let output = create_cuda_frame(&mut hw_frame_context, width, height)?;
+					let converter = CudaConverter::new(cuda_device, output, capture_size, (width, height), sw_format)?;
+					let capture_frame_context = create_hw_frame_context(cuda_device, capture_size.0, capture_size.1, Pixel::ZRGB32)?;
+					EncoderBackend::Cuda { frame_context: capture_frame_context, converter: Some(converter) }
+				} else {
+					EncoderBackend::Cuda { frame_context: hw_frame_context, converter: None }
+				}
+			},
+			None => {
+				let pixel_format = if hdr {
+					Pixel::YUV420P10LE
+				} else if yuv444 {
+					Pixel::YUV444P
+				} else {
+					Pixel::YUV420P
+				};
+				encoder.set_format(pixel_format);
+				set_color_properties(&mut encoder, hdr);
+				unsafe {
+					// Keep the bitrate constant, with a buffer of a single frame to keep latency low.
+					(*encoder.as_mut_ptr()).rc_max_rate = bitrate as i64;
+					(*encoder.as_mut_ptr()).rc_buffer_size = (bitrate / framerate as usize) as i32;
+				}
+				set_software_options(&mut encoder, codec_name)?;
+
+				EncoderBackend::Software(SoftwareConverter::new(capture_size, (width, height), Pixel::BGRZ, pixel_format, hdr)?)
+			},
+		};
+
+		// Intra refresh uses the GOP length as the period in which the whole frame is refreshed.
+		let intra_refresh = intra_refresh && enable_intra_refresh(&mut encoder, codec_name, framerate);
+		if intra_refresh {
+			encoder.set_gop(framerate);
 		}
-		encoder.set_str("preset", "fast")
-			.map_err(|e| log::error!("Failed to set preset for encoder: {e}"))?;
-		encoder.set_str("tune", "ull")
-			.map_err(|e| log::error!("Failed to set tuning option for encoder: {e}"))?;
-		encoder.set_str("forced-idr", "1")
-			.map_err(|e| log::error!("Failed to set forced-idr for encoder: {e}"))?;
 
 		let encoder = encoder.open()
 			.map_err(|e| log::error!("Failed to start encoder: {e}"))?;
 
+		let sequence_header_injector = if codec.id() == ffmpeg::codec::Id::AV1 {
+			let extradata = unsafe {
+				let context = encoder.as_ptr();
+				if (*context).extradata.is_null() {
+					&[][..]
+				} else {
+					std::slice::from_raw_parts((*context).extradata, (*context).extradata_size as usize)
+				}
+			};
+			Some(SequenceHeaderInjector::from_extradata(extradata))
+		} else {
+			None
+		};
+
 		Ok(Self {
 			encoder,
-			hw_frame_context,
+			backend,
+			capture_size,
+			framerate,
+			sequence_header_injector,
 		})
 	}
 
+	/// Create a frame (of the capture resolution) that can be captured in to and passed to this encoder.
+	pub fn create_frame(&mut self) -> Result<Frame, ()> {
+		unsafe {
+			match &mut self.backend {
+				EncoderBackend::Cuda { frame_context, .. } => {
+					let mut frame = create_cuda_frame(frame_context, self.capture_size.0, self.capture_size.1)?;
+					(*frame.as_mut_ptr()).linesize[0] = (*frame.as_ptr()).width * 4;
+					Ok(frame)
+				},
+				EncoderBackend::Software(_) => {
+					let mut frame = Frame::empty();
+					(*frame.as_mut_ptr()).width = self.capture_size.0 as i32;
+					(*frame.as_mut_ptr()).height = self.capture_size.1 as i32;
+					(*frame.as_mut_ptr()).format = ffmpeg::sys::AVPixelFormat::from(Pixel::BGRZ) as i32;
+
+					check_ret(ffmpeg::sys::av_frame_get_buffer(frame.as_mut_ptr(), 0))
+						.map_err(|e| log::error!("Failed to allocate frame: {e}"))?;
+
+					Ok(frame)
+				},
+			}
+		}
+	}
+
+	/// Change the target bitrate, encoders that support it reconfigure themselves on the next frame.
+	fn set_bitrate(&mut self, bitrate: usize) {
+		unsafe {
+			let context = self.encoder.as_mut_ptr();
+			(*context).bit_rate = bitrate as i64;
+			if let EncoderBackend::Software(_) = self.backend {
+				(*context).rc_max_rate = bitrate as i64;
+				(*context).rc_buffer_size = (bitrate / self.framerate as usize) as i32;
+			}
+		}
+	}
+
+	/// Parameters of the encoded stream, needed to record it.
+	fn stream_parameters(&self) -> StreamParameters {
+		unsafe {
+			let context = self.encoder.as_ptr();
+			let extradata = if (*context).extradata.is_null() {
+				Vec::new()
+			} else {
+				std::slice::from_raw_parts((*context).extradata, (*context).extradata_size as usize).to_vec()
+			};
+
+			StreamParameters::Video {
+				codec_id: self.encoder.id(),
+				width: (*context).width as u32,
+				height: (*context).height as u32,
+				extradata,
+			}
+		}
+	}
+
+	/// Encode frames until stopped, starting at the given position in the stream.
+	///
+	/// Returns the position in the stream after the last encoded frame.
 	#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
 	pub fn run(
 		mut self,
-		packet_tx: tokio::sync::mpsc::Sender<Vec<u8>>,
-		mut idr_frame_request_rx: tokio::sync::broadcast::Receiver<()>,
+		position: StreamPosition,
+		clock: MediaClock,
+		packet_tx: tokio::sync::mpsc::Sender<PacketizedFrame>,
+		mut recovery_request_rx: tokio::sync::broadcast::Receiver<RecoveryRequest>,
+		mut settings_rx: tokio::sync::watch::Receiver<StreamSettings>,
 		packet_size: usize,
 		minimum_fec_packets: u32,
-		fec_percentage: u8,
+		cipher: Option<VideoCipher>,
+		recorder: Option<Recorder>,
 		mut encoder_buffer: Frame,
-		intermediate_buffer: Arc<Mutex<Frame>>,
-		notifier: Arc<std::sync::Condvar>,
+		intermediate_buffer: Arc<Mutex<CapturedFrame>>,
 		stop_signal: ShutdownManager<()>,
-	) -> Result<(), ()> {
+	) -> Result<StreamPosition, ()> {
 		let mut packet = Packet::empty();
 
-		let mut frame_number = 0u32;
-		let mut sequence_number = 0u32;
-		let stream_start_time = std::time::Instant::now();
-		while !stop_signal.is_shutdown_triggered() {
-			// Swap the intermediate buffer with the output buffer.
+		let mut packetizer = Packetizer::new(packet_size, minimum_fec_packets, position.sequence_number);
+		let mut frame_number = position.frame_number;
+		// The first frame of a new encoder is always an IDR frame.
+		let mut last_idr_frame = frame_number as i64 + 1;
+		let mut fec_percentage = settings_rx.borrow_and_update().fec_percentage;
+		let mut frame_time = position.frame_time;
+		// Frames are encoded at a fixed rate, regardless of how often new frames are captured.
+		let frame_interval = std::time::Duration::from_secs(1) / self.framerate.max(1);
+		let mut next_frame_time = std::time::Instant::now();
+		let mut has_frame = false;
+
+		// The recorder may need regular key frames, to be able to start a recording at any time.
+		let keyframe_interval = recorder.as_ref().and_then(Recorder::keyframe_interval);
+		let mut last_keyframe_time = std::time::Instant::now();
+		if let Some(recorder) = &recorder {
+			recorder.set_video_parameters(self.stream_parameters());
+		}
+
+		'encode: while !stop_signal.is_shutdown_triggered() {
+			// Wait until it is time to encode the next frame.
+			let now = std::time::Instant::now();
+			if next_frame_time > now {
+				std::thread::sleep(next_frame_time - now);
+				next_frame_time += frame_interval;
+			} else {
+				// We are lagging behind, don't try to catch up.
+				next_frame_time = now + frame_interval;
+			}
+
+			// Swap the intermediate buffer with the output buffer if there is a new frame,
+			// otherwise the previous frame is encoded again.
 			// Note that the lock is only held while swapping buffers, to minimize wait time for others locking the buffer.
 			{
-				log::trace!("Waiting for new frame.");
-				// Wait for a new frame.
-				let lock = intermediate_buffer.lock()
+				let mut lock = intermediate_buffer.lock()
 					.map_err(|e| log::error!("Failed to acquire buffer lock: {e}"))?;
-				let mut result = notifier.wait_timeout(lock, std::time::Duration::from_millis(500))
-					.map_err(|e| log::error!("Failed to wait for new frame: {e}"))?;
-
-				// Didn't get a lock, let's check shutdown status and try again.
-				if result.1.timed_out() {
-					continue;
+				if lock.is_new {
+					std::mem::swap(&mut lock.frame, &mut encoder_buffer);
+					lock.is_new = false;
+					has_frame = true;
+					frame_time = lock.captured_at.max(frame_time);
+					log::trace!("Swapped new frame with old frame.");
+				} else {
+					// A repeated frame is shown at the time it is repeated, not at the time it was captured.
+					frame_time = std::time::Instant::now();
+					log::trace!("No new frame captured, repeating previous frame.");
 				}
+			}
 
-				log::trace!("Received notification of new frame.");
-
-				std::mem::swap(&mut *result.0, &mut encoder_buffer);
+			// Nothing was captured yet, so there is nothing to repeat either.
+			if !has_frame {
+				continue;
 			}
-			log::trace!("Swapped new frame with old frame.");
 			frame_number += 1;
-			encoder_buffer.set_pts(Some(frame_number as i64));
-
-			log::trace!("Sending frame {} to encoder", frame_number);
 
-			// TODO: Check if this is necessary?
-			// Reset possible previous request for keyframe.
-			unsafe {
-				(*encoder_buffer.as_mut_ptr()).pict_type = ffmpeg::picture::Type::None.into();
-				(*encoder_buffer.as_mut_ptr()).key_frame = 0;
+			// Apply changes to the bitrate and FEC percentage from the rate controller.
+			if settings_rx.has_changed().unwrap_or(false) {
+				let settings = *settings_rx.borrow_and_update();
+				log::debug!("Changing bitrate to {} kbps with {}% FEC.", settings.bitrate / 1024, settings.fec_percentage);
+				self.set_bitrate(settings.bitrate);
+				fec_percentage = settings.fec_percentage;
 			}
 
-			// Check if there was an IDR frame request.
-			match idr_frame_request_rx.try_recv() {
-				Ok(_) => {
-					log::debug!("Received request for IDR frame.");
-					unsafe {
-						(*encoder_buffer.as_mut_ptr()).pict_type = ffmpeg::picture::Type::I.into();
-						(*encoder_buffer.as_mut_ptr()).key_frame = 1;
+			// Check if the client asked us to recover from lost frames.
+			let mut force_idr = false;
+			loop {
+				match recovery_request_rx.try_recv() {
+					Ok(RecoveryRequest::IdrFrame) => {
+						log::debug!("Received request for IDR frame.");
+						force_idr = true;
+					},
+					Ok(RecoveryRequest::InvalidateReferenceFrames { first_frame, last_frame }) => {
+						// FFmpeg doesn't expose reference frame invalidation (like `nvEncInvalidateRefFrames` for NVENC),
+						// so the only way to recover is an IDR frame.
+						if lost_frames_need_idr(last_idr_frame, last_frame) {
+							log::debug!("Client lost frames {first_frame} to {last_frame}, sending an IDR frame.");
+							force_idr = true;
+						} else {
+							log::debug!("Ignoring loss of frames {first_frame} to {last_frame}, IDR frame {last_idr_frame} was already sent after them.");
+						}
+					},
+					Err(tokio::sync::broadcast::error::TryRecvError::Empty) => break,
+					Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {
+						log::debug!("Missed recovery requests, sending an IDR frame to be safe.");
+						force_idr = true;
+					},
+					Err(_) => {
+						log::debug!("Channel closed, quitting encoder task.");
+						break 'encode;
 					}
-				},
-				Err(tokio::sync::broadcast::error::TryRecvError::Empty) => {},
-				Err(tokio::sync::broadcast::error::TryRecvError::Lagged(_)) => {},
-				Err(_) => {
-					log::debug!("Channel closed, quitting encoder task.");
-					return Ok(());
+				}
+			}
+			if keyframe_interval.is_some_and(|interval| last_keyframe_time.elapsed() >= interval) {
+				log::trace!("Requesting IDR frame for the recorder.");
+				force_idr = true;
+			}
+			// The recorder skips video until the next key frame after dropping a packet, but don't send a key frame
+			// for every frame while it can't keep up.
+			if recorder.as_ref().is_some_and(Recorder::needs_key_frame) && last_keyframe_time.elapsed() >= RECORDER_KEYFRAME_RETRY {
+				log::debug!("Requesting IDR frame for the recorder after it dropped packets.");
+				force_idr = true;
+			}
+			if force_idr {
+				last_idr_frame = frame_number as i64;
+			}
+
+			// Convert the frame to the pixel format of the encoder, if necessary.
+			let frame = match &mut self.backend {
+				EncoderBackend::Cuda { converter: None, .. } => &mut encoder_buffer,
+				EncoderBackend::Cuda { converter: Some(converter), .. } => converter.convert(&encoder_buffer)?,
+				EncoderBackend::Software(converter) => converter.convert(&encoder_buffer)?,
+			};
+			frame.set_pts(Some(frame_number as i64));
+
+			log::trace!("Sending frame {} to encoder", frame_number);
+
+			// Request a keyframe if necessary, otherwise reset a possible previous request.
+			unsafe {
+				if force_idr {
+					(*frame.as_mut_ptr()).pict_type = ffmpeg::picture::Type::I.into();
+					(*frame.as_mut_ptr()).key_frame = 1;
+				} else {
+					(*frame.as_mut_ptr()).pict_type = ffmpeg::picture::Type::None.into();
+					(*frame.as_mut_ptr()).key_frame = 0;
 				}
 			}
 
 			// Send the frame to the encoder.
-			self.encoder.send_frame(&encoder_buffer)
+			self.encoder.send_frame(frame)
 				.map_err(|e| log::error!("Error sending frame for encoding: {e}"))?;
 
 			loop {
 				match self.encoder.receive_packet(&mut packet) {
 					Ok(()) => {
 						log::trace!("Received frame {} from encoder, converting frame to packets.", packet.pts().unwrap_or(-1));
-						encode_packet(
-							&packet,
-							&packet_tx,
-							packet_size,
-							minimum_fec_packets,
-							fec_percentage,
+						let key_frame = packet.flags().contains(Flags::KEY);
+						let packet_data = packet.data()
+							.ok_or_else(|| log::error!("Packet is empty, but we expected it to be full."))?;
+						let packet_data = match &mut self.sequence_header_injector {
+							Some(injector) => std::borrow::Cow::Owned(injector.process(packet_data, key_frame)),
+							None => std::borrow::Cow::Borrowed(packet_data),
+						};
+
+						if key_frame {
+							last_keyframe_time = std::time::Instant::now();
+						}
+						if let Some(recorder) = &recorder {
+							recorder.record_video(EncodedPacket {
+								data: packet_data.to_vec(),
+								timestamp: frame_time,
+								key_frame,
+							});
+						}
+
+						let timestamp = clock.rtp_timestamp(frame_time);
+						let shards = packetizer.packetize(&packet_data, key_frame, frame_number, timestamp, fec_percentage)?;
+
+						let shards = match &cipher {
+							Some(cipher) => shards.iter()
+								.map(|shard| cipher.encrypt(shard, frame_number))
+								.collect::<Result<Vec<_>, ()>>()?,
+							None => shards,
+						};
+
+						// Never wait for the send queue, if it is full the network can't keep up and the frame is dropped.
+						let nr_shards = shards.len();
+						let frame = PacketizedFrame {
 							frame_number,
-							&mut sequence_number,
-							stream_start_time,
-						)?
+							key_frame,
+							shards,
+							captured_at: frame_time,
+							encoded_at: std::time::Instant::now(),
+							frame_interval,
+						};
+						match packet_tx.try_send(frame) {
+							Ok(()) => log::trace!("Queued frame {frame_number} with {nr_shards} shards."),
+							Err(tokio::sync::mpsc::error::TrySendError::Full(_)) => {
+								log::debug!("Send queue is full, dropping frame {frame_number}.");
+							},
+							Err(tokio::sync::mpsc::error::TrySendError::Closed(_)) => {
+								log::info!("Channel closed, couldn't send frame.");
+								break 'encode;
+							},
+						}
 					},
 					Err(e) => {
 						match e {
@@ -241,194 +495,148 @@ impl Encoder {
 			}
 		}
 
-		log::debug!("Received stop signal.");
-		Ok(())
+		log::debug!("Stopped encoding at frame {frame_number}.");
+		Ok(StreamPosition {
+			frame_number,
+			sequence_number: packetizer.sequence_number(),
+			frame_time,
+		})
 	}
 }
 
-#[allow(clippy::too_many_arguments)] // TODO: Problem for later..
-fn encode_packet(
-	packet: &Packet,
-	packet_tx: &tokio::sync::mpsc::Sender<Vec<u8>>,
-	requested_packet_size: usize,
-	minimum_fec_packets: u32,
-	fec_percentage: u8,
-	frame_number: u32,
-	sequence_number: &mut u32,
-	stream_start_time: std::time::Instant,
-) -> Result<(), ()> {
-	// Random padding, because we need it.
-	const PADDING: u32 = 0;
-
-	let timestamp = ((std::time::Instant::now() - stream_start_time).as_micros() / (1000 / 90)) as u32;
-
-	// TODO: Figure out what this header means?
-	let video_frame_header = VideoFrameHeader {
-		header_type: 0x01, // Always 0x01 for short headers. What is this exactly?
-		padding1: 0,
-		frame_type: if packet.flags().contains(Flags::KEY) { 2 } else { 1 },
-		padding2: 0,
-	};
-
-	// Prefix the frame with a VideoFrameHeader.
-	let mut buffer = Vec::with_capacity(std::mem::size_of::<VideoFrameHeader>());
-	video_frame_header.serialize(&mut buffer);
-	let packet_data = packet.data()
-		.ok_or_else(|| log::error!("Packet is empty, but we expected it to be full."))?;
-	let packet_data = [&buffer, packet_data].concat();
-
-	let requested_shard_payload_size = requested_packet_size - std::mem::size_of::<NvVideoPacket>();
-
-	// The total size of a shard.
-	let requested_shard_size =
-		std::mem::size_of::<RtpHeader>()
-		+ std::mem::size_of_val(&PADDING)
-		+ std::mem::size_of::<NvVideoPacket>()
-		+ requested_shard_payload_size;
-
-	// Determine how many data shards we will be sending.
-	let nr_data_shards = packet_data.len() / requested_shard_payload_size + (packet_data.len() % requested_shard_payload_size != 0) as usize; // TODO: Replace with div_ceil when it lands in stable (https://doc.rust-lang.org/std/primitive.i32.html#method.div_ceil).
-	assert!(nr_data_shards != 0);
-
-	// Determine how many parity and data shards are permitted per FEC block.
-	let nr_parity_shards_per_block = MAX_SHARDS * fec_percentage as usize / (100 + fec_percentage as usize);
-	let nr_data_shards_per_block = MAX_SHARDS - nr_parity_shards_per_block;
-
-	// We need to subtract number of data shards by 1, otherwise you can get a situation where
-	// there are for example 100 data shards allowed per block and also 100 data shards available.
-	// In this case, nr_blocks = 100 / 100 + 1 = 2, but we only need to send 1 block.
-	// Subtracting the value of nr_data_shards by 1 avoids this situation.
-	let nr_blocks = (nr_data_shards - 1) / nr_data_shards_per_block + 1;
-	let last_block_index = (nr_blocks.min(4) as u8 - 1) << 6; // TODO: Why the bit shift? To 'force' a limit of 4 blocks?
-
-	log::trace!("Sending a max of {nr_data_shards_per_block} data shards and {nr_parity_shards_per_block} parity shards per block.");
-	log::trace!("Sending {nr_blocks} blocks of video data.");
-
-	for block_index in 0..nr_blocks {
-		// Determine what data shards are in this block.
-		let start = block_index * nr_data_shards_per_block;
-		let mut end = ((block_index + 1) * nr_data_shards_per_block)
-			.min(nr_data_shards);
-
-		if block_index >= 4 {
-			log::info!("Trying to create {nr_blocks} blocks, but we are limited to 4 blocks so we are sending all remaining packets without FEC.");
-			end = nr_data_shards;
-		}
-
-		// Compute how many parity shards we will need (approximately) in this block.
-		let nr_data_shards = end - start;
-		assert!(nr_data_shards != 0);
-
-		let mut nr_parity_shards = (nr_data_shards * fec_percentage as usize / 100)
-			.max(minimum_fec_packets as usize) // Lower limit by the minimum number of parity shards.
-			.min(MAX_SHARDS.saturating_sub(nr_data_shards)); // But hard total upper limit in the number of shards.
-
-		// Create the FEC encoder for this amount of shards.
-		let encoder = if nr_parity_shards > 0 {
-			match ReedSolomon::<galois_8::Field>::new(nr_data_shards, nr_parity_shards) {
-				Ok(encoder) => Ok(encoder),
-				Err(e) => {
-					nr_parity_shards = 0;
-					Err(format!("Couldn't create error correction for block {block_index}: {e}"))
-				}
-			}
-		} else {
-			Err("Can't create an FEC encoder for 0 parity shards.".to_string())
-		};
-
-		// Recompute the actual FEC percentage in case of a rounding error or when there are 0 parity shards.
-		let fec_percentage = nr_parity_shards * 100 / nr_data_shards;
-
-		log::trace!("Sending block {block_index} with {nr_data_shards} data shards and {nr_parity_shards} parity shards.");
-
-		let mut shards = Vec::with_capacity(nr_data_shards + nr_parity_shards);
-		for (block_shard_index, data_shard_index) in (start..end).enumerate() {
-			// Determine which part of the payload is in this shard.
-			let start = data_shard_index * requested_shard_payload_size;
-			let end = ((data_shard_index + 1) * requested_shard_payload_size).min(packet_data.len());
-
-			let mut shard = Vec::with_capacity(requested_shard_size);
+/// Whether an IDR frame is needed to recover from the loss of frames up to and including `last_frame`.
+///
+/// Frames after the last IDR frame don't reference the frames before it, so losing those frames doesn't matter.
+fn lost_frames_need_idr(last_idr_frame: i64, last_frame: i64) -> bool {
+	last_idr_frame <= last_frame
+}
 
-			let rtp_header = RtpHeader {
-				header: 0x90, // What is this?
-				packet_type: 0,
-				sequence_number: *sequence_number as u16,
-				timestamp,
-				ssrc: 0,
-			};
-			rtp_header.serialize(&mut shard);
-			shard.extend(PADDING.to_le_bytes());
-
-			let mut video_packet_header = NvVideoPacket {
-				stream_packet_index: *sequence_number << 8,
-				frame_index: frame_number,
-				flags: RtpFlag::ContainsPicData as u8,
-				reserved: 0,
-				multi_fec_flags: 0x10,
-				multi_fec_blocks: ((block_index as u8) << 4) | last_block_index,
-				fec_info: (block_shard_index << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32,
-			};
-			if block_shard_index == 0 {
-				video_packet_header.flags |= RtpFlag::StartOfFrame as u8;
-			}
-			if block_shard_index == nr_data_shards - 1 {
-				video_packet_header.flags |= RtpFlag::EndOfFrame as u8;
-			}
-			video_packet_header.serialize(&mut shard);
+/// Create a context for allocating frames in CUDA memory with the given pixel format.
+fn create_hw_frame_context(cuda_device: &CudaDevice, width: u32, height: u32, sw_format: Pixel) -> Result<HwFrameContext, ()> {
+	let cuda_device_context = CudaDeviceContextBuilder::new()
+		.map_err(|e| log::error!("Failed to create CUDA device context: {e}"))?
+		.set_cuda_context((*cuda_device.cu_primary_ctx()) as *mut _)
+		.build()
+		.map_err(|e| log::error!("Failed to build CUDA device context: {e}"))?
+	;
+
+	HwFrameContextBuilder::new(cuda_device_context)
+		.map_err(|e| log::error!("Failed to create CUDA frame context: {e}"))?
+		.set_width(width)
+		.set_height(height)
+		.set_sw_format(sw_format)
+		.set_format(Pixel::CUDA)
+		.build()
+		.map_err(|e| log::error!("Failed to build CUDA frame context: {e}"))
+}
 
-			// Append the payload.
-			shard.extend(&packet_data[start..end]);
+/// Allocate a frame in CUDA memory from the given frame context.
+fn create_cuda_frame(hw_frame_context: &mut HwFrameContext, width: u32, height: u32) -> Result<Frame, ()> {
+	unsafe {
+		let mut frame = Frame::empty();
+		(*frame.as_mut_ptr()).width = width as i32;
+		(*frame.as_mut_ptr()).height = height as i32;
+		(*frame.as_mut_ptr()).format = ffmpeg::sys::AVPixelFormat::from(Pixel::CUDA) as i32;
+		(*frame.as_mut_ptr()).hw_frames_ctx = hw_frame_context.as_raw_mut();
 
-			// Pad with zeros at the end to make an equally sized shard.
-			if end - start < requested_shard_payload_size {
-				shard.extend(vec![0u8; requested_shard_payload_size - (end - start)]);
-			}
+		check_ret(ffmpeg::sys::av_hwframe_get_buffer(hw_frame_context.as_raw_mut(), frame.as_mut_ptr(), 0))
+			.map_err(|e| log::error!("Failed to create CUDA frame: {e}"))?;
 
-			shards.push(shard);
+		Ok(frame)
+	}
+}
 
-			*sequence_number += 1;
+/// Signal the colorspace of the stream, which is limited range BT.709 for SDR and BT.2020 with PQ for HDR.
+fn set_color_properties(encoder: &mut ffmpeg::encoder::video::Video, hdr: bool) {
+	encoder.set_color_range(ffmpeg::color::Range::MPEG);
+	if hdr {
+		encoder.set_colorspace(ffmpeg::color::Space::BT2020NCL);
+		unsafe {
+			(*encoder.as_mut_ptr()).color_primaries = ffmpeg::color::Primaries::BT2020.into();
+			(*encoder.as_mut_ptr()).color_trc = ffmpeg::color::TransferCharacteristic::SMPTE2084.into();
 		}
+	} else {
+		encoder.set_colorspace(ffmpeg::color::Space::BT709);
+		unsafe {
+			(*encoder.as_mut_ptr()).color_primaries = ffmpeg::color::Primaries::BT709.into();
+			(*encoder.as_mut_ptr()).color_trc = ffmpeg::color::TransferCharacteristic::BT709.into();
+		}
+	}
+}
 
-		if let Ok(encoder) = encoder {
-			for _ in 0..nr_parity_shards {
-				shards.push(vec![0u8; requested_shard_size]);
-			}
-
-			encoder.encode(&mut shards)
-				.map_err(|e| log::error!("Failed to encode packet as FEC shards: {e}"))?;
+/// Low latency parameters for libx265, without scene cut detection and with an infinite GOP.
+const X265_PARAMS: &str = "keyint=-1:scenecut=0";
+
+/// Enable intra refresh, which refreshes the frame with a wave of intra coded blocks every `refresh_period` frames instead of sending IDR frames.
+///
+/// Returns `false` if the encoder doesn't support intra refresh.
+fn enable_intra_refresh(encoder: &mut ffmpeg::encoder::video::Video, codec_name: &str, refresh_period: u32) -> bool {
+	let result = match codec_name {
+		// libx265 only accepts this as an x265 parameter, which replaces the low latency parameters.
+		// It uses keyint as refresh period, later parameters override earlier ones.
+		"libx265" => encoder.set_str("x265-params", &format!("{X265_PARAMS}:intra-refresh=1:keyint={refresh_period}")),
+		_ => encoder.set_str("intra-refresh", "1"),
+	};
 
-			// Force these values for the parity shards, we don't need to reconstruct them, but Moonlight needs them to match with the frame they came from.
-			for (block_shard_index, shard) in shards[nr_data_shards..].iter_mut().enumerate() {
-				let rtp_header = unsafe { &mut *(shard.as_mut_ptr() as *mut RtpHeader) };
-				rtp_header.header = 0x90u8.to_be(); // The `.to_be` is redundant for u8, but is there to make it clear it should be big-endian.
-				rtp_header.sequence_number = (*sequence_number as u16).to_be();
+	match result {
+		Ok(()) => {
+			log::info!("Using intra refresh to recover from packet loss.");
+			true
+		},
+		Err(e) => {
+			log::warn!("Encoder '{codec_name}' doesn't support intra refresh ({e}), using IDR frames to recover from packet loss.");
+			false
+		},
+	}
+}
 
-				let video_packet_header = unsafe {
-					&mut *(shard.as_mut_ptr().add(std::mem::size_of::<RtpHeader>() + std::mem::size_of_val(&PADDING)) as *mut NvVideoPacket)
-				};
-				video_packet_header.multi_fec_blocks = ((block_index as u8) << 4) | last_block_index;
-				video_packet_header.fec_info = ((nr_data_shards + block_shard_index) << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32;
-				video_packet_header.frame_index = frame_number;
+/// Configure a software encoder for low latency streaming.
+fn set_software_options(encoder: &mut ffmpeg::encoder::video::Video, codec_name: &str) -> Result<(), ()> {
+	let options: &[(&str, &str)] = match codec_name {
+		"libx264" => &[
+			("preset", "superfast"),
+			("tune", "zerolatency"),
+			("forced-idr", "1"),
+			("x264-params", "scenecut=0"),
+		],
+		"libx265" => &[
+			("preset", "superfast"),
+			("tune", "zerolatency"),
+			("forced-idr", "1"),
+			("x265-params", X265_PARAMS),
+		],
+		"libsvtav1" => &[
+			("preset", "12"),
+			("svtav1-params", "pred-struct=1:scd=0:keyint=-1"),
+		],
+		_ => {
+			log::warn!("No low latency options known for encoder '{codec_name}', using encoder defaults.");
+			&[]
+		},
+	};
 
-				*sequence_number += 1;
-			}
-		}
+	for (name, value) in options {
+		encoder.set_str(name, value)
+			.map_err(|e| log::error!("Failed to set option '{name}' to '{value}' for encoder: {e}"))?;
+	}
 
-		for (index, shard) in shards.into_iter().enumerate() {
-			log::trace!("Sending shard {}/{} with size {} bytes.", index + 1, nr_data_shards + nr_parity_shards, shard.len());
-			if packet_tx.blocking_send(shard).is_err() {
-				log::info!("Channel closed, couldn't send packet.");
-				return Ok(());
-			}
-		}
+	Ok(())
+}
 
-		log::trace!("Finished sending frame {frame_number}.");
+#[cfg(test)]
+mod tests {
+	use super::*;
 
-		// At this point we should have sent all the data shards in the last block, so we can break the loop.
-		if block_index == 3 {
-			break;
-		}
+	#[test]
+	fn lost_frames_before_the_last_idr_frame_are_ignored() {
+		assert!(!lost_frames_need_idr(10, 5));
+		assert!(!lost_frames_need_idr(10, 9));
 	}
 
-	Ok(())
+	#[test]
+	fn lost_frames_after_the_last_idr_frame_need_an_idr_frame() {
+		// The IDR frame itself was lost.
+		assert!(lost_frames_need_idr(10, 10));
+		assert!(lost_frames_need_idr(10, 15));
+	}
 }
@@ -0,0 +1,52 @@
+use std::sync::{atomic::{AtomicU64, Ordering}, Arc};
+
+use openssl::symm::{encrypt_aead, Cipher};
+
+/// Length of the AES-GCM authentication tag of every encrypted packet.
+const TAG_LENGTH: usize = 16;
+
+/// Encrypts video packets with AES-GCM, when the client enabled video encryption.
+///
+/// Every encrypted packet is prefixed with `{ iv: [u8; 12], frame_number: u32, tag: [u8; 16] }`.
+///
+/// Clones share the IV counter, so a cipher can be handed to every pipeline of a stream without reusing an IV.
+#[derive(Clone)]
+pub struct VideoCipher {
+	key: Vec<u8>,
+	iv_counter: Arc<AtomicU64>,
+}
+
+impl VideoCipher {
+	pub fn new(key: Vec<u8>) -> Self {
+		Self { key, iv_counter: Arc::new(AtomicU64::new(0)) }
+	}
+
+	/// Encrypt a single packet (shard) of the given frame.
+	pub fn encrypt(&self, packet: &[u8], frame_number: u32) -> Result<Vec<u8>, ()> {
+		// An IV should never be reused with the same key, so use a counter for every packet.
+		// The last bytes mark it as a video stream IV, to keep it apart from IVs of other streams using the same key.
+		let mut initialization_vector = [0u8; 12];
+		let iv_counter = self.iv_counter.fetch_add(1, Ordering::Relaxed);
+		initialization_vector[..8].copy_from_slice(&iv_counter.to_le_bytes());
+		initialization_vector[10] = b'V';
+		initialization_vector[11] = b'S';
+
+		let mut tag = [0u8; TAG_LENGTH];
+		let encrypted = encrypt_aead(
+			Cipher::aes_128_gcm(),
+			&self.key,
+			Some(&initialization_vector),
+			&[],
+			packet,
+			&mut tag,
+		).map_err(|e| log::error!("Failed to encrypt video packet: {:?}", e.errors()))?;
+
+		let mut buffer = Vec::with_capacity(initialization_vector.len() + std::mem::size_of::<u32>() + TAG_LENGTH + encrypted.len());
+		buffer.extend(initialization_vector);
+		buffer.extend(frame_number.to_le_bytes());
+		buffer.extend(tag);
+		buffer.extend(encrypted);
+
+		Ok(buffer)
+	}
+}
@@ -1,24 +1,181 @@
-use std::sync::{Arc, Mutex};
+use std::{sync::{Arc, Mutex}, thread::JoinHandle};
 
 use async_shutdown::ShutdownManager;
-use ffmpeg::{format::Pixel, Frame};
-use tokio::{net::UdpSocket, sync::mpsc::{self, Sender}};
+use cudarc::driver::CudaDevice;
+use tokio::{net::UdpSocket, sync::{broadcast, mpsc::{self, Receiver, Sender}, watch}};
 
-use crate::{config::Config, ffmpeg::{check_ret, hwframe::HwFrameContext}};
+use crate::{config::{Config, VideoCodec, VideoEncoderType, VideoSourceConfig, VideoStreamConfig}, session::{recorder::Recorder, SessionKeys}};
+
+use super::clock::{LatencyMonitor, MediaClock};
+
+mod av1;
 
 mod capture;
-use capture::FrameCapturer;
+use capture::{CapturedFrame, FrameCapturer, FrameSource, NvFbcSource, TestPatternSource};
+
+mod convert;
+pub use convert::SDR_WHITE_NITS;
+
+#[cfg(test)]
+mod depacketizer;
 
 mod encoder;
-use encoder::Encoder;
+use encoder::{Encoder, RecoveryRequest, StreamPosition};
+
+mod encryption;
+use encryption::VideoCipher;
+
+mod packetizer;
+
+mod rate_control;
+pub use rate_control::LossStats;
+use rate_control::{RateController, StreamSettings, TIMER_INTERVAL};
+
+mod send_queue;
+use send_queue::{Admission, PacketizedFrame, SendQueue, FRAME_QUEUE_SIZE};
+
+mod sender;
+use sender::ShardSender;
 
 #[derive(Debug)]
 enum VideoStreamCommand {
-	Start,
+	Start(SessionKeys),
+	UpdateKeys(SessionKeys),
+	Reconfigure(VideoStreamContext),
+	SourceResized,
 	RequestIdrFrame,
+	InvalidateReferenceFrames { first_frame: i64, last_frame: i64 },
+	LossStats(LossStats),
+	FramesDropped(u32),
+}
+
+/// Format of the video stream, as requested by the client.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VideoFormat {
+	#[default]
+	H264,
+	Hevc,
+	Av1,
+}
+
+impl VideoFormat {
+	/// Parse the format from the value of `x-nv-vqos[0].bitStreamFormat`.
+	pub fn from_bitstream_format(format: u32) -> Option<Self> {
+		match format {
+			0 => Some(Self::H264),
+			1 => Some(Self::Hevc),
+			2 => Some(Self::Av1),
+			_ => None,
+		}
+	}
+
+	/// Get the name of the codec that encodes this format.
+	pub fn codec_name<'a>(&self, config: &'a VideoStreamConfig, hardware: bool) -> &'a str {
+		match (self, hardware) {
+			(Self::H264, true) => &config.codec_h264,
+			(Self::H264, false) => &config.codec_h264_software,
+			(Self::Hevc, true) => &config.codec_hevc,
+			(Self::Hevc, false) => &config.codec_hevc_software,
+			(Self::Av1, true) => &config.codec_av1,
+			(Self::Av1, false) => &config.codec_av1_software,
+		}
+	}
+
+	/// Check if an encoder for this format can be opened, in hardware or (if allowed) in software.
+	/// With `hdr` the encoder has to accept 10-bit frames, with `yuv444` it has to accept frames without chroma subsampling.
+	///
+	/// Finding the encoder isn't enough, a hardware encoder fails to open if the GPU doesn't support the format.
+	fn can_encode(&self, config: &VideoStreamConfig, cuda_device: Option<&Arc<CudaDevice>>, hdr: bool, yuv444: bool) -> bool {
+		let candidates = match (config.encoder, cuda_device) {
+			(VideoEncoderType::Software, _) | (VideoEncoderType::Auto, None) => vec![None],
+			// Software encoding isn't allowed, so without a CUDA device nothing can be encoded.
+			(VideoEncoderType::Cuda, None) => vec![],
+			(VideoEncoderType::Cuda, Some(cuda_device)) => vec![Some(cuda_device)],
+			(VideoEncoderType::Auto, Some(cuda_device)) => vec![Some(cuda_device), None],
+		};
+
+		candidates.into_iter().any(|cuda_device| {
+			let codec_name = self.codec_name(config, cuda_device.is_some());
+			ffmpeg::encoder::find_by_name(codec_name).is_some() && Encoder::new(
+				cuda_device,
+				codec_name,
+				(PROBE_WIDTH, PROBE_HEIGHT),
+				PROBE_WIDTH, PROBE_HEIGHT,
+				60,
+				1_000_000,
+				hdr,
+				yuv444,
+				1,
+				false,
+			).is_ok()
+		})
+	}
+}
+
+/// Resolution of the encoders that are opened to probe the capabilities of the host.
+const PROBE_WIDTH: u32 = 640;
+const PROBE_HEIGHT: u32 = 360;
+
+/// What the configured encoders can stream, which is probed once at startup and advertised to clients.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct VideoCapabilities {
+	/// AV1 can be encoded.
+	pub av1: bool,
+
+	/// HEVC can be encoded in 10-bit (Main10), which is needed for HDR.
+	pub hevc_main10: bool,
+
+	/// AV1 can be encoded in 10-bit (Main10), which is needed for HDR.
+	pub av1_main10: bool,
+
+	/// H264 can be encoded without chroma subsampling (High 4:4:4).
+	pub h264_yuv444: bool,
+
+	/// HEVC can be encoded without chroma subsampling (RExt 4:4:4).
+	pub hevc_yuv444: bool,
+}
+
+impl VideoCapabilities {
+	/// Probe the capabilities by opening the encoders, this blocks until the encoders are opened.
+	pub fn probe(config: &VideoStreamConfig) -> Self {
+		let cuda_device = match config.encoder {
+			VideoEncoderType::Software => None,
+			_ => CudaDevice::new(0).ok().filter(|cuda_device| {
+				cuda_device.bind_to_thread()
+					.map_err(|e| log::warn!("Failed to bind CUDA device to thread: {e}"))
+					.is_ok()
+			}),
+		};
+
+		let av1 = VideoFormat::Av1.can_encode(config, cuda_device.as_ref(), false, false);
+		let capabilities = Self {
+			av1,
+			hevc_main10: VideoFormat::Hevc.can_encode(config, cuda_device.as_ref(), true, false),
+			av1_main10: av1 && VideoFormat::Av1.can_encode(config, cuda_device.as_ref(), true, false),
+			h264_yuv444: VideoFormat::H264.can_encode(config, cuda_device.as_ref(), false, true),
+			hevc_yuv444: VideoFormat::Hevc.can_encode(config, cuda_device.as_ref(), false, true),
+		};
+		log::info!("Probed video encoders: {capabilities:?}");
+
+		capabilities
+	}
+
+	/// HDR can be streamed in at least one format, using codecs up to `preferred_codec`.
+	pub fn hdr(&self, preferred_codec: VideoCodec) -> bool {
+		(self.hevc_main10 && preferred_codec >= VideoCodec::Hevc) || (self.av1_main10 && preferred_codec >= VideoCodec::Av1)
+	}
+
+	/// YUV 4:4:4 can be streamed in `format`, AV1 is never streamed in 4:4:4.
+	pub fn yuv444(&self, format: VideoFormat) -> bool {
+		match format {
+			VideoFormat::H264 => self.h264_yuv444,
+			VideoFormat::Hevc => self.hevc_yuv444,
+			VideoFormat::Av1 => false,
+		}
+	}
 }
 
-#[derive(Clone, Debug, Default)]
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
 pub struct VideoStreamContext {
 	pub width: u32,
 	pub height: u32,
@@ -27,7 +184,39 @@ pub struct VideoStreamContext {
 	pub bitrate: usize,
 	pub minimum_fec_packets: u32,
 	pub qos: bool,
-	pub video_format: u32,
+	pub video_format: VideoFormat,
+
+	/// Stream in HDR, using 10-bit BT.2020 with the PQ transfer function.
+	pub hdr: bool,
+
+	/// Encrypt video packets, as negotiated with the client.
+	pub encrypted: bool,
+
+	/// Number of slices every frame is split in to, as requested by the client.
+	pub slices_per_frame: u32,
+
+	/// Encode without chroma subsampling (YUV 4:4:4), as negotiated with the client.
+	pub yuv444: bool,
+}
+
+impl VideoStreamContext {
+	/// Lower the bitrate and frame rate requested by the client to the configured maximum.
+	fn limit(mut self, config: &VideoStreamConfig) -> Self {
+		if let Some(max_bitrate) = config.max_bitrate {
+			if self.bitrate > max_bitrate * 1024 {
+				log::info!("Limiting bitrate from {} kbps to {max_bitrate} kbps.", self.bitrate / 1024);
+				self.bitrate = max_bitrate * 1024;
+			}
+		}
+		if let Some(max_fps) = config.max_fps {
+			if self.fps > max_fps {
+				log::info!("Limiting frame rate from {} to {max_fps}.", self.fps);
+				self.fps = max_fps.max(1);
+			}
+		}
+
+		self
+	}
 }
 
 #[derive(Clone)]
@@ -35,47 +224,144 @@ pub struct VideoStream {
 	command_tx: Sender<VideoStreamCommand>
 }
 
+/// The capture and encode threads, these are recreated when the stream is reconfigured.
+struct Pipeline {
+	/// Stops the threads of this pipeline, without stopping the session.
+	stop_signal: ShutdownManager<()>,
+	capture_thread: JoinHandle<Result<FrameCapturer, ()>>,
+	encode_thread: JoinHandle<Result<StreamPosition, ()>>,
+}
+
+impl Pipeline {
+	/// Stop the threads and wait for them to finish.
+	///
+	/// Returns the frame capturer and the position in the stream, so that a new pipeline can continue where this one stopped.
+	async fn stop(self) -> (Option<FrameCapturer>, Option<StreamPosition>) {
+		let _ = self.stop_signal.trigger_shutdown(());
+		tokio::task::spawn_blocking(move || {
+			let capturer = self.capture_thread.join().ok().and_then(Result::ok);
+			let position = self.encode_thread.join().ok().and_then(Result::ok);
+			(capturer, position)
+		})
+			.await
+			.map_err(|e| log::error!("Failed to wait for video threads to stop: {e}"))
+			.unwrap_or((None, None))
+	}
+}
+
 struct VideoStreamInner {
+	config: Config,
+	context: VideoStreamContext,
+	command_tx: Sender<VideoStreamCommand>,
+	packet_tx: Sender<PacketizedFrame>,
+	recovery_request_tx: broadcast::Sender<RecoveryRequest>,
+	rate_controller: RateController,
+	settings_tx: watch::Sender<StreamSettings>,
+	stop_signal: ShutdownManager<()>,
+
+	/// Encrypts the stream with the session keys, these are known once the stream is started.
+	///
+	/// The cipher outlives the pipelines, so that a restarted pipeline continues with the IVs where the previous one stopped.
+	cipher: Option<VideoCipher>,
+
+	/// CUDA device used for capturing and encoding, if CUDA is used.
+	cuda_device: Option<Arc<CudaDevice>>,
+
+	/// Clock of the session, which stamps the frames.
+	clock: MediaClock,
+
+	/// Position in the stream, restored when the pipeline is recreated.
+	position: StreamPosition,
+
+	/// The running pipeline, if the stream is started.
+	pipeline: Option<Pipeline>,
+
+	/// Records the encoded video, if recording is enabled.
+	recorder: Option<Recorder>,
 }
 
 impl VideoStream {
-	pub fn new(config: Config, context: VideoStreamContext, stop_signal: ShutdownManager<()>) -> Self {
+	pub fn new(
+		config: Config,
+		context: VideoStreamContext,
+		clock: MediaClock,
+		recorder: Option<Recorder>,
+		stop_signal: ShutdownManager<()>,
+	) -> Self {
+		let context = context.limit(&config.stream.video);
 		let (command_tx, command_rx) = mpsc::channel(10);
-		let inner = VideoStreamInner { };
-		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
+		let (packet_tx, packet_rx) = mpsc::channel(FRAME_QUEUE_SIZE);
+		let (recovery_request_tx, _recovery_request_rx) = broadcast::channel(16);
+		let rate_controller = RateController::new(context.bitrate, config.stream.video.fec_percentage, context.fps);
+		let (settings_tx, _settings_rx) = watch::channel(rate_controller.settings());
+		let inner = VideoStreamInner {
 			config,
 			context,
+			command_tx: command_tx.clone(),
+			packet_tx,
+			recovery_request_tx,
+			rate_controller,
+			settings_tx,
+			stop_signal: stop_signal.clone(),
+			cipher: None,
+			cuda_device: None,
+			clock,
+			position: StreamPosition::new(),
+			pipeline: None,
+			recorder,
+		};
+		tokio::spawn(stop_signal.wrap_cancel(stop_signal.wrap_trigger_shutdown((), inner.run(
 			command_rx,
-			stop_signal.clone()
+			packet_rx,
 		))));
 
 		Self { command_tx }
 	}
 
-	pub async fn start(&self) -> Result<(), ()> {
-		self.command_tx.send(VideoStreamCommand::Start).await
+	pub async fn start(&self, keys: SessionKeys) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::Start(keys)).await
 			.map_err(|e| log::warn!("Failed to send Start command: {e}"))
 	}
 
+	pub async fn update_keys(&self, keys: SessionKeys) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::UpdateKeys(keys)).await
+			.map_err(|e| log::warn!("Failed to send UpdateKeys command: {e}"))
+	}
+
+	/// Continue the stream with a new context, for example when a client resumes with a different resolution.
+	pub async fn reconfigure(&self, context: VideoStreamContext) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::Reconfigure(context)).await
+			.map_err(|e| log::warn!("Failed to send Reconfigure command: {e}"))
+	}
+
 	pub async fn request_idr_frame(&self) -> Result<(), ()> {
 		self.command_tx.send(VideoStreamCommand::RequestIdrFrame).await
 			.map_err(|e| log::warn!("Failed to send RequestIdrFrame command: {e}"))
 	}
+
+	/// Let the encoder know that the client lost the frames from `first_frame` up to and including `last_frame`.
+	pub async fn invalidate_reference_frames(&self, first_frame: i64, last_frame: i64) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::InvalidateReferenceFrames { first_frame, last_frame }).await
+			.map_err(|e| log::warn!("Failed to send InvalidateReferenceFrames command: {e}"))
+	}
+
+	pub async fn report_loss_stats(&self, stats: LossStats) -> Result<(), ()> {
+		self.command_tx.send(VideoStreamCommand::LossStats(stats)).await
+			.map_err(|e| log::warn!("Failed to send LossStats command: {e}"))
+	}
 }
 
 impl VideoStreamInner {
 	async fn run(
-		self,
-		config: Config,
-		mut context: VideoStreamContext,
-		mut command_rx: mpsc::Receiver<VideoStreamCommand>,
-		stop_signal: ShutdownManager<()>,
+		mut self,
+		mut command_rx: Receiver<VideoStreamCommand>,
+		mut packet_rx: Receiver<PacketizedFrame>,
 	) -> Result<(), ()> {
-		let socket = UdpSocket::bind((config.address, config.stream.video.port))
+		let socket = UdpSocket::bind((self.config.address.as_str(), self.config.stream.video.port))
 			.await
 			.map_err(|e| log::error!("Failed to bind to UDP socket: {e}"))?;
 
-		if context.qos {
+		if self.context.qos {
 			// TODO: Check this value 160, what does it mean exactly?
 			log::debug!("Enabling QoS on video socket.");
 			socket.set_tos(160)
@@ -88,19 +374,46 @@ impl VideoStreamInner {
 				.map_err(|e| log::error!("Failed to get local address associated with control socket: {e}"))?
 		);
 
-		let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(1024);
+		let command_tx = self.command_tx.clone();
+		let recovery_request_tx = self.recovery_request_tx.clone();
+		let mut shard_sender = ShardSender::new(&self.config.stream.video);
 		tokio::spawn(async move {
 			let mut buf = [0; 1024];
 			let mut client_address = None;
+			let mut send_queue = SendQueue::default();
+			let mut latency = LatencyMonitor::new("video");
 
 			loop {
 				tokio::select! {
-					packet = packet_rx.recv() => {
-						match packet {
-							Some(packet) => {
+					frame = packet_rx.recv() => {
+						match frame {
+							Some(frame) => {
+								match send_queue.admit(&frame, std::time::Instant::now()) {
+									Admission::Send => {},
+									Admission::Drop => {
+										latency.record_dropped(1);
+										continue;
+									},
+									Admission::Recover { dropped_frames } => {
+										// Every frame is dropped until the next key frame, so ask for one right away.
+										log::info!("Dropped {dropped_frames} frame(s) because the network can't keep up, requesting IDR frame.");
+										latency.record_dropped(dropped_frames);
+										if let Err(e) = recovery_request_tx.send(RecoveryRequest::IdrFrame) {
+											log::warn!("Failed to send IDR frame request to encoder: {e}");
+										}
+
+										// Don't wait for the command to be handled, sending has priority.
+										if let Err(e) = command_tx.try_send(VideoStreamCommand::FramesDropped(dropped_frames)) {
+											log::debug!("Failed to report dropped frames to the rate controller: {e}");
+										}
+										continue;
+									},
+								}
+
 								if let Some(client_address) = client_address {
-									if let Err(e) = socket.send_to(packet.as_slice(), client_address).await {
-										log::warn!("Failed to send packet to client: {e}");
+									match shard_sender.send(&socket, client_address, &frame).await {
+										Ok(()) => latency.record(frame.captured_at, std::time::Instant::now()),
+										Err(e) => log::warn!("Failed to send frame {} to client: {e}", frame.frame_number),
 									}
 								}
 							},
@@ -130,101 +443,94 @@ impl VideoStreamInner {
 				}
 			}
 
-			log::debug!("Stopping video stream.");
+			log::debug!("Stopping video stream, {} frame(s) were dropped because the network couldn't keep up.", send_queue.dropped_frames());
 		});
 
-		let mut started_streaming = false;
-		let (idr_frame_request_tx, _idr_frame_request_rx) = tokio::sync::broadcast::channel(1);
-		while let Some(command) = command_rx.recv().await {
+		// The rate controller raises the bitrate again once the link was clean for a while.
+		let mut rate_control_timer = tokio::time::interval(TIMER_INTERVAL);
+		loop {
+			let command = tokio::select! {
+				command = command_rx.recv() => match command {
+					Some(command) => command,
+					None => break,
+				},
+				_ = rate_control_timer.tick() => {
+					self.adapt_settings(|controller, now| controller.on_timer(now));
+					continue;
+				},
+			};
+
 			match command {
+				VideoStreamCommand::LossStats(stats) => {
+					self.adapt_settings(|controller, now| controller.on_loss_stats(&stats, now));
+				},
+				VideoStreamCommand::FramesDropped(dropped_frames) => {
+					self.adapt_settings(|controller, now| controller.on_dropped_frames(dropped_frames, now));
+				},
 				VideoStreamCommand::RequestIdrFrame => {
 					log::info!("Received request for IDR frame, next frame will be an IDR frame.");
-					idr_frame_request_tx.send(())
+					self.recovery_request_tx.send(RecoveryRequest::IdrFrame)
 						.map_err(|e| log::error!("Failed to send IDR frame request to encoder: {e}"))?;
+
+					// Clients request an IDR frame when they lost frames.
+					self.adapt_settings(|controller, now| controller.on_lost_frames(now));
 				},
-				VideoStreamCommand::Start => {
-					if started_streaming {
+				VideoStreamCommand::InvalidateReferenceFrames { first_frame, last_frame } => {
+					log::debug!("Received request to invalidate frames {first_frame} to {last_frame}.");
+					self.recovery_request_tx.send(RecoveryRequest::InvalidateReferenceFrames { first_frame, last_frame })
+						.map_err(|e| log::error!("Failed to send reference frame invalidation request to encoder: {e}"))?;
+				},
+				VideoStreamCommand::Start(keys) => {
+					if self.pipeline.is_some() {
 						log::warn!("Can't start streaming twice.");
 						continue;
 					}
 
 					// TODO: Make the GPU index configurable.
-					let cuda_device = cudarc::driver::CudaDevice::new(0)
-						.map_err(|e| log::error!("Failed to initialize CUDA: {e}"))?;
-
-					let capturer = FrameCapturer::new()?;
-					let status = capturer.status()?;
-					if status.screen_size.w != context.width || status.screen_size.h != context.height {
-						// TODO: Resize the CUDA buffer to the requested size?
-						log::warn!(
-							"Client asked for resolution {}x{}, but we are generating a resolution of {}x{}.",
-							context.width, context.height, status.screen_size.w, status.screen_size.h
-						);
-						context.width = status.screen_size.w;
-						context.height = status.screen_size.h;
-					}
+					self.cuda_device = match self.config.stream.video.encoder {
+						VideoEncoderType::Cuda => Some(CudaDevice::new(0)
+							.map_err(|e| log::error!("Failed to initialize CUDA: {e}"))?),
+						VideoEncoderType::Software => None,
+						VideoEncoderType::Auto => CudaDevice::new(0)
+							.map_err(|e| log::info!("CUDA is not available ({e}), using software encoding."))
+							.ok(),
+					};
+					self.cipher = Some(VideoCipher::new(keys.remote_input_key));
+
+					let capturer = FrameCapturer::new(self.create_source()?);
+					self.pipeline = Some(self.start_pipeline(capturer)?);
+				},
+				VideoStreamCommand::UpdateKeys(keys) => {
+					log::debug!("Updating session keys.");
+					self.cipher = Some(VideoCipher::new(keys.remote_input_key));
 
-					let mut encoder = Encoder::new(
-						&cuda_device,
-						if context.video_format == 0 { &config.stream.video.codec_h264 } else { &config.stream.video.codec_hevc },
-						context.width, context.height,
-						context.fps,
-						context.bitrate,
-					)?;
-
-					let capture_buffer = create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
-					let intermediate_buffer = Arc::new(Mutex::new(create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?));
-					let encoder_buffer = create_frame(context.width, context.height, Pixel::CUDA, &mut encoder.hw_frame_context)?;
-					let notifier = Arc::new(std::sync::Condvar::new());
-
-					let capture_thread = std::thread::Builder::new().name("video-capture".to_string()).spawn({
-						let intermediate_buffer = intermediate_buffer.clone();
-						let notifier = notifier.clone();
-						let context = context.clone();
-						let stop_signal = stop_signal.clone();
-						move || {
-							cuda_device.bind_to_thread()
-								.map_err(|e| log::error!("Failed to bind CUDA device to thread: {e}"))?;
-							capturer.run(
-								context.fps,
-								capture_buffer,
-								intermediate_buffer,
-								notifier,
-								stop_signal,
-							)
-						}
-					});
-					if let Err(e) = capture_thread {
-						log::error!("Failed to start video capture thread: {e}");
+					// Encrypted packets have to use the new keys, so restart with the new cipher.
+					if self.context.encrypted {
+						self.restart_pipeline().await?;
+					}
+				},
+				VideoStreamCommand::Reconfigure(context) => {
+					let context = context.limit(&self.config.stream.video);
+					if context == self.context {
+						log::debug!("Stream context is unchanged, not reconfiguring the video stream.");
 						continue;
 					}
 
-					let encode_thread = std::thread::Builder::new().name("video-encode".to_string()).spawn({
-						let packet_tx = packet_tx.clone();
-						let notifier = notifier.clone();
-						let idr_frame_request_rx = idr_frame_request_tx.subscribe();
-						let context = context.clone();
-						let stop_signal = stop_signal.clone();
-						move || {
-							encoder.run(
-								packet_tx,
-								idr_frame_request_rx,
-								context.packet_size,
-								context.minimum_fec_packets,
-								config.stream.video.fec_percentage,
-								encoder_buffer,
-								intermediate_buffer,
-								notifier,
-								stop_signal,
-							)
-						}
-					});
-					if let Err(e) = encode_thread {
-						log::error!("Failed to video encoding thread: {e}");
-						continue;
+					log::info!(
+						"Reconfiguring video stream from {}x{}@{} to {}x{}@{}.",
+						self.context.width, self.context.height, self.context.fps,
+						context.width, context.height, context.fps,
+					);
+					if context.bitrate != self.context.bitrate || context.fps != self.context.fps {
+						self.rate_controller = RateController::new(context.bitrate, self.config.stream.video.fec_percentage, context.fps);
+						self.settings_tx.send_replace(self.rate_controller.settings());
 					}
+					self.context = context;
 
-					started_streaming = true;
+					self.restart_pipeline().await?;
+				},
+				VideoStreamCommand::SourceResized => {
+					self.restart_pipeline().await?;
 				},
 			}
 		}
@@ -232,22 +538,184 @@ impl VideoStreamInner {
 		log::debug!("Command channel closed.");
 		Ok(())
 	}
-}
 
-fn create_frame(width: u32, height: u32, pixel_format: Pixel, context: &mut HwFrameContext) -> Result<Frame, ()> {
-	unsafe {
-		let mut frame = Frame::empty();
-		(*frame.as_mut_ptr()).format = pixel_format as i32;
-		(*frame.as_mut_ptr()).width = width as i32;
-		(*frame.as_mut_ptr()).height = height as i32;
-		(*frame.as_mut_ptr()).hw_frames_ctx = context.as_raw_mut();
-
-		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
-			.map_err(|e| log::error!("Failed to create CUDA frame: {e}"))?;
-		check_ret(ffmpeg::sys::av_hwframe_get_buffer(context.as_raw_mut(), frame.as_mut_ptr(), 0))
-			.map_err(|e| println!("Failed to allocate hardware frame: {e}"))?;
-		(*frame.as_mut_ptr()).linesize[0] = (*frame.as_ptr()).width * 4;
-
-		Ok(frame)
+	/// Let the rate controller process an event, and pass the new settings to the encoder if adaptive bitrate is enabled.
+	fn adapt_settings(&mut self, event: impl FnOnce(&mut RateController, std::time::Instant) -> Option<StreamSettings>) {
+		if !self.config.stream.video.adaptive_bitrate {
+			return;
+		}
+
+		if let Some(settings) = event(&mut self.rate_controller, std::time::Instant::now()) {
+			self.settings_tx.send_replace(settings);
+		}
+	}
+
+	fn create_source(&self) -> Result<Box<dyn FrameSource>, ()> {
+		Ok(match self.config.stream.video.source {
+			VideoSourceConfig::Nvfbc => Box::new(NvFbcSource::new()?),
+			VideoSourceConfig::TestPattern => Box::new(TestPatternSource::new(self.context.width, self.context.height)),
+		})
+	}
+
+	/// Stop the running pipeline (if any) and start a new one, continuing the stream where the old pipeline stopped.
+	///
+	/// The new encoder starts with an IDR frame.
+	async fn restart_pipeline(&mut self) -> Result<(), ()> {
+		let Some(pipeline) = self.pipeline.take() else {
+			return Ok(());
+		};
+
+		let (capturer, position) = pipeline.stop().await;
+		match position {
+			Some(position) => self.position = position,
+			None => log::warn!("Failed to retrieve the position of the stream, the client may drop frames."),
+		}
+
+		let capturer = match capturer {
+			Some(capturer) => capturer,
+			None => FrameCapturer::new(self.create_source()?),
+		};
+
+		self.pipeline = Some(self.start_pipeline(capturer)?);
+		Ok(())
+	}
+
+	fn start_pipeline(&self, capturer: FrameCapturer) -> Result<Pipeline, ()> {
+		let context = &self.context;
+		let capture_size = capturer.size();
+		if capture_size != (context.width, context.height) {
+			log::info!(
+				"Client asked for resolution {}x{}, scaling captured resolution of {}x{}.",
+				context.width, context.height, capture_size.0, capture_size.1
+			);
+		}
+
+		let create_encoder = |cuda_device: Option<&Arc<CudaDevice>>| Encoder::new(
+			cuda_device,
+			context.video_format.codec_name(&self.config.stream.video, cuda_device.is_some()),
+			capture_size,
+			context.width, context.height,
+			context.fps,
+			self.rate_controller.settings().bitrate,
+			context.hdr,
+			context.yuv444,
+			context.slices_per_frame,
+			self.config.stream.video.intra_refresh,
+		);
+		let (mut encoder, cuda_device) = match create_encoder(self.cuda_device.as_ref()) {
+			Ok(encoder) => (encoder, self.cuda_device.clone()),
+			Err(()) if self.cuda_device.is_some() && self.config.stream.video.encoder == VideoEncoderType::Auto => {
+				// The hardware encoder can fail when for example all NVENC sessions are in use.
+				log::warn!("Failed to create hardware encoder, falling back to software encoding.");
+				(create_encoder(None)?, None)
+			},
+			Err(()) => return Err(()),
+		};
+
+		let cipher = match &self.cipher {
+			Some(cipher) if context.encrypted => {
+				log::debug!("Encrypting video stream.");
+				Some(cipher.clone())
+			},
+			_ => None,
+		};
+
+		let capture_buffer = encoder.create_frame()?;
+		let intermediate_buffer = Arc::new(Mutex::new(CapturedFrame::new(encoder.create_frame()?)));
+		let encoder_buffer = encoder.create_frame()?;
+
+		// Stop the pipeline when the session stops.
+		let stop_signal = ShutdownManager::new();
+		tokio::spawn({
+			let session_stop_signal = self.stop_signal.clone();
+			let stop_signal = stop_signal.clone();
+			async move {
+				tokio::select! {
+					_ = session_stop_signal.wait_shutdown_triggered() => {
+						let _ = stop_signal.trigger_shutdown(());
+					},
+					_ = stop_signal.wait_shutdown_triggered() => {},
+				}
+			}
+		});
+
+		let capture_thread = std::thread::Builder::new().name("video-capture".to_string()).spawn({
+			let cuda_device = cuda_device.clone();
+			let intermediate_buffer = intermediate_buffer.clone();
+			let fps = context.fps;
+			let command_tx = self.command_tx.clone();
+			let stop_signal = stop_signal.clone();
+			move || {
+				if let Some(cuda_device) = cuda_device {
+					cuda_device.bind_to_thread()
+						.map_err(|e| log::error!("Failed to bind CUDA device to thread: {e}"))?;
+				}
+				let capturer = capturer.run(
+					fps,
+					capture_buffer,
+					intermediate_buffer,
+					stop_signal,
+				)?;
+
+				// Let the stream know that the pipeline has to be recreated for the new size.
+				if capturer.size() != capture_size {
+					let _ = command_tx.blocking_send(VideoStreamCommand::SourceResized)
+						.map_err(|e| log::error!("Failed to send SourceResized command: {e}"));
+				}
+
+				Ok(capturer)
+			}
+		});
+		let capture_thread = match capture_thread {
+			Ok(capture_thread) => capture_thread,
+			Err(e) => {
+				log::error!("Failed to start video capture thread: {e}");
+				let _ = stop_signal.trigger_shutdown(());
+				return Err(());
+			},
+		};
+
+		let encode_thread = std::thread::Builder::new().name("video-encode".to_string()).spawn({
+			let position = self.position;
+			let clock = self.clock;
+			let packet_tx = self.packet_tx.clone();
+			let recovery_request_rx = self.recovery_request_tx.subscribe();
+			let settings_rx = self.settings_tx.subscribe();
+			let packet_size = context.packet_size;
+			let minimum_fec_packets = context.minimum_fec_packets;
+			let recorder = self.recorder.clone();
+			let stop_signal = stop_signal.clone();
+			move || {
+				// The encoder may need to run CUDA kernels to convert frames.
+				if let Some(cuda_device) = cuda_device {
+					cuda_device.bind_to_thread()
+						.map_err(|e| log::error!("Failed to bind CUDA device to thread: {e}"))?;
+				}
+				encoder.run(
+					position,
+					clock,
+					packet_tx,
+					recovery_request_rx,
+					settings_rx,
+					packet_size,
+					minimum_fec_packets,
+					cipher,
+					recorder,
+					encoder_buffer,
+					intermediate_buffer,
+					stop_signal,
+				)
+			}
+		});
+		let encode_thread = match encode_thread {
+			Ok(encode_thread) => encode_thread,
+			Err(e) => {
+				log::error!("Failed to video encoding thread: {e}");
+				let _ = stop_signal.trigger_shutdown(());
+				return Err(());
+			},
+		};
+
+		Ok(Pipeline { stop_signal, capture_thread, encode_thread })
 	}
 }
@@ -0,0 +1,480 @@
+use reed_solomon_erasure::{galois_8, ReedSolomon};
+
+use crate::session::stream::RtpHeader;
+
+/// Maximum allowed number of shards in a FEC block (data + parity).
+pub const MAX_SHARDS: usize = 255;
+
+/// Maximum number of FEC blocks in a frame, any data that doesn't fit is sent without FEC in the last block.
+pub const MAX_BLOCKS: usize = 4;
+
+/// Random padding between the RTP header and the video packet header, because we need it.
+const PADDING: u32 = 0;
+
+/// Offset of the video packet header in a shard.
+const NV_VIDEO_PACKET_OFFSET: usize = std::mem::size_of::<RtpHeader>() + std::mem::size_of::<u32>();
+
+/// Offset of the payload in a shard.
+pub const PAYLOAD_OFFSET: usize = NV_VIDEO_PACKET_OFFSET + std::mem::size_of::<NvVideoPacket>();
+
+#[repr(u8)]
+enum RtpFlag {
+	ContainsPicData = 0x1,
+	EndOfFrame = 0x2,
+	StartOfFrame = 0x4,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+pub struct VideoFrameHeader {
+	header_type: u8,
+	padding1: u16,
+	frame_type: u8,
+	padding2: u32,
+}
+
+impl VideoFrameHeader {
+	/// Size of the serialized header, which is smaller than the in-memory representation because of alignment.
+	pub const SIZE: usize = 8;
+
+	fn serialize(&self, buffer: &mut Vec<u8>) {
+		buffer.extend(self.header_type.to_le_bytes());
+		buffer.extend(self.padding1.to_le_bytes());
+		buffer.extend(self.frame_type.to_le_bytes());
+		buffer.extend(self.padding2.to_le_bytes());
+	}
+}
+
+#[derive(Debug, PartialEq, Eq)]
+#[repr(C)]
+pub struct NvVideoPacket {
+	pub stream_packet_index: u32,
+	pub frame_index: u32,
+	pub flags: u8,
+	pub reserved: u8,
+	pub multi_fec_flags: u8,
+	pub multi_fec_blocks: u8,
+	pub fec_info: u32,
+}
+
+impl NvVideoPacket {
+	fn serialize(&self, buffer: &mut Vec<u8>) {
+		buffer.extend(self.stream_packet_index.to_le_bytes());
+		buffer.extend(self.frame_index.to_le_bytes());
+		buffer.extend(self.flags.to_le_bytes());
+		buffer.extend(self.reserved.to_le_bytes());
+		buffer.extend(self.multi_fec_flags.to_le_bytes());
+		buffer.extend(self.multi_fec_blocks.to_le_bytes());
+		buffer.extend(self.fec_info.to_le_bytes());
+	}
+
+	/// Parse the video packet header from a complete shard.
+	#[cfg(test)]
+	pub fn from_shard(shard: &[u8]) -> Option<Self> {
+		let header = shard.get(NV_VIDEO_PACKET_OFFSET..PAYLOAD_OFFSET)?;
+		Some(Self {
+			stream_packet_index: u32::from_le_bytes(header[0..4].try_into().unwrap()),
+			frame_index: u32::from_le_bytes(header[4..8].try_into().unwrap()),
+			flags: header[8],
+			reserved: header[9],
+			multi_fec_flags: header[10],
+			multi_fec_blocks: header[11],
+			fec_info: u32::from_le_bytes(header[12..16].try_into().unwrap()),
+		})
+	}
+}
+
+/// Pack the index of the current block and the index of the last block in `NvVideoPacket::multi_fec_blocks`.
+fn multi_fec_blocks(block_index: usize, last_block_index: usize) -> u8 {
+	((block_index as u8) << 4) | ((last_block_index as u8) << 6)
+}
+
+/// Pack the index of the shard in its block, the number of data shards and the FEC percentage in `NvVideoPacket::fec_info`.
+fn fec_info(shard_index: usize, nr_data_shards: usize, fec_percentage: usize) -> u32 {
+	(shard_index << 12 | nr_data_shards << 22 | fec_percentage << 4) as u32
+}
+
+/// Splits encoded frames in to shards (RTP packets) with FEC, as expected by Moonlight.
+///
+/// This doesn't perform any I/O, the caller is responsible for sending the shards.
+pub struct Packetizer {
+	/// Size of the video packet header and payload of every shard, as requested by the client.
+	requested_packet_size: usize,
+	minimum_fec_packets: u32,
+	sequence_number: u32,
+}
+
+impl Packetizer {
+	/// Create a packetizer, the first shard gets the given sequence number.
+	pub fn new(requested_packet_size: usize, minimum_fec_packets: u32, sequence_number: u32) -> Self {
+		Self { requested_packet_size, minimum_fec_packets, sequence_number }
+	}
+
+	/// Sequence number of the next shard.
+	pub fn sequence_number(&self) -> u32 {
+		self.sequence_number
+	}
+
+	/// Size of the payload in every shard.
+	fn shard_payload_size(&self) -> usize {
+		self.requested_packet_size - std::mem::size_of::<NvVideoPacket>()
+	}
+
+	/// Split an encoded frame in to shards, ordered as they should be sent.
+	pub fn packetize(
+		&mut self,
+		frame_data: &[u8],
+		key_frame: bool,
+		frame_number: u32,
+		timestamp: u32,
+		fec_percentage: u8,
+	) -> Result<Vec<Vec<u8>>, ()> {
+		// TODO: Figure out what this header means?
+		let video_frame_header = VideoFrameHeader {
+			header_type: 0x01, // Always 0x01 for short headers. What is this exactly?
+			padding1: 0,
+			frame_type: if key_frame { 2 } else { 1 },
+			padding2: 0,
+		};
+
+		// Prefix the frame with a VideoFrameHeader.
+		let mut packet_data = Vec::with_capacity(VideoFrameHeader::SIZE + frame_data.len());
+		video_frame_header.serialize(&mut packet_data);
+		packet_data.extend(frame_data);
+
+		let requested_shard_payload_size = self.shard_payload_size();
+
+		// The total size of a shard.
+		let requested_shard_size = PAYLOAD_OFFSET + requested_shard_payload_size;
+
+		// Determine how many data shards we will be sending.
+		let nr_data_shards = packet_data.len().div_ceil(requested_shard_payload_size);
+		assert!(nr_data_shards != 0);
+
+		// Determine how many data shards are permitted per FEC block, so that the parity shards still fit in the block.
+		let nr_data_shards_per_block = MAX_SHARDS * 100 / (100 + fec_percentage as usize);
+		let nr_parity_shards_per_block = MAX_SHARDS - nr_data_shards_per_block;
+
+		// We need to subtract number of data shards by 1, otherwise you can get a situation where
+		// there are for example 100 data shards allowed per block and also 100 data shards available.
+		// In this case, nr_blocks = 100 / 100 + 1 = 2, but we only need to send 1 block.
+		// Subtracting the value of nr_data_shards by 1 avoids this situation.
+		let nr_blocks = (nr_data_shards - 1) / nr_data_shards_per_block + 1;
+		let last_block_index = nr_blocks.min(MAX_BLOCKS) - 1;
+
+		log::trace!("Sending a max of {nr_data_shards_per_block} data shards and {nr_parity_shards_per_block} parity shards per block.");
+		log::trace!("Sending {nr_blocks} blocks of video data.");
+
+		let mut output = Vec::new();
+		for block_index in 0..nr_blocks.min(MAX_BLOCKS) {
+			// Determine what data shards are in this block.
+			let start = block_index * nr_data_shards_per_block;
+			let mut end = ((block_index + 1) * nr_data_shards_per_block)
+				.min(nr_data_shards);
+
+			if block_index == MAX_BLOCKS - 1 && end < nr_data_shards {
+				log::info!("Trying to create {nr_blocks} blocks, but we are limited to {MAX_BLOCKS} blocks so we are sending all remaining packets without FEC.");
+				end = nr_data_shards;
+			}
+
+			// Compute how many parity shards we will need (approximately) in this block.
+			let nr_data_shards = end - start;
+			assert!(nr_data_shards != 0);
+
+			// Moonlight computes the number of parity shards from the FEC percentage in the header (rounding up),
+			// so the percentage decides the number of parity shards and not the other way around.
+			let mut fec_percentage = fec_percentage as usize;
+			if (nr_data_shards * fec_percentage).div_ceil(100) < self.minimum_fec_packets as usize {
+				// Lower limit by the minimum number of parity shards.
+				fec_percentage = (self.minimum_fec_packets as usize * 100).div_ceil(nr_data_shards);
+			}
+			fec_percentage = fec_percentage
+				.min(MAX_SHARDS.saturating_sub(nr_data_shards) * 100 / nr_data_shards) // Hard total upper limit in the number of shards.
+				.min(u8::MAX as usize); // And the percentage has to fit in its field in the header.
+			let mut nr_parity_shards = (nr_data_shards * fec_percentage).div_ceil(100);
+
+			// Create the FEC encoder for this amount of shards.
+			let encoder = if nr_parity_shards > 0 {
+				match ReedSolomon::<galois_8::Field>::new(nr_data_shards, nr_parity_shards) {
+					Ok(encoder) => Some(encoder),
+					Err(e) => {
+						log::debug!("Couldn't create error correction for block {block_index}: {e}");
+						nr_parity_shards = 0;
+						fec_percentage = 0;
+						None
+					}
+				}
+			} else {
+				None
+			};
+
+			log::trace!("Sending block {block_index} with {nr_data_shards} data shards and {nr_parity_shards} parity shards.");
+
+			let mut shards = Vec::with_capacity(nr_data_shards + nr_parity_shards);
+			for (block_shard_index, data_shard_index) in (start..end).enumerate() {
+				// Determine which part of the payload is in this shard.
+				let start = data_shard_index * requested_shard_payload_size;
+				let end = ((data_shard_index + 1) * requested_shard_payload_size).min(packet_data.len());
+
+				let mut shard = Vec::with_capacity(requested_shard_size);
+
+				let rtp_header = RtpHeader {
+					header: 0x90, // What is this?
+					packet_type: 0,
+					sequence_number: self.sequence_number as u16,
+					timestamp,
+					ssrc: 0,
+				};
+				rtp_header.serialize(&mut shard);
+				shard.extend(PADDING.to_le_bytes());
+
+				let mut video_packet_header = NvVideoPacket {
+					stream_packet_index: self.sequence_number << 8,
+					frame_index: frame_number,
+					flags: RtpFlag::ContainsPicData as u8,
+					reserved: 0,
+					multi_fec_flags: 0x10,
+					multi_fec_blocks: multi_fec_blocks(block_index, last_block_index),
+					fec_info: fec_info(block_shard_index, nr_data_shards, fec_percentage),
+				};
+				if block_shard_index == 0 {
+					video_packet_header.flags |= RtpFlag::StartOfFrame as u8;
+				}
+				if block_shard_index == nr_data_shards - 1 {
+					video_packet_header.flags |= RtpFlag::EndOfFrame as u8;
+				}
+				video_packet_header.serialize(&mut shard);
+
+				// Append the payload.
+				shard.extend(&packet_data[start..end]);
+
+				// Pad with zeros at the end to make an equally sized shard.
+				shard.resize(requested_shard_size, 0);
+
+				shards.push(shard);
+
+				self.sequence_number += 1;
+			}
+
+			if let Some(encoder) = encoder {
+				for _ in 0..nr_parity_shards {
+					shards.push(vec![0u8; requested_shard_size]);
+				}
+
+				encoder.encode(&mut shards)
+					.map_err(|e| log::error!("Failed to encode packet as FEC shards: {e}"))?;
+
+				// Force these values for the parity shards, we don't need to reconstruct them, but Moonlight needs them to match with the frame they came from.
+				for (block_shard_index, shard) in shards[nr_data_shards..].iter_mut().enumerate() {
+					shard[0] = 0x90;
+					shard[2..4].copy_from_slice(&(self.sequence_number as u16).to_be_bytes());
+
+					let header = &mut shard[NV_VIDEO_PACKET_OFFSET..PAYLOAD_OFFSET];
+					header[4..8].copy_from_slice(&frame_number.to_le_bytes());
+					header[11] = multi_fec_blocks(block_index, last_block_index);
+					header[12..16].copy_from_slice(&fec_info(nr_data_shards + block_shard_index, nr_data_shards, fec_percentage).to_le_bytes());
+
+					self.sequence_number += 1;
+				}
+			}
+
+			output.extend(shards);
+		}
+
+		Ok(output)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use super::super::depacketizer::Depacketizer;
+
+	/// Size of the video packet header and payload, as requested by Moonlight by default.
+	const PACKET_SIZE: usize = 1024;
+	const SHARD_PAYLOAD_SIZE: usize = PACKET_SIZE - std::mem::size_of::<NvVideoPacket>();
+
+	/// Create a frame that fills `nr_data_shards` data shards, including the video frame header.
+	fn frame(nr_data_shards: usize) -> Vec<u8> {
+		(0..nr_data_shards * SHARD_PAYLOAD_SIZE - VideoFrameHeader::SIZE).map(|i| (i % 251) as u8).collect()
+	}
+
+	fn headers(shards: &[Vec<u8>]) -> Vec<NvVideoPacket> {
+		shards.iter().map(|shard| NvVideoPacket::from_shard(shard).unwrap()).collect()
+	}
+
+	/// Feed all shards for which `lost` returns false to a depacketizer, returns the reassembled frame.
+	fn depacketize(shards: &[Vec<u8>], lost: impl Fn(&NvVideoPacket) -> bool) -> Option<Vec<u8>> {
+		let mut depacketizer = Depacketizer::default();
+		let mut result = None;
+		for shard in shards {
+			if lost(&NvVideoPacket::from_shard(shard).unwrap()) {
+				continue;
+			}
+
+			if let Some(frame) = depacketizer.push(shard).unwrap() {
+				assert!(result.is_none(), "frame was completed twice");
+				result = Some(frame);
+			}
+		}
+
+		result
+	}
+
+	fn assert_frame(received: Option<Vec<u8>>, frame: &[u8]) {
+		let received = received.expect("frame was not reassembled");
+		assert_eq!(&received[..frame.len()], frame);
+		assert!(received[frame.len()..].iter().all(|&byte| byte == 0), "frame isn't padded with zeros");
+	}
+
+	fn block_index(header: &NvVideoPacket) -> usize {
+		((header.multi_fec_blocks >> 4) & 0x3) as usize
+	}
+
+	fn last_block_index(header: &NvVideoPacket) -> usize {
+		(header.multi_fec_blocks >> 6) as usize
+	}
+
+	fn shard_index(header: &NvVideoPacket) -> usize {
+		((header.fec_info >> 12) & 0x3FF) as usize
+	}
+
+	fn nr_data_shards(header: &NvVideoPacket) -> usize {
+		(header.fec_info >> 22) as usize
+	}
+
+	fn fec_percentage(header: &NvVideoPacket) -> usize {
+		((header.fec_info >> 4) & 0xFF) as usize
+	}
+
+	/// Number of parity shards in a block, computed from the header like Moonlight does.
+	fn nr_parity_shards(header: &NvVideoPacket) -> usize {
+		(nr_data_shards(header) * fec_percentage(header)).div_ceil(100)
+	}
+
+	#[test]
+	fn packs_multi_fec_blocks() {
+		assert_eq!(multi_fec_blocks(0, 0), 0x00);
+		assert_eq!(multi_fec_blocks(1, 3), 0xD0);
+		assert_eq!(multi_fec_blocks(3, 3), 0xF0);
+	}
+
+	#[test]
+	fn packs_fec_info() {
+		let info = fec_info(254, 1000, 255);
+		assert_eq!((info >> 12) & 0x3FF, 254);
+		assert_eq!(info >> 22, 1000);
+		assert_eq!((info >> 4) & 0xFF, 255);
+		assert_eq!(info & 0xF, 0);
+	}
+
+	#[test]
+	fn round_trip() {
+		let frame = frame(10);
+		let mut packetizer = Packetizer::new(PACKET_SIZE, 2, 100);
+		let shards = packetizer.packetize(&frame, true, 7, 1234, 20).unwrap();
+
+		// 10 data shards with 20% FEC gives 2 parity shards.
+		assert_eq!(shards.len(), 12);
+		assert!(shards.iter().all(|shard| shard.len() == PAYLOAD_OFFSET + SHARD_PAYLOAD_SIZE));
+		assert_eq!(packetizer.sequence_number(), 112);
+
+		let headers = headers(&shards);
+		for (index, header) in headers.iter().enumerate() {
+			assert_eq!(header.frame_index, 7);
+			assert_eq!(block_index(header), 0);
+			assert_eq!(last_block_index(header), 0);
+			assert_eq!(shard_index(header), index);
+			assert_eq!(nr_data_shards(header), 10);
+			assert_eq!(nr_parity_shards(header), 2);
+		}
+		assert_eq!(headers[0].flags, RtpFlag::ContainsPicData as u8 | RtpFlag::StartOfFrame as u8);
+		assert_eq!(headers[9].flags, RtpFlag::ContainsPicData as u8 | RtpFlag::EndOfFrame as u8);
+
+		assert_frame(depacketize(&shards, |_| false), &frame);
+	}
+
+	#[test]
+	fn recovers_lost_shards() {
+		let frame = frame(50);
+		let mut packetizer = Packetizer::new(PACKET_SIZE, 2, 0);
+		let shards = packetizer.packetize(&frame, false, 1, 0, 20).unwrap();
+		assert_eq!(shards.len(), 60);
+
+		// Every parity shard can replace a lost data shard.
+		assert_frame(depacketize(&shards, |header| shard_index(header) < 50 && shard_index(header).is_multiple_of(5)), &frame);
+
+		// But not more than that.
+		assert_eq!(depacketize(&shards, |header| shard_index(header) <= 10), None);
+	}
+
+	#[test]
+	fn minimum_fec_packets() {
+		// A single data shard with 20% FEC would have no parity shards, so the FEC percentage is raised.
+		let frame = frame(1);
+		let mut packetizer = Packetizer::new(PACKET_SIZE, 2, 0);
+		let shards = packetizer.packetize(&frame, false, 1, 0, 20).unwrap();
+		assert_eq!(shards.len(), 3);
+		assert!(headers(&shards).iter().all(|header| nr_parity_shards(header) == 2));
+
+		assert_frame(depacketize(&shards, |header| shard_index(header) == 0), &frame);
+	}
+
+	#[test]
+	fn parity_shards_match_fec_percentage() {
+		// Moonlight derives the number of parity shards from the percentage, so it must match what was sent for any block size.
+		let mut packetizer = Packetizer::new(PACKET_SIZE, 2, 0);
+		for nr_data_shards in [1, 2, 3, 7, 99, 100, 101, 151, 199, 212] {
+			let frame = frame(nr_data_shards);
+			let shards = packetizer.packetize(&frame, false, 1, 0, 20).unwrap();
+			let headers = headers(&shards);
+			assert_eq!(shards.len(), nr_data_shards + nr_parity_shards(&headers[0]), "{nr_data_shards} data shards");
+			assert!(shards.len() <= MAX_SHARDS);
+
+			// Lose as many data shards as there are parity shards.
+			assert_frame(depacketize(&shards, |header| shard_index(header) < nr_parity_shards(header)), &frame);
+		}
+	}
+
+	#[test]
+	fn multiple_blocks() {
+		// At 20% FEC a block contains 212 data shards and 43 parity shards.
+		let frame = frame(212 * 2 + 10);
+		let mut packetizer = Packetizer::new(PACKET_SIZE, 2, 0);
+		let shards = packetizer.packetize(&frame, false, 1, 0, 20).unwrap();
+		let headers = headers(&shards);
+
+		let nr_shards = |block: usize| headers.iter().filter(|header| block_index(header) == block).count();
+		assert_eq!((nr_shards(0), nr_shards(1), nr_shards(2)), (255, 255, 12));
+		assert!(headers.iter().all(|header| last_block_index(header) == 2));
+
+		// Lose shards in every block.
+		assert_frame(depacketize(&shards, |header| shard_index(header) < 2), &frame);
+	}
+
+	#[test]
+	fn remaining_data_is_sent_without_fec_in_last_block() {
+		// Six blocks worth of data, but only four blocks can be sent.
+		let frame = frame(212 * 6);
+		let mut packetizer = Packetizer::new(PACKET_SIZE, 2, 0);
+		let shards = packetizer.packetize(&frame, false, 1, 0, 20).unwrap();
+		let headers = headers(&shards);
+
+		assert_eq!(shards.len(), 3 * 255 + 212 * 3);
+		assert!(headers.iter().all(|header| last_block_index(header) == MAX_BLOCKS - 1));
+
+		let last_block: Vec<_> = headers.iter().filter(|header| block_index(header) == MAX_BLOCKS - 1).collect();
+		assert_eq!(last_block.len(), 212 * 3);
+		for (index, header) in last_block.iter().enumerate() {
+			assert_eq!(shard_index(header), index);
+			assert_eq!(nr_data_shards(header), 212 * 3);
+			assert_eq!(fec_percentage(header), 0);
+		}
+
+		assert_frame(depacketize(&shards, |_| false), &frame);
+
+		// The first blocks can still recover lost shards, the last block can't.
+		assert_frame(depacketize(&shards, |header| block_index(header) < MAX_BLOCKS - 1 && shard_index(header) == 0), &frame);
+		assert_eq!(depacketize(&shards, |header| block_index(header) == MAX_BLOCKS - 1 && shard_index(header) == 0), None);
+	}
+}
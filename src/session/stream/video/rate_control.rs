@@ -0,0 +1,306 @@
+use std::time::{Duration, Instant};
+
+/// Loss ratio (lost frames / sent frames) above which the bitrate is lowered.
+const LOSS_THRESHOLD: f64 = 0.02;
+
+/// Factor with which the bitrate is multiplied when loss is detected.
+const DECREASE_FACTOR: f64 = 0.8;
+
+/// Factor with which the bitrate is multiplied when the link has been clean for a while.
+const INCREASE_FACTOR: f64 = 1.05;
+
+/// Minimum time between two consecutive decreases, to give the new bitrate a chance to take effect.
+const DECREASE_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Time the link needs to be free of loss before the bitrate is raised again.
+const INCREASE_INTERVAL: Duration = Duration::from_secs(2);
+
+/// The bitrate never drops below the maximum bitrate divided by this value.
+const MINIMUM_BITRATE_DIVISOR: usize = 10;
+
+/// Amount with which the FEC percentage changes on every step.
+const FEC_PERCENTAGE_STEP: u8 = 10;
+
+/// Upper limit for the FEC percentage.
+const MAXIMUM_FEC_PERCENTAGE: u8 = 50;
+
+/// Interval at which the rate controller checks whether the bitrate can be raised again.
+pub const TIMER_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Settings of the video stream that can change while streaming.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct StreamSettings {
+	/// Target bitrate of the encoder in bits per second.
+	pub bitrate: usize,
+
+	/// What percentage of data packets should be parity packets.
+	pub fec_percentage: u8,
+}
+
+/// Loss statistics as periodically reported by the client.
+#[derive(Debug)]
+pub struct LossStats {
+	/// Number of frames that were lost since the previous report.
+	pub loss_count: u32,
+
+	/// Time between two reports.
+	pub interval: Duration,
+
+	/// Index of the last frame that was received correctly.
+	pub last_good_frame: u64,
+}
+
+/// Adjusts the bitrate and FEC percentage based on the loss reported by the client.
+///
+/// Loss lowers the bitrate quickly, while it is raised slowly when the link is clean,
+/// up to the maximum bitrate requested by the client.
+///
+/// Loss is reported through loss statistics (which only older clients send), through requests for IDR frames
+/// and through frames that were dropped before sending. The link is clean when none of these happened for a while.
+pub struct RateController {
+	maximum_bitrate: usize,
+	minimum_fec_percentage: u8,
+	framerate: u32,
+	settings: StreamSettings,
+	last_change: Instant,
+	last_loss: Instant,
+}
+
+impl RateController {
+	pub fn new(maximum_bitrate: usize, fec_percentage: u8, framerate: u32) -> Self {
+		let now = Instant::now();
+		Self {
+			maximum_bitrate,
+			minimum_fec_percentage: fec_percentage,
+			framerate,
+			settings: StreamSettings { bitrate: maximum_bitrate, fec_percentage },
+			last_change: now,
+			last_loss: now,
+		}
+	}
+
+	pub fn settings(&self) -> StreamSettings {
+		self.settings
+	}
+
+	/// Process a loss report, returns the new settings if they changed.
+	pub fn on_loss_stats(&mut self, stats: &LossStats, now: Instant) -> Option<StreamSettings> {
+		let expected_frames = (self.framerate as f64 * stats.interval.as_secs_f64()).max(1.0);
+		let loss_ratio = stats.loss_count as f64 / expected_frames;
+		log::trace!("Client lost {} frames ({:.1}%), last good frame is {}.", stats.loss_count, loss_ratio * 100.0, stats.last_good_frame);
+
+		if stats.loss_count == 0 {
+			return self.try_increase(now);
+		}
+
+		self.last_loss = now;
+		if loss_ratio < LOSS_THRESHOLD || now - self.last_change < DECREASE_INTERVAL {
+			return None;
+		}
+
+		let settings = self.decreased_settings();
+		log::info!("Client reported {:.1}% frame loss, lowering bitrate to {} kbps with {}% FEC.", loss_ratio * 100.0, settings.bitrate / 1024, settings.fec_percentage);
+
+		self.update(settings, now)
+	}
+
+	/// Process a request of the client for an IDR frame, which it sends when it lost frames.
+	pub fn on_lost_frames(&mut self, now: Instant) -> Option<StreamSettings> {
+		self.last_loss = now;
+		if now - self.last_change < DECREASE_INTERVAL {
+			return None;
+		}
+
+		let settings = self.decreased_settings();
+		log::info!("Client lost frames, lowering bitrate to {} kbps with {}% FEC.", settings.bitrate / 1024, settings.fec_percentage);
+
+		self.update(settings, now)
+	}
+
+	/// Process frames that were dropped before sending because the network couldn't keep up, this is treated like loss.
+	pub fn on_dropped_frames(&mut self, dropped_frames: u32, now: Instant) -> Option<StreamSettings> {
+		self.last_loss = now;
+		if now - self.last_change < DECREASE_INTERVAL {
+			return None;
+		}
+
+		// More FEC would only add to the congestion, so only the bitrate is lowered.
+		let settings = StreamSettings {
+			bitrate: self.decreased_bitrate(),
+			fec_percentage: self.settings.fec_percentage,
+		};
+		log::info!("Dropped {dropped_frames} frame(s) due to congestion, lowering bitrate to {} kbps.", settings.bitrate / 1024);
+
+		self.update(settings, now)
+	}
+
+	/// Called every `TIMER_INTERVAL`, raises the bitrate if no loss was reported for a while.
+	pub fn on_timer(&mut self, now: Instant) -> Option<StreamSettings> {
+		self.try_increase(now)
+	}
+
+	/// Raise the bitrate (and lower the FEC percentage) if there was no loss for a while.
+	fn try_increase(&mut self, now: Instant) -> Option<StreamSettings> {
+		if now - self.last_loss < INCREASE_INTERVAL || now - self.last_change < INCREASE_INTERVAL {
+			return None;
+		}
+
+		let settings = StreamSettings {
+			bitrate: ((self.settings.bitrate as f64 * INCREASE_FACTOR) as usize).min(self.maximum_bitrate),
+			fec_percentage: self.settings.fec_percentage.saturating_sub(FEC_PERCENTAGE_STEP).max(self.minimum_fec_percentage),
+		};
+		if settings != self.settings {
+			log::debug!("No loss reported recently, raising bitrate to {} kbps with {}% FEC.", settings.bitrate / 1024, settings.fec_percentage);
+		}
+
+		self.update(settings, now)
+	}
+
+	/// The current settings with the bitrate lowered and the FEC percentage raised by one step.
+	fn decreased_settings(&self) -> StreamSettings {
+		StreamSettings {
+			bitrate: self.decreased_bitrate(),
+			fec_percentage: (self.settings.fec_percentage + FEC_PERCENTAGE_STEP).min(MAXIMUM_FEC_PERCENTAGE.max(self.minimum_fec_percentage)),
+		}
+	}
+
+	/// The current bitrate lowered by one step, but not below the minimum bitrate.
+	fn decreased_bitrate(&self) -> usize {
+		let minimum_bitrate = self.maximum_bitrate / MINIMUM_BITRATE_DIVISOR;
+		((self.settings.bitrate as f64 * DECREASE_FACTOR) as usize).max(minimum_bitrate)
+	}
+
+	fn update(&mut self, settings: StreamSettings, now: Instant) -> Option<StreamSettings> {
+		if settings == self.settings {
+			return None;
+		}
+
+		self.settings = settings;
+		self.last_change = now;
+		Some(settings)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const MAXIMUM_BITRATE: usize = 10_000_000;
+
+	fn loss(loss_count: u32) -> LossStats {
+		// 60 fps with a report every 50ms means 3 frames per report.
+		LossStats { loss_count, interval: Duration::from_millis(50), last_good_frame: 0 }
+	}
+
+	/// A controller for 60 fps with 20% FEC, and a time after which the first change is allowed.
+	fn controller() -> (RateController, Instant) {
+		let controller = RateController::new(MAXIMUM_BITRATE, 20, 60);
+		(controller, Instant::now() + DECREASE_INTERVAL)
+	}
+
+	#[test]
+	fn loss_lowers_bitrate_and_raises_fec() {
+		let (mut controller, start) = controller();
+		let settings = controller.on_loss_stats(&loss(1), start).unwrap();
+		assert_eq!(settings, StreamSettings { bitrate: 8_000_000, fec_percentage: 30 });
+		assert_eq!(controller.settings(), settings);
+	}
+
+	#[test]
+	fn loss_below_threshold_is_ignored() {
+		let mut controller = RateController::new(MAXIMUM_BITRATE, 20, 1000);
+		let start = Instant::now() + DECREASE_INTERVAL;
+
+		// 1 frame of 1000 frames per second for 1 second is 0.1% loss.
+		let stats = LossStats { loss_count: 1, interval: Duration::from_secs(1), last_good_frame: 0 };
+		assert_eq!(controller.on_loss_stats(&stats, start), None);
+		assert_eq!(controller.settings().bitrate, MAXIMUM_BITRATE);
+	}
+
+	#[test]
+	fn decreases_wait_for_the_decrease_interval() {
+		let (mut controller, start) = controller();
+		assert!(controller.on_loss_stats(&loss(1), start).is_some());
+		assert_eq!(controller.on_loss_stats(&loss(1), start + DECREASE_INTERVAL / 2), None);
+		assert_eq!(controller.on_lost_frames(start + DECREASE_INTERVAL / 2), None);
+
+		let settings = controller.on_lost_frames(start + DECREASE_INTERVAL).unwrap();
+		assert_eq!(settings, StreamSettings { bitrate: 6_400_000, fec_percentage: 40 });
+	}
+
+	#[test]
+	fn dropped_frames_only_lower_bitrate() {
+		let (mut controller, start) = controller();
+		let settings = controller.on_dropped_frames(2, start).unwrap();
+		assert_eq!(settings, StreamSettings { bitrate: 8_000_000, fec_percentage: 20 });
+		assert_eq!(controller.on_dropped_frames(2, start + DECREASE_INTERVAL / 2), None);
+	}
+
+	#[test]
+	fn bitrate_recovers_after_the_increase_interval() {
+		let (mut controller, start) = controller();
+		controller.on_loss_stats(&loss(1), start).unwrap();
+
+		// Clean reports or timers don't raise the bitrate until the link was clean for long enough.
+		assert_eq!(controller.on_loss_stats(&loss(0), start + INCREASE_INTERVAL / 2), None);
+		assert_eq!(controller.on_timer(start + INCREASE_INTERVAL / 2), None);
+
+		let settings = controller.on_timer(start + INCREASE_INTERVAL).unwrap();
+		assert_eq!(settings, StreamSettings { bitrate: 8_400_000, fec_percentage: 20 });
+
+		// Every step waits for the increase interval again.
+		assert_eq!(controller.on_timer(start + INCREASE_INTERVAL + TIMER_INTERVAL), None);
+		assert!(controller.on_timer(start + INCREASE_INTERVAL * 2).is_some());
+	}
+
+	#[test]
+	fn loss_delays_recovery() {
+		let (mut controller, start) = controller();
+		controller.on_dropped_frames(1, start).unwrap();
+
+		// Loss that is too soon to lower the bitrate again still restarts the wait for a clean link.
+		let loss = start + DECREASE_INTERVAL / 2;
+		assert_eq!(controller.on_lost_frames(loss), None);
+		assert_eq!(controller.on_timer(start + INCREASE_INTERVAL), None);
+		assert!(controller.on_timer(loss + INCREASE_INTERVAL).is_some());
+	}
+
+	#[test]
+	fn bitrate_stays_between_minimum_and_maximum() {
+		let (mut controller, start) = controller();
+		let mut now = start;
+		for _ in 0..50 {
+			controller.on_dropped_frames(1, now);
+			now += DECREASE_INTERVAL;
+		}
+		assert_eq!(controller.settings().bitrate, MAXIMUM_BITRATE / MINIMUM_BITRATE_DIVISOR);
+
+		for _ in 0..100 {
+			now += INCREASE_INTERVAL;
+			controller.on_timer(now);
+		}
+		assert_eq!(controller.settings().bitrate, MAXIMUM_BITRATE);
+	}
+
+	#[test]
+	fn fec_stays_between_configured_and_maximum_percentage() {
+		let (mut controller, start) = controller();
+		let mut now = start;
+		for _ in 0..10 {
+			controller.on_lost_frames(now);
+			now += DECREASE_INTERVAL;
+		}
+		assert_eq!(controller.settings().fec_percentage, MAXIMUM_FEC_PERCENTAGE);
+
+		for _ in 0..10 {
+			now += INCREASE_INTERVAL;
+			controller.on_timer(now);
+		}
+		assert_eq!(controller.settings().fec_percentage, 20);
+
+		// A configured percentage above the maximum is never lowered.
+		let mut controller = RateController::new(MAXIMUM_BITRATE, 80, 60);
+		let start = Instant::now() + DECREASE_INTERVAL;
+		assert_eq!(controller.on_lost_frames(start).unwrap().fec_percentage, 80);
+	}
+}
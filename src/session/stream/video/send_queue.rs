@@ -0,0 +1,206 @@
+use std::time::{Duration, Instant};
+
+/// Number of frames that can wait to be sent, the encoder drops frames when the queue is full.
+pub const FRAME_QUEUE_SIZE: usize = 16;
+
+/// Frames that waited longer than this to be sent are dropped, to keep latency bounded when the network is congested.
+const MAX_QUEUE_DELAY: Duration = Duration::from_millis(100);
+
+/// An encoded frame, split in to shards that are ready to be sent.
+pub struct PacketizedFrame {
+	pub frame_number: u32,
+	pub key_frame: bool,
+	pub shards: Vec<Vec<u8>>,
+
+	/// Time at which the frame was captured.
+	pub captured_at: Instant,
+
+	/// Time at which the frame left the encoder.
+	pub encoded_at: Instant,
+
+	/// Time between two frames, sending is paced within a fraction of this interval.
+	pub frame_interval: Duration,
+}
+
+/// What to do with a frame that is taken from the send queue.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Admission {
+	/// Send the frame to the client.
+	Send,
+
+	/// Drop the frame, because it references a frame that was dropped before.
+	Drop,
+
+	/// Drop the frame and recover with an IDR frame, `dropped_frames` frames were dropped since the last frame that was sent.
+	Recover { dropped_frames: u32 },
+}
+
+/// Decides which frames in the send queue are sent.
+///
+/// Frames that waited too long are dropped, as are frames that the encoder couldn't queue.
+/// Once a frame is dropped, every frame up to the next key frame is dropped too, because the client can't decode them.
+#[derive(Default)]
+pub struct SendQueue {
+	/// Number of the frame that is expected next, a gap means the encoder dropped frames.
+	next_frame_number: Option<u32>,
+
+	/// Set after a frame was dropped, until a key frame is sent.
+	waiting_for_key_frame: bool,
+
+	/// Total number of frames that were dropped, including frames that the encoder couldn't queue.
+	dropped_frames: u64,
+}
+
+impl SendQueue {
+	/// Total number of frames that were dropped so far.
+	pub fn dropped_frames(&self) -> u64 {
+		self.dropped_frames
+	}
+
+	pub fn admit(&mut self, frame: &PacketizedFrame, now: Instant) -> Admission {
+		let admission = self.admit_frame(frame, now);
+		if admission != Admission::Send {
+			self.dropped_frames += 1;
+		}
+
+		admission
+	}
+
+	fn admit_frame(&mut self, frame: &PacketizedFrame, now: Instant) -> Admission {
+		let skipped_frames = match self.next_frame_number {
+			Some(next_frame_number) => frame.frame_number.wrapping_sub(next_frame_number),
+			None => 0,
+		};
+		self.next_frame_number = Some(frame.frame_number.wrapping_add(1));
+		self.dropped_frames += skipped_frames as u64;
+
+		let queue_delay = now.saturating_duration_since(frame.encoded_at);
+		if queue_delay > MAX_QUEUE_DELAY {
+			log::debug!("Frame {} waited {}ms to be sent, dropping it.", frame.frame_number, queue_delay.as_millis());
+
+			// A dropped key frame means the IDR frame we were waiting for is lost as well, so ask for another.
+			if !self.waiting_for_key_frame || frame.key_frame {
+				self.waiting_for_key_frame = true;
+				return Admission::Recover { dropped_frames: skipped_frames + 1 };
+			}
+			return Admission::Drop;
+		}
+
+		if skipped_frames > 0 && !frame.key_frame && !self.waiting_for_key_frame {
+			log::debug!("Send queue was full for {skipped_frames} frames, dropping frames until the next key frame.");
+			self.waiting_for_key_frame = true;
+			return Admission::Recover { dropped_frames: skipped_frames + 1 };
+		}
+
+		if self.waiting_for_key_frame && !frame.key_frame {
+			log::trace!("Dropping frame {} while waiting for a key frame.", frame.frame_number);
+			return Admission::Drop;
+		}
+
+		self.waiting_for_key_frame = false;
+		Admission::Send
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn frame(frame_number: u32, key_frame: bool, encoded_at: Instant) -> PacketizedFrame {
+		PacketizedFrame {
+			frame_number,
+			key_frame,
+			shards: Vec::new(),
+			captured_at: encoded_at,
+			encoded_at,
+			frame_interval: Duration::from_millis(16),
+		}
+	}
+
+	/// Admit a frame that didn't wait in the queue.
+	fn admit(queue: &mut SendQueue, frame_number: u32, key_frame: bool) -> Admission {
+		let now = Instant::now();
+		queue.admit(&frame(frame_number, key_frame, now), now)
+	}
+
+	/// Admit a frame that waited longer than the maximum queue delay.
+	fn admit_late(queue: &mut SendQueue, frame_number: u32, key_frame: bool) -> Admission {
+		let now = Instant::now();
+		queue.admit(&frame(frame_number, key_frame, now), now + MAX_QUEUE_DELAY + Duration::from_millis(1))
+	}
+
+	#[test]
+	fn sends_consecutive_frames() {
+		let mut queue = SendQueue::default();
+		assert_eq!(admit(&mut queue, 5, true), Admission::Send);
+		for frame_number in 6..20 {
+			assert_eq!(admit(&mut queue, frame_number, false), Admission::Send);
+		}
+		assert_eq!(queue.dropped_frames(), 0);
+	}
+
+	#[test]
+	fn late_frame_drops_until_key_frame() {
+		let mut queue = SendQueue::default();
+		assert_eq!(admit(&mut queue, 1, true), Admission::Send);
+		assert_eq!(admit_late(&mut queue, 2, false), Admission::Recover { dropped_frames: 1 });
+
+		// Frames that reference the dropped frame can't be decoded, even if they are on time.
+		assert_eq!(admit(&mut queue, 3, false), Admission::Drop);
+		assert_eq!(admit_late(&mut queue, 4, false), Admission::Drop);
+		assert_eq!(admit(&mut queue, 5, true), Admission::Send);
+		assert_eq!(admit(&mut queue, 6, false), Admission::Send);
+		assert_eq!(queue.dropped_frames(), 3);
+	}
+
+	#[test]
+	fn dropped_key_frame_recovers_again() {
+		let mut queue = SendQueue::default();
+		assert_eq!(admit(&mut queue, 1, true), Admission::Send);
+		assert_eq!(admit_late(&mut queue, 2, false), Admission::Recover { dropped_frames: 1 });
+
+		// The key frame that was requested is late too, so another one is needed.
+		assert_eq!(admit_late(&mut queue, 3, true), Admission::Recover { dropped_frames: 1 });
+		assert_eq!(admit(&mut queue, 4, false), Admission::Drop);
+		assert_eq!(admit(&mut queue, 5, true), Admission::Send);
+		assert_eq!(queue.dropped_frames(), 3);
+	}
+
+	#[test]
+	fn gap_drops_until_key_frame() {
+		let mut queue = SendQueue::default();
+		assert_eq!(admit(&mut queue, 1, true), Admission::Send);
+
+		// Frames 2 to 4 didn't fit in the queue.
+		assert_eq!(admit(&mut queue, 5, false), Admission::Recover { dropped_frames: 4 });
+		assert_eq!(admit(&mut queue, 6, false), Admission::Drop);
+
+		// Another gap while waiting doesn't ask for another key frame.
+		assert_eq!(admit(&mut queue, 9, false), Admission::Drop);
+		assert_eq!(admit(&mut queue, 10, true), Admission::Send);
+		assert_eq!(queue.dropped_frames(), 8);
+	}
+
+	#[test]
+	fn gap_before_key_frame_is_sent() {
+		let mut queue = SendQueue::default();
+		assert_eq!(admit(&mut queue, 1, true), Admission::Send);
+
+		// The frames in the gap aren't needed to decode a key frame.
+		assert_eq!(admit(&mut queue, 4, true), Admission::Send);
+		assert_eq!(admit(&mut queue, 5, false), Admission::Send);
+		assert_eq!(queue.dropped_frames(), 2);
+	}
+
+	#[test]
+	fn frame_numbers_wrap_around() {
+		let mut queue = SendQueue::default();
+		assert_eq!(admit(&mut queue, u32::MAX - 1, true), Admission::Send);
+		assert_eq!(admit(&mut queue, u32::MAX, false), Admission::Send);
+		assert_eq!(admit(&mut queue, 0, false), Admission::Send);
+		assert_eq!(queue.dropped_frames(), 0);
+
+		assert_eq!(admit(&mut queue, 2, false), Admission::Recover { dropped_frames: 2 });
+		assert_eq!(queue.dropped_frames(), 2);
+	}
+}
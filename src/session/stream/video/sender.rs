@@ -0,0 +1,254 @@
+use std::{
+	net::SocketAddr,
+	os::fd::{AsRawFd, RawFd},
+	time::{Duration, Instant},
+};
+
+use tokio::{io::Interest, net::UdpSocket};
+
+use crate::config::VideoStreamConfig;
+
+use super::send_queue::PacketizedFrame;
+
+/// Number of shards that can be sent back to back when pacing.
+const BURST_SHARDS: usize = 4;
+
+/// Resolution of the timer that paces the shards, sleeps are never shorter than this.
+const TIMER_RESOLUTION: Duration = Duration::from_millis(1);
+
+/// Sends the shards of a frame in batches with `sendmmsg`, paced over a fraction of the frame interval.
+///
+/// Without pacing a large key frame leaves as a single burst at line rate, which can overflow the buffers of switches and Wi-Fi bridges.
+pub struct ShardSender {
+	/// Maximum number of shards sent with a single system call.
+	batch_size: usize,
+
+	/// Fraction of the frame interval over which the shards of a frame are spread, 0 disables pacing.
+	pacing: f64,
+
+	bucket: TokenBucket,
+}
+
+impl ShardSender {
+	pub fn new(config: &VideoStreamConfig) -> Self {
+		Self {
+			batch_size: config.send_batch_size.max(1),
+			pacing: config.pacing.clamp(0.0, 1.0),
+			bucket: TokenBucket::new(),
+		}
+	}
+
+	/// Send all shards of a frame to `address`.
+	pub async fn send(&mut self, socket: &UdpSocket, address: SocketAddr, frame: &PacketizedFrame) -> std::io::Result<()> {
+		let address = RawAddress::new(address);
+
+		let mut batch_size = self.batch_size;
+		if self.pacing > 0.0 {
+			// Send the frame at the rate that spreads it over the pacing window, allowing a burst of a few shards.
+			let frame_size: usize = frame.shards.iter().map(Vec::len).sum();
+			let shard_size = frame.shards.first().map(Vec::len).unwrap_or_default();
+			let window = frame.frame_interval.as_secs_f64() * self.pacing;
+			let rate = frame_size as f64 / window.max(f64::EPSILON);
+			let burst_size = burst_size(rate, shard_size);
+			self.bucket.configure(rate, burst_size, Instant::now());
+
+			// A batch leaves as a single burst, so it can't be larger than the bucket.
+			batch_size = batch_size.min((burst_size as usize / shard_size.max(1)).max(1));
+		}
+
+		for batch in frame.shards.chunks(batch_size) {
+			if self.pacing > 0.0 {
+				let wait = self.bucket.reserve(batch.iter().map(Vec::len).sum(), Instant::now());
+				if !wait.is_zero() {
+					tokio::time::sleep(wait).await;
+				}
+			}
+
+			// The kernel may accept only part of a batch, so keep going until everything is sent.
+			let mut remaining = batch;
+			while !remaining.is_empty() {
+				let sent = socket.async_io(Interest::WRITABLE, || send_batch(socket.as_raw_fd(), remaining, &address)).await?;
+				remaining = &remaining[sent..];
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Number of bytes that can be sent back to back at the given rate (in bytes per second).
+///
+/// This is a few shards, but at high rates the timer can't wake up often enough for that,
+/// so then it is whatever is sent at that rate between two ticks of the timer.
+fn burst_size(rate: f64, shard_size: usize) -> f64 {
+	((BURST_SHARDS * shard_size) as f64).max(rate * TIMER_RESOLUTION.as_secs_f64())
+}
+
+/// Limits the rate at which bytes are sent, while allowing bursts up to the capacity of the bucket.
+struct TokenBucket {
+	/// Rate in bytes per second at which tokens are added.
+	rate: f64,
+
+	/// Maximum number of tokens in the bucket.
+	capacity: f64,
+
+	tokens: f64,
+	last_refill: Instant,
+}
+
+impl TokenBucket {
+	fn new() -> Self {
+		Self { rate: 0.0, capacity: 0.0, tokens: 0.0, last_refill: Instant::now() }
+	}
+
+	fn configure(&mut self, rate: f64, capacity: f64, now: Instant) {
+		self.refill(now);
+		self.rate = rate;
+		self.capacity = capacity;
+		self.tokens = self.tokens.min(capacity);
+	}
+
+	fn refill(&mut self, now: Instant) {
+		let elapsed = now.saturating_duration_since(self.last_refill);
+		self.tokens = (self.tokens + elapsed.as_secs_f64() * self.rate).min(self.capacity);
+		self.last_refill = self.last_refill.max(now);
+	}
+
+	/// Take `amount` tokens from the bucket, returns how long to wait before they may be sent.
+	///
+	/// The bucket goes in to debt until the tokens are available, which delays the next batch.
+	fn reserve(&mut self, amount: usize, now: Instant) -> Duration {
+		self.refill(now);
+		self.tokens -= amount as f64;
+
+		if self.tokens >= 0.0 || self.rate <= 0.0 {
+			return Duration::ZERO;
+		}
+		Duration::from_secs_f64(-self.tokens / self.rate)
+	}
+}
+
+/// A socket address in the representation of the C library.
+enum RawAddress {
+	V4(libc::sockaddr_in),
+	V6(libc::sockaddr_in6),
+}
+
+impl RawAddress {
+	fn new(address: SocketAddr) -> Self {
+		match address {
+			SocketAddr::V4(address) => Self::V4(libc::sockaddr_in {
+				sin_family: libc::AF_INET as libc::sa_family_t,
+				sin_port: address.port().to_be(),
+				sin_addr: libc::in_addr { s_addr: u32::from_ne_bytes(address.ip().octets()) },
+				sin_zero: [0; 8],
+			}),
+			SocketAddr::V6(address) => Self::V6(libc::sockaddr_in6 {
+				sin6_family: libc::AF_INET6 as libc::sa_family_t,
+				sin6_port: address.port().to_be(),
+				sin6_flowinfo: address.flowinfo(),
+				sin6_addr: libc::in6_addr { s6_addr: address.ip().octets() },
+				sin6_scope_id: address.scope_id(),
+			}),
+		}
+	}
+
+	fn as_raw(&self) -> (*const libc::c_void, libc::socklen_t) {
+		match self {
+			Self::V4(address) => (address as *const _ as *const _, std::mem::size_of::<libc::sockaddr_in>() as libc::socklen_t),
+			Self::V6(address) => (address as *const _ as *const _, std::mem::size_of::<libc::sockaddr_in6>() as libc::socklen_t),
+		}
+	}
+}
+
+/// Send every shard as a separate datagram with a single system call, returns the number of shards that were sent.
+fn send_batch(socket: RawFd, shards: &[Vec<u8>], address: &RawAddress) -> std::io::Result<usize> {
+	let (name, name_length) = address.as_raw();
+
+	let mut buffers: Vec<libc::iovec> = shards.iter()
+		.map(|shard| libc::iovec { iov_base: shard.as_ptr() as *mut _, iov_len: shard.len() })
+		.collect();
+	let mut messages: Vec<libc::mmsghdr> = buffers.iter_mut()
+		.map(|buffer| {
+			// Not every field can be set with a struct literal on every target, so start from zeroed memory.
+			let mut message: libc::mmsghdr = unsafe { std::mem::zeroed() };
+			message.msg_hdr.msg_name = name as *mut _;
+			message.msg_hdr.msg_namelen = name_length;
+			message.msg_hdr.msg_iov = buffer;
+			message.msg_hdr.msg_iovlen = 1;
+			message
+		})
+		.collect();
+
+	let result = unsafe { libc::sendmmsg(socket, messages.as_mut_ptr(), messages.len() as libc::c_uint, 0) };
+	if result < 0 {
+		return Err(std::io::Error::last_os_error());
+	}
+
+	Ok(result as usize)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn assert_wait(wait: Duration, expected_ms: f64) {
+		assert!((wait.as_secs_f64() * 1000.0 - expected_ms).abs() < 1e-6, "waited {wait:?}, expected {expected_ms}ms");
+	}
+
+	#[test]
+	fn burst_is_a_few_shards() {
+		assert_eq!(burst_size(1_000_000.0, 1024), (BURST_SHARDS * 1024) as f64);
+	}
+
+	#[test]
+	fn burst_covers_timer_resolution() {
+		// At 1 GB/s a millisecond is a megabyte, the timer can't pace smaller bursts than that.
+		assert_eq!(burst_size(1_000_000_000.0, 1024), 1_000_000.0);
+	}
+
+	#[test]
+	fn fills_up_to_capacity() {
+		let mut bucket = TokenBucket::new();
+		let start = Instant::now();
+		bucket.configure(1000.0, 100.0, start);
+
+		// Idle time doesn't add more tokens than the capacity.
+		let now = start + Duration::from_secs(10);
+		assert_wait(bucket.reserve(100, now), 0.0);
+		assert_wait(bucket.reserve(50, now), 50.0);
+	}
+
+	#[test]
+	fn debt_delays_next_batch() {
+		let mut bucket = TokenBucket::new();
+		let start = Instant::now();
+		bucket.configure(1000.0, 100.0, start);
+
+		// A batch larger than the available tokens waits for the missing tokens.
+		assert_wait(bucket.reserve(100, start), 100.0);
+
+		// Halfway, the debt isn't paid yet.
+		assert_wait(bucket.reserve(10, start + Duration::from_millis(50)), 60.0);
+
+		// Sleeping longer than needed isn't lost, as long as it fits in the bucket.
+		assert_wait(bucket.reserve(30, start + Duration::from_millis(150)), 0.0);
+		assert_wait(bucket.reserve(10, start + Duration::from_millis(150)), 0.0);
+		assert_wait(bucket.reserve(10, start + Duration::from_millis(150)), 10.0);
+	}
+
+	#[test]
+	fn reconfigure_keeps_tokens_within_capacity() {
+		let mut bucket = TokenBucket::new();
+		let start = Instant::now();
+		bucket.configure(1000.0, 100.0, start);
+		bucket.configure(1000.0, 20.0, start + Duration::from_secs(1));
+		assert_wait(bucket.reserve(40, start + Duration::from_secs(1)), 20.0);
+	}
+
+	#[test]
+	fn zero_rate_doesnt_wait() {
+		let mut bucket = TokenBucket::new();
+		assert_wait(bucket.reserve(100, Instant::now()), 0.0);
+	}
+}
@@ -8,6 +8,8 @@ enum StateCommand {
 	Save(PathBuf, oneshot::Sender<Result<(), ()>>),
 	HasClient(String, oneshot::Sender<bool>),
 	AddClient(String),
+	HasCertificate(String, oneshot::Sender<bool>),
+	AddCertificate(String),
 	// RemoveClient(String, oneshot::Sender<bool>),
 }
 
@@ -79,6 +81,20 @@ impl State {
 			.map_err(|e| log::error!("Failed to send AddClient command: {e}"))
 	}
 
+	pub async fn has_certificate(&self, fingerprint: String) -> Result<bool, ()> {
+		let (result_tx, result_rx) = oneshot::channel();
+		self.command_tx.send(StateCommand::HasCertificate(fingerprint, result_tx)).await
+			.map_err(|e| log::error!("Failed to send HasCertificate command: {e}"))?;
+		result_rx.await.map_err(|e| log::error!("Failed to receive HasCertificate response: {e}"))
+	}
+
+	pub async fn add_certificate(&self, fingerprint: String) -> Result<(), ()> {
+		self.command_tx.send(StateCommand::AddCertificate(fingerprint)).await
+			.map_err(|e| log::error!("Failed to send AddCertificate command: {e}"))?;
+
+		self.save().await
+	}
+
 	// pub async fn remove_client(&self, client: String) -> Result<bool, ()> {
 	// 	let (result_tx, result_rx) = oneshot::channel();
 	// 	self.command_tx.send(StateCommand::RemoveClient(client, result_tx)).await
@@ -95,11 +111,15 @@ impl State {
 struct StateInner {
 	unique_id: String,
 	clients: Vec<String>,
+
+	/// SHA-256 fingerprints of the certificates of paired clients.
+	#[serde(default)]
+	certificates: Vec<String>,
 }
 
 impl StateInner {
 	fn new() -> Self {
-		Self { unique_id: uuid::Uuid::new_v4().to_string(), clients: Default::default() }
+		Self { unique_id: uuid::Uuid::new_v4().to_string(), clients: Default::default(), certificates: Default::default() }
 	}
 
 	async fn run(mut self, mut command_rx: mpsc::Receiver<StateCommand>) {
@@ -129,6 +149,18 @@ impl StateInner {
 					let _ = self.add_client(client);
 				},
 
+				StateCommand::HasCertificate(fingerprint, result_tx) => {
+					if result_tx.send(self.certificates.contains(&fingerprint)).is_err() {
+						log::error!("Failed to send HasCertificate result.");
+					}
+				},
+
+				StateCommand::AddCertificate(fingerprint) => {
+					if !self.certificates.contains(&fingerprint) {
+						self.certificates.push(fingerprint);
+					}
+				},
+
 				// StateCommand::RemoveClient(client, result_tx) => {
 				// 	if result_tx.send(self.remove_client(client)).is_err() {
 				// 		log::error!("Failed to send RemoveClient result.");
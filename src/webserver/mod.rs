@@ -9,7 +9,12 @@ use network_interface::NetworkInterfaceConfig;
 use openssl::x509::X509;
 use tokio::net::TcpListener;
 
-use crate::{config::Config, clients::ClientManager, webserver::tls::TlsAcceptor, session::{manager::SessionManager, SessionContext, SessionKeys}};
+use crate::{
+	config::{Config, VideoCodec},
+	clients::ClientManager,
+	webserver::tls::TlsAcceptor,
+	session::{manager::SessionManager, stream::VideoCapabilities, SessionContext, SessionKeys},
+};
 
 use self::pairing::handle_pair_request;
 
@@ -20,9 +25,19 @@ mod tls;
 const SERVERINFO_APP_VERSION: &str = "7.1.431.-1";
 const SERVERINFO_GFE_VERSION: &str = "3.23.0.74";
 
+// Flags used in ServerCodecModeSupport to indicate which codecs we can stream.
+const SCM_H264: u32 = 0x00001;
+const SCM_HEVC: u32 = 0x00100;
+const SCM_HEVC_MAIN10: u32 = 0x00200;
+const SCM_AV1_MAIN8: u32 = 0x10000;
+const SCM_AV1_MAIN10: u32 = 0x20000;
+const SCM_H264_HIGH8_444: u32 = 0x40000;
+const SCM_HEVC_REXT8_444: u32 = 0x80000;
+
 #[derive(Clone)]
 pub struct Webserver {
 	config: Config,
+	video_capabilities: VideoCapabilities,
 	unique_id: String,
 	client_manager: ClientManager,
 	session_manager: SessionManager,
@@ -33,6 +48,7 @@ impl Webserver {
 	#[allow(clippy::result_unit_err)]
 	pub fn new(
 		config: Config,
+		video_capabilities: VideoCapabilities,
 		unique_id: String,
 		server_certs: X509,
 		client_manager: ClientManager,
@@ -41,6 +57,7 @@ impl Webserver {
 	) -> Result<Self, ()> {
 		let server = Self {
 			config: config.clone(),
+			video_capabilities,
 			unique_id,
 			client_manager,
 			session_manager,
@@ -82,7 +99,7 @@ impl Webserver {
 							async move {
 								let _ = hyper::server::conn::http1::Builder::new()
 									.serve_connection(io, service_fn(|request| {
-										server.serve(request, mac_address.clone(), false)
+										server.serve(request, mac_address.clone(), None, false)
 									})).await;
 							}
 						});
@@ -127,6 +144,7 @@ impl Webserver {
 							Ok(connection) => connection,
 							Err(()) => continue,
 						};
+						let client_certificate = connection.ssl().peer_certificate();
 
 						let io = TokioIo::new(connection);
 
@@ -135,7 +153,7 @@ impl Webserver {
 							async move {
 								let _ = hyper::server::conn::http1::Builder::new()
 									.serve_connection(io, service_fn(|request| {
-										server.serve(request, mac_address.clone(), true)
+										server.serve(request, mac_address.clone(), client_certificate.clone(), true)
 									})).await;
 							}
 						});
@@ -153,7 +171,13 @@ impl Webserver {
 		Ok(server)
 	}
 
-	async fn serve(&self, request: Request<hyper::body::Incoming>, mac_address: Option<String>, https: bool) -> Result<Response<Full<Bytes>>, Infallible> {
+	async fn serve(
+		&self,
+		request: Request<hyper::body::Incoming>,
+		mac_address: Option<String>,
+		client_certificate: Option<X509>,
+		https: bool,
+	) -> Result<Response<Full<Bytes>>, Infallible> {
 		let params = request.uri()
 			.query()
 			.map(|v| {
@@ -175,6 +199,7 @@ impl Webserver {
 				(&Method::GET, "/launch") => self.launch(params).await,
 				(&Method::GET, "/resume") => self.resume(params).await,
 				(&Method::GET, "/cancel") => self.cancel().await,
+				(&Method::GET, "/save-replay") => self.save_replay(client_certificate).await,
 				(method, uri) => {
 					log::warn!("Unhandled {method} request with URI '{uri}'");
 					not_found()
@@ -201,8 +226,8 @@ impl Webserver {
 		for application in self.config.applications.iter() {
 			response += "<App>";
 
-			// TODO: Fix HDR support.
-			response += "<IsHdrSupported>0</IsHdrSupported>";
+			let preferred_codec = application.stream.preferred_codec.unwrap_or(self.config.stream.video.preferred_codec);
+			response += &format!("<IsHdrSupported>{}</IsHdrSupported>", u8::from(self.video_capabilities.hdr(preferred_codec)));
 			response += format!("<AppTitle>{}</AppTitle>", application.title).as_ref();
 			response += format!("<ID>{}</ID>", application.id()).as_ref();
 
@@ -334,7 +359,7 @@ impl Webserver {
 		response += &format!("<mac>{}</mac>", mac_address.unwrap_or("".to_string()));
 		response += "<MaxLumaPixelsHEVC>1869449984</MaxLumaPixelsHEVC>"; // TODO: Check if HEVC is supported, set this to 0 if it is not.
 		response += "<LocalIP></LocalIP>";
-		response += "<ServerCodecModeSupport>259</ServerCodecModeSupport>";
+		response += &format!("<ServerCodecModeSupport>{}</ServerCodecModeSupport>", self.codec_mode_support().await);
 		response += "<SupportedDisplayMode></SupportedDisplayMode>";
 		response += &format!("<PairStatus>{paired}</PairStatus>");
 		response += &format!("<currentgame>{}</currentgame>", session_context.clone().map(|s| s.application_id).unwrap_or(0));
@@ -346,6 +371,47 @@ impl Webserver {
 		response
 	}
 
+	async fn codec_mode_support(&self) -> u32 {
+		// Only offer codecs up to the preferred codec of the running application, or the global preferred codec.
+		// Likewise, only offer 4:4:4 if the running application (or every application) streams it.
+		let (preferred_codec, yuv444) = match self.session_manager.get_session_context().await {
+			Ok(Some(context)) => (
+				context.application.stream.preferred_codec.unwrap_or(self.config.stream.video.preferred_codec),
+				context.application.yuv444 || self.config.stream.video.yuv444,
+			),
+			_ => (self.config.stream.video.preferred_codec, self.config.stream.video.yuv444),
+		};
+
+		// HDR is streamed as 10-bit HEVC (Main10) or AV1 (Main10), if the encoder supports it.
+		let mut codec_mode_support = SCM_H264;
+		if preferred_codec >= VideoCodec::Hevc {
+			codec_mode_support |= SCM_HEVC;
+			if self.video_capabilities.hevc_main10 {
+				codec_mode_support |= SCM_HEVC_MAIN10;
+			}
+		}
+		if preferred_codec >= VideoCodec::Av1 {
+			if self.video_capabilities.av1 {
+				codec_mode_support |= SCM_AV1_MAIN8;
+			}
+			if self.video_capabilities.av1_main10 {
+				codec_mode_support |= SCM_AV1_MAIN10;
+			}
+		}
+
+		// Clients can only ask for 4:4:4 if we advertise it, which we only do for formats that the encoder can open in 4:4:4.
+		if yuv444 {
+			if self.video_capabilities.h264_yuv444 {
+				codec_mode_support |= SCM_H264_HIGH8_444;
+			}
+			if self.video_capabilities.hevc_yuv444 && preferred_codec >= VideoCodec::Hevc {
+				codec_mode_support |= SCM_HEVC_REXT8_444;
+			}
+		}
+
+		codec_mode_support
+	}
+
 	async fn pin(
 		&self,
 	) -> Response<Full<Bytes>> {
@@ -547,7 +613,8 @@ impl Webserver {
 			keys: SessionKeys {
 				remote_input_key,
 				remote_input_key_id,
-			}
+			},
+			features: Default::default(),
 		}).await;
 
 		if initialize_result.is_err() {
@@ -654,6 +721,28 @@ impl Webserver {
 		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("application/xml"));
 		response
 	}
+
+	async fn save_replay(&self, client_certificate: Option<X509>) -> Response<Full<Bytes>> {
+		// Saving a replay writes to disk, so only allow it for paired clients.
+		let paired = match client_certificate {
+			Some(client_certificate) => self.client_manager.is_certificate_paired(client_certificate).await.unwrap_or(false),
+			None => false,
+		};
+		if !paired {
+			log::warn!("Refusing to save replay for a client without the certificate of a paired client.");
+			return forbidden();
+		}
+
+		if self.session_manager.save_replay().await.is_err() {
+			let message = "Failed to save replay".to_string();
+			log::warn!("{message}");
+			return bad_request(message);
+		}
+
+		let mut response = Response::new(Full::new(Bytes::from("Saving replay.")));
+		response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static("text/plain"));
+		response
+	}
 }
 
 fn bad_request(message: String) -> Response<Full<Bytes>> {
@@ -663,6 +752,13 @@ fn bad_request(message: String) -> Response<Full<Bytes>> {
 		.unwrap()
 }
 
+fn forbidden() -> Response<Full<Bytes>> {
+	Response::builder()
+		.status(StatusCode::FORBIDDEN)
+		.body(Full::new(Bytes::from("FORBIDDEN")))
+		.unwrap()
+}
+
 fn not_found() -> Response<Full<Bytes>> {
 	Response::builder()
 		.status(StatusCode::NOT_FOUND)
@@ -1,6 +1,6 @@
 use std::{path::Path, pin::Pin};
 
-use openssl::ssl::{SslMethod, SslFiletype, SslAcceptor, Ssl};
+use openssl::ssl::{SslMethod, SslFiletype, SslAcceptor, Ssl, SslVerifyMode};
 use tokio::net::TcpStream;
 use tokio_openssl::SslStream;
 
@@ -37,5 +37,9 @@ fn load_tls_files<P: AsRef<Path>>(certificate: P, private_key: P) -> Result<SslA
 		.set_certificate_chain_file(&certificate)
 		.map_err(|e| log::error!("Failed to set certificate file '{:?}': {}", certificate.as_ref(), e))?;
 
+	// Ask clients for their certificate. Moonlight uses self signed certificates,
+	// so they are accepted here and compared against the certificates of paired clients when needed.
+	builder.set_verify_callback(SslVerifyMode::PEER, |_, _| true);
+
 	Ok(builder.build())
 }